@@ -1,4 +1,10 @@
-use spacetimedb::{reducer, table, Identity, ReducerContext, Table, Timestamp};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use serde_json::json;
+use spacetimedb::{
+    reducer, table, Identity, ReducerContext, ScheduleAt, SpacetimeType, Table, Timestamp,
+};
 
 #[table(name = cursor, public)]
 // Cursor table definition - tracks user cursor positions and brush settings
@@ -8,11 +14,17 @@ pub struct Cursor {
     x: f32,
     y: f32,
     color: String, // Current brush color
-    size: f32,     // Current brush size
+    size: f32,     // Current brush radius
     last_updated: Timestamp,
+    idle_hidden: bool, // Set after CURSOR_IDLE_TIMEOUT without activity; clients hide it
+    is_drawing: bool,  // The user has a stroke open and moved within DRAWING_IDLE_TIMEOUT
 }
 
-// New table for storing drawing points
+// New table for storing drawing points.
+// Render contract: clients draw points in ascending `draw_order`, so later
+// points paint over earlier ones identically on every client. `draw_order` is
+// the canonical ordering key: a total order taken from a server-side counter
+// in canvas_meta, never reused, and independent of `id`, strokes and layers.
 #[table(name = canvas_point, public)]
 pub struct CanvasPoint {
     #[primary_key]
@@ -21,9 +33,20 @@ pub struct CanvasPoint {
     identity: Identity, // Who drew this point
     x: f32,
     y: f32,
-    color: String, // Using string for color (e.g., "#000000")
-    size: f32,     // Brush size
+    color: String,   // Using string for color (e.g., "#000000")
+    size: f32,       // Brush radius, rendered as a circle of this radius
+    opacity: f32,    // 0.0 (transparent) to 1.0 (opaque)
+    draw_order: u64, // Global paint and creation order, assigned by insert_canvas_point
+    #[index(btree)]
+    stroke_id: u64, // Stroke this point belongs to, or 0 for loose points
+    layer_id: u64,   // Layer this point is drawn on, or BASE_LAYER_ID
+    mirror_of: u64,  // Point this symmetry copy was made from, or 0
+    origin: String,  // ORIGIN_DRAWN or ORIGIN_LOADED
+    blend_mode: String, // One of BLEND_MODES, applied by clients when compositing
     timestamp: Timestamp,
+    #[index(btree)]
+    bucket_x: u32, // Spatial bucket of the point's center; see view_buckets
+    bucket_y: u32,
 }
 
 // New table for storing saved canvas states
@@ -35,6 +58,16 @@ pub struct CanvasState {
     name: String,         // Name of the saved state
     created_by: Identity, // Who created this save
     created_at: Timestamp,
+    is_autosave: bool,   // Written by the periodic autosave rather than a user
+    is_locked: bool,     // Locked states can't be overwritten, renamed or deleted
+    point_count: u64,    // Number of saved points, kept current on overwrite
+    description: String, // Free-text notes about what the save holds, may be empty
+    // Hash of the saved points' drawing data in order; states with equal
+    // hashes hold the same drawing. Kept current on overwrite.
+    content_hash: String,
+    // Last canvas_history event reflected in the save, or 0 when the state
+    // wasn't saved from the whole live canvas (imports, selections, ...)
+    history_seq: u64,
 }
 
 // New table to store the points associated with a saved canvas state
@@ -43,193 +76,9453 @@ pub struct SavedCanvasPoint {
     #[primary_key]
     #[auto_inc]
     id: u64,
+    #[index(btree)]
     state_id: u64, // References the state this point belongs to
     x: f32,
     y: f32,
     color: String,
     size: f32,
+    opacity: f32,
+    origin: String, // Origin of the canvas point when it was saved
+    blend_mode: String,
+    drawn_at: Timestamp, // The canvas point's timestamp when it was saved
 }
 
-#[reducer(client_connected)]
-// Handles a new client connection
-pub fn identity_connected(ctx: &ReducerContext) {
-    // Create a cursor entry for the new user
-    ctx.db.cursor().insert(Cursor {
-        identity: ctx.sender,
-        x: 0.0,
-        y: 0.0,
-        color: "#000000".to_string(), // Default color
-        size: 3.0,                    // Default size
-        last_updated: ctx.timestamp,
-    });
+// Identities allowed to run admin-gated reducers
+#[table(name = room_admin, public)]
+pub struct RoomAdmin {
+    #[primary_key]
+    identity: Identity,
+    granted_at: Timestamp,
 }
 
-#[reducer(client_disconnected)]
-// Handles a client disconnection
-pub fn identity_disconnected(ctx: &ReducerContext) {
-    // Remove the cursor when a user disconnects
-    if let Some(cursor) = ctx.db.cursor().identity().find(ctx.sender) {
-        ctx.db.cursor().delete(cursor);
-    }
+// Room-wide brush presets shared by everyone drawing on the canvas
+#[table(name = brush_preset, public)]
+pub struct BrushPreset {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[unique]
+    name: String,
+    color: String,
+    size: f32,
+    opacity: f32, // 0.0 (transparent) to 1.0 (opaque)
+    created_by: Identity,
 }
 
-#[reducer]
-// Updates a user's cursor position and brush settings
-pub fn update_cursor(ctx: &ReducerContext, x: f32, y: f32, color: String, size: f32) {
-    if let Some(cursor) = ctx.db.cursor().identity().find(ctx.sender) {
-        ctx.db.cursor().identity().update(Cursor {
-            x,
-            y,
-            color,
-            size,
-            last_updated: ctx.timestamp,
-            ..cursor
-        });
-    }
+// Each user's quick-switch brush sizes, which clients bind to the number keys
+#[table(name = size_preset, public)]
+pub struct SizePreset {
+    #[primary_key]
+    identity: Identity,
+    sizes: Vec<f32>, // Slot 0 is key 1; at most MAX_SIZE_PRESETS
+    updated_at: Timestamp,
 }
 
-#[reducer]
-// Adds a new drawing point to the canvas
-pub fn add_drawing_point(ctx: &ReducerContext, x: f32, y: f32, color: String, size: f32) {
-    ctx.db.canvas_point().insert(CanvasPoint {
-        id: 0, // Will be auto-incremented
-        identity: ctx.sender,
-        x,
-        y,
-        color,
-        size,
-        timestamp: ctx.timestamp,
-    });
+// Most used colors on the live canvas, ranked from 0 (most used)
+#[table(name = color_histogram, public)]
+pub struct ColorHistogram {
+    #[primary_key]
+    rank: u32,
+    color: String,
+    count: u64, // Number of canvas points using this color
 }
 
-#[reducer]
-// Erases points near the given coordinates
-pub fn erase_points(ctx: &ReducerContext, x: f32, y: f32, radius: f32) {
-    // Remove a point if the eraser (brush) circle overlaps with the point's circle
-    let points_to_erase: Vec<CanvasPoint> = ctx
-        .db
-        .canvas_point()
-        .iter()
-        .filter(|point| {
-            let dx = point.x - x;
-            let dy = point.y - y;
-            let dist_sq = dx * dx + dy * dy;
-            let combined_radius = radius + point.size;
-            dist_sq <= combined_radius * combined_radius
-        })
-        .collect();
+// Strokes a user's eraser touched while confirm_stroke_erase is on, waiting
+// for confirm_stroke_erase before they are removed
+#[table(name = stroke_erase_candidate, public)]
+pub struct StrokeEraseCandidate {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Whose eraser touched the stroke
+    stroke_id: u64,
+}
 
-    for point in points_to_erase {
-        ctx.db.canvas_point().delete(point);
-    }
+// Points each user has selected for selection-based tools
+#[table(name = selected_point, public)]
+pub struct SelectedPoint {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Whose selection this is
+    point_id: u64, // References a canvas point; erased points are ignored
 }
 
-#[reducer]
-// Saves the current canvas state with a given name
-pub fn save_canvas_state(ctx: &ReducerContext, name: String) {
-    // Insert the state metadata
-    let state = ctx.db.canvas_state().insert(CanvasState {
-        id: 0, // Will be auto-incremented
-        name,
-        created_by: ctx.sender,
-        created_at: ctx.timestamp,
-    });
+// Points a user's eraser would remove, shown before the erase is committed
+#[table(name = erase_preview, public)]
+pub struct ErasePreview {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who is previewing
+    point_id: u64, // References the canvas point that would be erased
+}
 
-    // Save all current canvas points with this state
-    for point in ctx.db.canvas_point().iter() {
-        ctx.db.saved_canvas_point().insert(SavedCanvasPoint {
-            id: 0, // Will be auto-incremented
-            state_id: state.id,
-            x: point.x,
-            y: point.y,
-            color: point.color.clone(),
-            size: point.size,
-        });
-    }
+// Timing of each stroke so clients can replay how it was drawn
+#[table(name = stroke_meta, public)]
+pub struct StrokeMeta {
+    #[primary_key]
+    #[auto_inc]
+    stroke_id: u64, // Allocated by begin_stroke; never 0, which marks loose points
+    #[index(btree)]
+    identity: Identity, // Who is drawing the stroke
+    started_at: Timestamp,
+    ended_at: Option<Timestamp>, // None while the stroke is still being drawn
+    point_count: u64,
 }
 
-#[reducer]
-// Clears all drawing points from the canvas
-pub fn clear_canvas(ctx: &ReducerContext) {
-    for point in ctx.db.canvas_point().iter() {
-        ctx.db.canvas_point().delete(point);
-    }
+// Append-only log of every change to canvas_point, for full session replay.
+// `seq` is monotonic, so replaying events in `seq` order rebuilds the canvas.
+#[table(name = canvas_history, public)]
+pub struct CanvasHistory {
+    #[primary_key]
+    #[auto_inc]
+    seq: u64,
+    kind: String,  // One of HISTORY_ADD, HISTORY_UPDATE or HISTORY_REMOVE
+    point_id: u64, // References the canvas point the event applies to
+    identity: Identity,
+    x: f32,
+    y: f32,
+    color: String,
+    size: f32,
+    opacity: f32,
+    recorded_at: Timestamp,
 }
 
-#[reducer]
-// Loads a saved canvas state by its ID
-pub fn load_canvas_state(ctx: &ReducerContext, state_id: u64) {
-    // First clear the current canvas
-    for point in ctx.db.canvas_point().iter() {
-        ctx.db.canvas_point().delete(point);
-    }
+// Schedules the periodic pruning of old canvas_history events
+#[table(name = history_prune_schedule, scheduled(prune_canvas_history))]
+pub struct HistoryPruneSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
 
-    // Find the saved state
-    if let Some(state) = ctx.db.canvas_state().id().find(state_id) {
-        let saved_points: Vec<SavedCanvasPoint> = ctx
-            .db
-            .saved_canvas_point()
-            .iter()
-            .filter(|p| p.state_id == state_id)
-            .collect();
+// Schedules the periodic removal of ghost cursors
+#[table(name = cursor_reconcile_schedule, scheduled(reconcile_cursors))]
+pub struct CursorReconcileSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
 
-        // Store length before we consume the vector
-        let point_count = saved_points.len();
-
-        // Recreate each saved point on the current canvas
-        for saved_point in saved_points {
-            ctx.db.canvas_point().insert(CanvasPoint {
-                id: 0,                // Will be auto-incremented
-                identity: ctx.sender, // The person loading becomes the owner
-                x: saved_point.x,
-                y: saved_point.y,
-                color: saved_point.color,
-                size: saved_point.size,
-                timestamp: ctx.timestamp,
-            });
-        }
+// Schedules the periodic clearing of is_drawing flags left by idle strokes
+#[table(name = drawing_sweep_schedule, scheduled(clear_idle_drawing))]
+pub struct DrawingSweepSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
 
-        log::info!(
-            "User {} loaded canvas state {} ({}) with {} points",
-            ctx.sender,
-            state.id,
-            state.name,
-            point_count
-        );
-    }
+// Users who gave the room's access code; only they and admins can draw while
+// the room has one
+#[table(name = room_member, public)]
+pub struct RoomMember {
+    #[primary_key]
+    identity: Identity,
+    joined_at: Timestamp,
 }
 
-#[reducer]
-// Deletes a saved canvas state by its ID
-pub fn delete_canvas_state(ctx: &ReducerContext, state_id: u64) {
-    // Check if the state exists
-    if let Some(state) = ctx.db.canvas_state().id().find(state_id) {
-        // Only allow deletion by the creator or if the state exists
-        if state.created_by == ctx.sender {
-            // Store the name before deleting
-            let state_name = state.name.clone();
+// When each connected user joined, for the join_cooldown_seconds setting.
+// The row lasts until the user's last connection closes.
+#[table(name = join_record, public)]
+pub struct JoinRecord {
+    #[primary_key]
+    identity: Identity,
+    joined_at: Timestamp, // When the first of the user's open connections was made
+    connections: u32,     // Open connections; the row goes when this reaches 0
+}
 
-            // Delete all saved points associated with this state
-            let points_to_delete: Vec<SavedCanvasPoint> = ctx
-                .db
-                .saved_canvas_point()
-                .iter()
-                .filter(|p| p.state_id == state_id)
-                .collect();
+// Connected users who joined a full room and can watch but not draw
+#[table(name = spectator, public)]
+pub struct Spectator {
+    #[primary_key]
+    identity: Identity,
+    joined_at: Timestamp,
+}
 
-            for point in points_to_delete {
-                ctx.db.saved_canvas_point().delete(point);
-            }
+// Users currently composing a chat message
+#[table(name = typing_indicator, public)]
+pub struct TypingIndicator {
+    #[primary_key]
+    identity: Identity,
+    started_at: Timestamp,
+}
 
-            // Delete the state itself
-            ctx.db.canvas_state().delete(state);
+// Schedules the periodic removal of stale typing indicators
+#[table(name = typing_cleanup_schedule, scheduled(clear_stale_typing))]
+pub struct TypingCleanupSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
 
-            log::info!(
-                "User {} deleted canvas state {} ({})",
-                ctx.sender,
-                state_id,
-                state_name
-            );
-        }
+// Chat messages sent in the room
+#[table(name = chat_message, public)]
+pub struct ChatMessage {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    sender: Identity,
+    text: String,
+    sent_at: Timestamp,
+}
+
+// Emoji reactions on chat messages; at most one per (message, user, emoji)
+#[table(name = message_reaction, public)]
+pub struct MessageReaction {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    message_id: u64, // References the chat message reacted to
+    identity: Identity,
+    emoji: String,
+}
+
+// The room's pinned chat message, if any (at most one row)
+#[table(name = pinned_message, public)]
+pub struct PinnedMessage {
+    #[primary_key]
+    id: u32, // Always PINNED_MESSAGE_ID
+    message_id: u64, // References the pinned chat message
+    pinned_by: Identity,
+    pinned_at: Timestamp,
+}
+
+// Per-user profile details shown in the participant list
+#[table(name = user_profile, public)]
+pub struct UserProfile {
+    #[primary_key]
+    identity: Identity,
+    display_name: String,
+    #[unique]
+    name_key: String, // Lowercased display_name, keeps names unique ignoring case
+    updated_at: Timestamp,
+    last_seen: Timestamp, // Last connect, disconnect or profile change
+}
+
+// Schedules the periodic autosave of the live canvas
+#[table(name = autosave_schedule, scheduled(autosave_canvas))]
+pub struct AutosaveSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Drawing layers stacked above the implicit base layer (BASE_LAYER_ID)
+#[table(name = layer, public)]
+pub struct Layer {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    name: String,
+    z_order: u32, // Stacking position, 1 is just above the base layer
+    locked: bool, // Locked layers can't be erased
+    hidden: bool,
+    created_by: Identity,
+}
+
+// What each user was doing when they last left, restored when they reconnect:
+// their brush (saved on disconnect) and their viewport (kept by set_viewport)
+#[table(name = session_state, public)]
+pub struct SessionState {
+    #[primary_key]
+    identity: Identity,
+    viewport_x: f32, // Canvas position at the top-left of the user's view
+    viewport_y: f32,
+    viewport_zoom: f32,
+    brush_color: Option<String>, // None until the user's first disconnect
+    brush_size: Option<f32>,
+    updated_at: Timestamp,
+}
+
+// The layer each user's new drawing lands on; users without a row draw on
+// the base layer
+#[table(name = user_active_layer, public)]
+pub struct UserActiveLayer {
+    #[primary_key]
+    identity: Identity,
+    layer_id: u64,
+}
+
+// The range of spatial buckets each user's client is showing. A bucket is a
+// SPATIAL_BUCKET_SIZE square of the canvas, and every canvas point records
+// the bucket its center is in. Rather than the whole canvas_point table, a
+// client subscribes to just its range and resubscribes whenever it calls
+// set_view_buckets:
+//
+//   SELECT * FROM canvas_point
+//   WHERE bucket_x >= <min_cx> AND bucket_x <= <max_cx>
+//     AND bucket_y >= <min_cy> AND bucket_y <= <max_cy>
+//
+// with the range's numbers filled in. Brush circles can reach into a
+// neighbouring bucket, so clients should pad their range by one bucket.
+#[table(name = view_buckets, public)]
+pub struct ViewBuckets {
+    #[primary_key]
+    identity: Identity,
+    min_cx: u32,
+    min_cy: u32,
+    max_cx: u32, // Inclusive
+    max_cy: u32, // Inclusive
+    updated_at: Timestamp,
+}
+
+// Single-row table of room-wide settings
+#[table(name = canvas_settings, public)]
+pub struct CanvasSettings {
+    #[primary_key]
+    id: u32, // Always CANVAS_SETTINGS_ID
+    erase_hidden_layers: bool, // Whether the eraser reaches points on hidden layers
+    erase_hidden_strokes: bool, // Whether the eraser reaches strokes hidden with set_stroke_visibility
+    guest_expiry_seconds: u64,  // Offline time after which per-user rows are removed
+    default_brush_color: Option<String>, // Starting color, or None for a distinct palette color
+    default_brush_size: f32,    // Starting brush radius for new cursors
+    erase_mode: String,         // ERASE_MODE_PIXEL or ERASE_MODE_STROKE
+    symmetry_axes: u32,         // Mirror axes for drawing, or 0 when symmetry is off
+    symmetry_center_x: f32,
+    symmetry_center_y: f32,
+    max_brush_size: f32,    // Room cap on brush radius, at most MAX_BRUSH_SIZE
+    point_ttl_seconds: u64, // Age at which points disappear, or 0 to keep them
+    room_topic: String,     // Topic shown in the room header, empty for none
+    autosave_name_template: String, // Autosave name; see AUTOSAVE_PLACEHOLDERS
+    save_quiet_window_ms: u64, // Saves wait until nothing was drawn for this long; 0 is off
+    canvas_frozen: bool,    // While set, nothing on the canvas can change
+    snap_grid_size: f32,    // Spacing of the snapping grid in canvas units
+    snap_shapes: bool,      // Snap the endpoints of straight segments to the grid
+    snap_freehand: bool,    // Snap freehand points to the grid too
+    max_participants: Option<u32>, // Users who can draw at once; later joiners spectate
+    // Client pixels per canvas unit. Every stored position, size and radius
+    // is in canvas units; clients divide their pixel values by this before
+    // calling reducers and multiply when rendering, so one canvas looks the
+    // same at any DPI.
+    coordinate_scale: f32,
+    // Whether users must give join_room the room's access code before
+    // drawing. The code's hash lives in the private room_secret table.
+    access_code_required: bool,
+    coordinate_precision: Option<u8>, // Decimal places drawn points keep; None is full f32
+    confirm_stroke_erase: bool, // In stroke mode, erased strokes wait for confirm_stroke_erase
+    room_template_state_id: Option<u64>, // Saved state a never-drawn-on canvas starts from
+    min_point_spacing: f32, // Stroke points closer than this to the last one are dropped; 0 is off
+    outlier_threshold: f32, // Spread, in standard deviations, past which trimmed exports drop points
+    allowed_tools: Vec<String>, // TOOLS non-admins may draw with; all of them by default
+    archive_after_seconds: u64, // Inactivity after which the room is archived; 0 never archives
+    archived: bool,         // Hidden from list_rooms; the canvas and saves are kept
+    bound_erasers: bool,    // Refuse erasers centered off the canvas instead of scanning
+    join_cooldown_seconds: u64, // Wait after joining before non-members may draw; 0 is off
+}
+
+// Outcome of each user's latest request that reports back, e.g. a refusal
+#[table(name = action_result, public)]
+pub struct ActionResult {
+    #[primary_key]
+    identity: Identity, // Who made the request
+    action: String, // Name of the reducer that produced the result
+    success: bool,
+    message: String,
+    created_at: Timestamp,
+}
+
+// Schedules the periodic cleanup of rows left by departed guests
+#[table(name = guest_cleanup_schedule, scheduled(cleanup_expired_guests))]
+pub struct GuestCleanupSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Running per-user activity counters, bumped as users draw, erase and save
+#[table(name = user_stats, public)]
+pub struct UserStats {
+    #[primary_key]
+    identity: Identity,
+    points_drawn: u64,
+    strokes: u64,
+    erases: u64, // Points removed by this user's erases
+    states_saved: u64,
+    ink_used: f64, // Stroke length times brush size, from recompute_ink_usage
+}
+
+// Single-row table of room-wide activity metrics for the admin dashboard
+#[table(name = room_analytics, public)]
+pub struct RoomAnalytics {
+    #[primary_key]
+    id: u32, // Always ROOM_ANALYTICS_ID; the module hosts a single room
+    total_points: u64,        // Points ever drawn, including ones erased since
+    total_strokes: u64,       // Strokes ever begun
+    active_users: u32,        // Cursors that moved within ACTIVE_USER_WINDOW
+    last_activity: Timestamp, // Last draw, stroke or erase by anyone
+}
+
+// Schedules the periodic recount of room_analytics.active_users
+#[table(name = analytics_schedule, scheduled(recompute_active_users))]
+pub struct AnalyticsSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Copy of the canvas taken by the most recent clear_canvas, for undo_clear
+#[table(name = clear_backup, public)]
+pub struct ClearBackup {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    cleared_by: Identity, // Who ran the clear; they or an admin may undo it
+    identity: Identity,   // Original author of the point
+    x: f32,
+    y: f32,
+    color: String,
+    size: f32,
+    opacity: f32,
+    draw_order: u64, // Original paint order, replayed in this order
+    stroke_id: u64,
+    layer_id: u64,
+    origin: String,
+    blend_mode: String,
+}
+
+// Strokes that had points in clear_backup, with their labels and visibility,
+// so undo_clear can bring strokes back whole rather than as loose points
+#[table(name = clear_stroke_backup, public)]
+pub struct ClearStrokeBackup {
+    #[primary_key]
+    stroke_id: u64,
+    identity: Identity,
+    started_at: Timestamp,
+    ended_at: Option<Timestamp>,
+    point_count: u64,
+    label: Option<String>,
+    hidden: bool,
+}
+
+// Points removed by each user's most recent erase, for undo_last_erase
+#[table(name = erase_undo, public)]
+pub struct EraseUndo {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    erased_by: Identity, // Whose erase removed the point
+    identity: Identity, // Original author of the point
+    x: f32,
+    y: f32,
+    color: String,
+    size: f32,
+    opacity: f32,
+    draw_order: u64, // Original paint order, replayed in this order
+    stroke_id: u64,
+    layer_id: u64,
+    origin: String,
+    blend_mode: String,
+}
+
+// Coarse preview of each saved state for state-list UIs
+#[table(name = state_thumbnail, public)]
+pub struct StateThumbnail {
+    #[primary_key]
+    state_id: u64,
+    // JSON {"cols", "rows", "cells"}: `cells` is row-major, holding each
+    // cell's dominant "#rrggbb" color or null for an empty cell
+    grid: String,
+}
+
+// The stroke id most recently allocated to each user by begin_stroke.
+// Contract: call begin_stroke, read your row here, then add_drawing_point
+// groups your points under that stroke until end_stroke.
+#[table(name = stroke_alloc, public)]
+pub struct StrokeAlloc {
+    #[primary_key]
+    identity: Identity,
+    stroke_id: u64,
+    allocated_at: Timestamp,
+}
+
+// The one stroke each user is drawing right now, if any. Set by begin_stroke
+// and cleared by end_stroke, so a user never has two strokes open at once.
+#[table(name = active_stroke, public)]
+pub struct ActiveStroke {
+    #[primary_key]
+    identity: Identity,
+    stroke_id: u64,
+    last_point_id: u64, // The stroke's most recently drawn point, or 0 before the first
+}
+
+// Colors admins have locked users to; an assigned user always draws in it
+#[table(name = color_assignment, public)]
+pub struct ColorAssignment {
+    #[primary_key]
+    identity: Identity,
+    color: String,
+    assigned_by: Identity,
+    assigned_at: Timestamp,
+}
+
+// Each user's latest search_states matches, newest state first by `rank`
+#[table(name = state_search_result, public)]
+pub struct StateSearchResult {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who searched
+    rank: u32, // Position in the results, starting at 0
+    state_id: u64,
+    name: String,
+}
+
+// Each user's latest query_states page, in the requested order by `rank`
+#[table(name = state_query_result, public)]
+pub struct StateQueryResult {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who queried
+    rank: u32, // Position in the results, starting at 0
+    state_id: u64,
+    name: String,
+    created_at: Timestamp,
+    point_count: u64,
+}
+
+// Each user's latest state_color_breakdown, most used colors first by `rank`
+#[table(name = state_color_stats, public)]
+pub struct StateColorStats {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who asked
+    state_id: u64,
+    rank: u32, // Position in the breakdown, starting at 0
+    color: String,
+    count: u64, // Number of the state's points using this color
+}
+
+// Who loaded which saved state and when, for "last loaded by" displays
+#[table(name = state_load_log, public)]
+pub struct StateLoadLog {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    state_id: u64,
+    loaded_by: Identity,
+    loaded_at: Timestamp,
+}
+
+// Schedules the sweep that removes points past the room's point TTL
+#[table(name = point_expiry_schedule, scheduled(expire_old_points))]
+pub struct PointExpirySchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Append-only record of moderation and room-level changes
+#[table(name = audit_log, public)]
+pub struct AuditLog {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    actor: Identity, // Who made the change
+    action: String,  // Reducer that made it, e.g. "set_room_topic"
+    detail: String,  // Human-readable description of the change
+    recorded_at: Timestamp,
+}
+
+// Each user's latest find_nearest_point answer
+#[table(name = nearest_result, public)]
+pub struct NearestResult {
+    #[primary_key]
+    identity: Identity,
+    point_id: Option<u64>, // None when no point was within range
+    x: f32,                // Position of the found point
+    y: f32,
+    distance: f32, // Center-to-center distance from the queried position
+    found_at: Timestamp,
+}
+
+// Each user's latest sample_color answer
+#[table(name = eyedropper_result, public)]
+pub struct EyedropperResult {
+    #[primary_key]
+    identity: Identity,
+    color: Option<String>, // None when no visible point covers the position
+    point_id: Option<u64>, // The topmost point that was sampled
+    sampled_at: Timestamp,
+}
+
+// Each user's latest list_populated_tiles answer: one row per tile of
+// `tile_size` that some brush circle reaches. Tile (tx, ty) covers
+// [tx * tile_size, (tx + 1) * tile_size) on each axis.
+#[table(name = tile_manifest, public)]
+pub struct TileManifest {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who asked for the manifest
+    tile_size: f32,
+    tile_x: i32,
+    tile_y: i32,
+    point_count: u64, // Points whose brush circle reaches into the tile
+}
+
+// Each user's latest count_points_near answer
+#[table(name = density_result, public)]
+pub struct DensityResult {
+    #[primary_key]
+    identity: Identity,
+    x: f32, // Center and radius that were counted
+    y: f32,
+    radius: f32,
+    count: u64, // Points whose brush circle overlaps the circle
+    counted_at: Timestamp,
+}
+
+// Per-stroke summary for object lists: size, extent and color of each stroke
+// that has points. Kept current by the canvas_point write helpers.
+#[table(name = stroke_index, public)]
+pub struct StrokeIndex {
+    #[primary_key]
+    stroke_id: u64,
+    #[index(btree)]
+    identity: Identity, // Who drew the stroke
+    point_count: u64,
+    min_x: f32, // Bounding box of the stroke's brush circles
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+    color: String, // Color of the stroke's first point
+}
+
+// Row counts of the largest tables over time, for watching storage growth.
+// Written hourly and by report_usage; only the newest MAX_USAGE_REPORTS are kept.
+#[table(name = usage_report, public)]
+pub struct UsageReport {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    recorded_at: Timestamp,
+    canvas_points: u64,
+    canvas_states: u64,
+    saved_points: u64,
+    cursors: u64,
+}
+
+// Schedules the periodic usage_report snapshot
+#[table(name = usage_report_schedule, scheduled(record_usage))]
+pub struct UsageReportSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// The next step of a running fade_clear. Each step is a one-off schedule that
+// inserts the one after it until the canvas is empty.
+#[table(name = fade_clear_schedule, scheduled(fade_clear_step))]
+pub struct FadeClearSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+    steps_left: u32, // Including this one; the last step removes everything left
+    started_by: Identity,
+}
+
+// Strokes each user asked to see flashed by flash_user_strokes, until they
+// expire
+#[table(name = highlight_result, public)]
+pub struct HighlightResult {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who asked for the highlight
+    target: Identity, // Whose strokes are highlighted
+    stroke_id: u64,
+    expires_at: Timestamp,
+}
+
+// Schedules the periodic removal of expired highlights
+#[table(name = highlight_cleanup_schedule, scheduled(clear_expired_highlights))]
+pub struct HighlightCleanupSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Each user's latest analyze_stroke_directions answer. Angles are in degrees
+// from the +x axis, turning toward +y (clockwise on screen).
+#[table(name = analysis_result, public)]
+pub struct AnalysisResult {
+    #[primary_key]
+    identity: Identity,
+    stroke_count: u64, // Strokes measured; ones that end where they start are skipped
+    mean_dx: f32,      // Average first-to-last displacement of those strokes
+    mean_dy: f32,
+    mean_angle: Option<f32>, // Direction of the average, None when it is zero
+    angle_histogram: Vec<u64>, // Strokes per ANGLE_HISTOGRAM_BINS equal slices, from 0 degrees
+    analyzed_at: Timestamp,
+}
+
+// Each user's latest list_rooms answer, one row per room they can see. The
+// module hosts a single room, identified by the module's own identity.
+#[table(name = room_list, public)]
+pub struct RoomList {
+    #[primary_key]
+    #[auto_inc]
+    id: u64,
+    #[index(btree)]
+    identity: Identity, // Who asked
+    room_id: Identity,
+    topic: String,
+    online_count: u64, // Connected users, spectators included
+    point_count: u64,
+    listed_at: Timestamp,
+}
+
+// Share links to saved states, resolved by load_by_link. A link only saves
+// passing a state id around: saved states are public and any user may load
+// one with load_canvas_state, so links grant no access of their own.
+// Tokens are generated by the creating client and only their hash is kept.
+#[table(name = state_link)]
+pub struct StateLink {
+    #[primary_key]
+    token_hash: String, // hash_state_link_token of the token in the URL
+    #[index(btree)]
+    state_id: u64, // References the linked canvas state
+    created_by: Identity,
+    created_at: Timestamp,
+    expires_at: Option<Timestamp>, // None for a link that never expires
+}
+
+// Schedules the periodic removal of expired state links
+#[table(name = state_link_prune_schedule, scheduled(prune_state_links))]
+pub struct StateLinkPruneSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Schedules the periodic check for a room gone quiet long enough to archive
+#[table(name = archive_check_schedule, scheduled(archive_inactive_room))]
+pub struct ArchiveCheckSchedule {
+    #[primary_key]
+    #[auto_inc]
+    scheduled_id: u64,
+    scheduled_at: ScheduleAt,
+}
+
+// Single-row table holding the extent of everything drawn, grown as points
+// are added. A point removed from the edge marks it stale: the box may then be
+// larger than the drawing until the next erase or recompute shrinks it.
+#[table(name = canvas_bounds, public)]
+pub struct CanvasBounds {
+    #[primary_key]
+    id: u32, // Always CANVAS_META_ID
+    has_points: bool, // False when the canvas is empty and the extent is meaningless
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+    stale: bool, // A point on the edge was removed since the last rescan
+}
+
+// Names users give their strokes (e.g. "outline") for the objects list. A
+// label lasts as long as its stroke has points.
+#[table(name = stroke_label, public)]
+pub struct StrokeLabel {
+    #[primary_key]
+    stroke_id: u64,
+    label: String,
+}
+
+// Strokes hidden by their owners while editing; clients skip drawing them.
+// A row lasts as long as its stroke has points.
+#[table(name = stroke_visibility, public)]
+pub struct StrokeVisibility {
+    #[primary_key]
+    stroke_id: u64,
+    hidden: bool,
+}
+
+// The room's access code hash and salt, when it has a code (at most one row).
+// Private so clients can't read the hash and guess codes offline.
+#[table(name = room_secret)]
+pub struct RoomSecret {
+    #[primary_key]
+    id: u32, // Always ROOM_SECRET_ID
+    access_code_hash: String,
+    salt: String, // Random per code, mixed into every hashing round
+}
+
+// Wrong access codes each user gave join_room, for rate limiting guesses.
+// Private so it doesn't advertise who is trying.
+#[table(name = join_failure)]
+pub struct JoinFailure {
+    #[primary_key]
+    identity: Identity,
+    failures: u32,             // Wrong codes since window_started
+    window_started: Timestamp, // Start of the current JOIN_FAILURE_WINDOW
+}
+
+// Single-row table of live canvas aggregates so clients never need to scan
+#[table(name = canvas_meta, public)]
+pub struct CanvasMeta {
+    #[primary_key]
+    id: u32, // Always CANVAS_META_ID
+    point_count: u64,        // Number of rows in canvas_point
+    next_draw_order: u64,    // draw_order given to the next inserted point
+    revision: u64,           // Bumped on every change to canvas_point
+    autosaved_revision: u64, // revision captured by the latest autosave
+    saved_revision: u64,     // revision last captured by any save or restored by a load
+    autosaves_written: u64,  // Autosaves made so far, numbering {index} in their names
+    history_seq: u64,        // seq of the newest canvas_history event, 0 before the first
+}
+
+// Latest export requested by each user, e.g. an SVG document
+#[table(name = export_result, public)]
+pub struct ExportResult {
+    #[primary_key]
+    identity: Identity, // Who requested the export
+    format: String, // e.g. "svg"
+    content: String,
+    created_at: Timestamp,
+}
+
+// A position along an eraser path sent by the client
+#[derive(SpacetimeType)]
+pub struct PathPoint {
+    x: f32,
+    y: f32,
+}
+
+// One user's brush in an apply_brush_template call
+#[derive(SpacetimeType)]
+pub struct BrushAssignment {
+    identity: Identity,
+    color: String,
+    size: f32,
+}
+
+// Where a canvas point came from: drawn by a user here, or recreated from a
+// saved state or an imported file
+const ORIGIN_DRAWN: &str = "drawn";
+const ORIGIN_LOADED: &str = "loaded";
+
+// Compositing modes a point can be drawn with; BLEND_MODE_NORMAL is the default
+const BLEND_MODE_NORMAL: &str = "normal";
+const BLEND_MODES: [&str; 4] = [BLEND_MODE_NORMAL, "multiply", "screen", "overlay"];
+
+// Drawing tools a room can allow or forbid, each naming the reducers it covers
+const TOOL_FREEHAND: &str = "freehand"; // add_drawing_point
+const TOOL_LINE: &str = "line"; // add_constrained_segment
+const TOOL_GRADIENT: &str = "gradient"; // add_gradient_stroke
+const TOOL_TAPER: &str = "taper"; // add_tapered_stroke
+const TOOL_SVG_IMPORT: &str = "svg_import"; // import_svg
+const TOOLS: [&str; 5] = [
+    TOOL_FREEHAND,
+    TOOL_LINE,
+    TOOL_GRADIENT,
+    TOOL_TAPER,
+    TOOL_SVG_IMPORT,
+];
+
+// Limits shared by every reducer that accepts brush settings
+const MIN_BRUSH_SIZE: f32 = 0.5;
+const MAX_BRUSH_SIZE: f32 = 100.0;
+const MAX_PRESET_NAME_LEN: usize = 32;
+const MAX_SIZE_PRESETS: usize = 9;
+const MAX_BRUSH_ASSIGNMENTS: usize = 500;
+const COLOR_HISTOGRAM_SIZE: usize = 16;
+const CANVAS_META_ID: u32 = 0;
+
+// Canvas the client renders onto, used by render manifests
+const CANVAS_WIDTH: u32 = 800;
+const CANVAS_HEIGHT: u32 = 600;
+// Side of the square spatial buckets points are grouped into
+const SPATIAL_BUCKET_SIZE: f32 = 100.0;
+// Widest range find_nearest_point searches, so a lookup stays a few buckets
+const MAX_NEAREST_POINT_DISTANCE: f32 = 2.0 * SPATIAL_BUCKET_SIZE;
+// Tile manifests: smallest tile side accepted and most tiles one may list
+const MIN_TILE_SIZE: f32 = 8.0;
+const MAX_MANIFEST_TILES: usize = 10_000;
+const CANVAS_BACKGROUND: &str = "#ffffff";
+const RENDER_MANIFEST_VERSION: u32 = 1;
+const MAX_IMPORT_ELEMENTS: usize = 5000;
+const USER_STATES_BUNDLE_VERSION: u32 = 1;
+// Most states import_user_states accepts from one bundle
+const MAX_BUNDLE_STATES: usize = 100;
+// Largest bundle export_user_states will write to export_result
+const MAX_EXPORT_BUNDLE_BYTES: usize = 4 * 1024 * 1024;
+// Time-lapse exports: format version and the most events one may hold
+const TIMELAPSE_VERSION: u32 = 1;
+const MAX_TIMELAPSE_EVENTS: usize = 50_000;
+// Chat transcripts: format version and the most messages one page may hold
+const CHAT_TRANSCRIPT_VERSION: u32 = 1;
+const MAX_CHAT_EXPORT_MESSAGES: usize = 1000;
+
+// Canvas history event kinds and retention
+const HISTORY_ADD: &str = "add";
+const HISTORY_UPDATE: &str = "update";
+const HISTORY_REMOVE: &str = "remove";
+const HISTORY_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const CURSOR_RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+// Cursors still connected but untouched for this long are marked idle_hidden
+const CURSOR_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// A drawing cursor untouched for this long stops showing as drawing
+const DRAWING_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAWING_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Typing indicators expire so a crashed client can't leave one stuck
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPING_CLEANUP_INTERVAL: Duration = Duration::from_secs(2);
+
+// Stroke highlights: how many recent strokes flash and for how long
+const FLASH_STROKE_COUNT: usize = 5;
+const HIGHLIGHT_DURATION: Duration = Duration::from_secs(3);
+const HIGHLIGHT_CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_CHAT_MESSAGE_LEN: usize = 500;
+const MAX_EMOJI_LEN: usize = 16; // In characters, enough for ZWJ sequences
+const PINNED_MESSAGE_ID: u32 = 0;
+const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+// Default brush colors handed out to new users so cursors are told apart
+const DEFAULT_COLOR_PALETTE: [&str; 10] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+    "#469990", "#000075",
+];
+
+// Periodic autosave: name of the states it writes and how many to keep
+const AUTOSAVE_NAME: &str = "autosave";
+// Placeholders an autosave name template may use: the autosave's running
+// number and the time it was taken. A template without either gets the time
+// appended so autosave names stay distinguishable.
+const AUTOSAVE_PLACEHOLDERS: [&str; 2] = ["{index}", "{time}"];
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const AUTOSAVES_KEPT: usize = 5;
+
+// Erase modes: remove only the touched points, or every point of any stroke
+// that is touched
+const ERASE_MODE_PIXEL: &str = "pixel";
+const ERASE_MODE_STROKE: &str = "stroke";
+
+// Most mirror axes symmetry drawing allows; each axis doubles the copies
+const MAX_SYMMETRY_AXES: u32 = 8;
+
+// Most points add_gradient_stroke and add_tapered_stroke accept in one call
+const MAX_STROKE_PATH_POINTS: usize = 1000;
+// Tapered strokes start and end at this fraction of their full size
+const TAPER_END_SCALE: f32 = 0.2;
+
+// close_stroke only joins ends that are at most this far apart, and only on
+// strokes with at least CLOSE_STROKE_MIN_POINTS points
+const CLOSE_STROKE_THRESHOLD: f32 = 40.0;
+const CLOSE_STROKE_MIN_POINTS: usize = 3;
+
+// dedup_strokes treats two strokes of one color as duplicates when their
+// bounding boxes and every point lie within this distance of each other
+const DEDUP_TOLERANCE: f32 = 2.0;
+
+// Most points a single drawn segment is split into
+const MAX_SEGMENT_POINTS: usize = 2000;
+
+// Bounds and default for the snapping grid spacing
+const MIN_SNAP_GRID_SIZE: f32 = 2.0;
+const MAX_SNAP_GRID_SIZE: f32 = 200.0;
+const DEFAULT_SNAP_GRID_SIZE: f32 = 20.0;
+
+// Accepted range for canvas_settings.coordinate_scale
+const MIN_COORDINATE_SCALE: f32 = 0.1;
+const MAX_COORDINATE_SCALE: f32 = 10.0;
+
+// How often points past their TTL are swept away
+const POINT_EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Longest path erase_along_path accepts in one call
+const MAX_ERASE_PATH_POINTS: usize = 256;
+
+// The eraser skips its own user's points this young
+const SELF_ERASE_PROTECTION: Duration = Duration::from_millis(100);
+
+// Soft eraser tuning: opacity removed at the eraser center per pass, and the
+// opacity below which a point is treated as gone and deleted
+const SOFT_ERASE_STRENGTH: f32 = 0.5;
+const MIN_VISIBLE_OPACITY: f32 = 0.05;
+
+// The base layer has no row in `layer`; it is always unlocked, visible and
+// stacked below every other layer
+const BASE_LAYER_ID: u64 = 0;
+const MAX_LAYER_NAME_LEN: usize = 32;
+const MAX_STROKE_LABEL_LEN: usize = 48;
+const CANVAS_SETTINGS_ID: u32 = 0;
+
+const MAX_STATE_NAME_LEN: usize = 64;
+const MAX_STATE_DESCRIPTION_LEN: usize = 500;
+const MAX_ROOM_TOPIC_LEN: usize = 120;
+const MAX_STATE_SEARCH_RESULTS: usize = 50;
+const MAX_STATE_QUERY_RESULTS: u32 = 100;
+
+// Most colors state_color_breakdown reports for one saved state
+const MAX_STATE_COLOR_STATS: usize = 32;
+
+// Slices of the circle analyze_stroke_directions sorts stroke angles into
+const ANGLE_HISTOGRAM_BINS: usize = 8;
+// action_result message when a load would discard unsaved drawing
+const CONFIRM_NEEDED: &str = "confirm_needed";
+// Refusal given by canvas-changing reducers while the canvas is frozen
+const CANVAS_FROZEN: &str = "The canvas is frozen";
+// Refusal given to spectators by canvas-changing reducers
+const SPECTATORS_CANT_DRAW: &str = "Spectators can't change the canvas";
+// Refusal given to non-members of a room with an access code
+const MEMBERS_ONLY: &str = "Join the room with its access code to draw";
+// Refusal given to new arrivals still inside the room's join cooldown
+const COOLDOWN_ACTIVE: &str = "Cooldown active: new arrivals must wait before changing the canvas";
+// action_result message when a save falls inside the quiet window
+const SAVE_BUSY: &str = "busy, retry";
+// state_load_log entries are kept this long, pruned with canvas_history
+const STATE_LOAD_LOG_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Brush a new cursor starts with unless an admin picks another
+const DEFAULT_BRUSH_SIZE: f32 = 3.0;
+
+// Offline identities' per-user rows are removed after this long by default
+const DEFAULT_GUEST_EXPIRY_SECONDS: u64 = 7 * 24 * 60 * 60;
+const GUEST_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Usage reports: an hourly snapshot, keeping a week of them
+const USAGE_REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_USAGE_REPORTS: usize = 7 * 24;
+
+// Fade clears: how often a step runs and the most steps one may take
+const FADE_CLEAR_TICK: Duration = Duration::from_millis(200);
+const MAX_FADE_CLEAR_STEPS: u32 = 100;
+
+// Room analytics
+const ROOM_ANALYTICS_ID: u32 = 0;
+const ACTIVE_USER_WINDOW: Duration = Duration::from_secs(5 * 60);
+const ANALYTICS_INTERVAL: Duration = Duration::from_secs(60);
+// With no drawing and no cursor movement for this long the room is idle, and
+// background upkeep pauses until someone comes back
+const ROOM_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+// How often archive_inactive_room checks the room's last activity
+const ARCHIVE_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How often expired state links are removed
+const STATE_LINK_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Accepted length of client-generated state link tokens, in characters
+const MIN_STATE_LINK_TOKEN_LEN: usize = 32;
+const MAX_STATE_LINK_TOKEN_LEN: usize = 128;
+
+// Saved state thumbnails are a grid of this many cells on each side
+const THUMBNAIL_GRID_SIZE: usize = 16;
+
+// quantize_palette accepts palettes of at most this many colors
+const MAX_PALETTE_COLORS: usize = 64;
+
+// Length limits for room access codes
+const MIN_ACCESS_CODE_LEN: usize = 8;
+const MAX_ACCESS_CODE_LEN: usize = 64;
+// Hashing rounds that make each access code guess deliberately slow
+const ACCESS_CODE_HASH_ROUNDS: u32 = 20_000;
+const ROOM_SECRET_ID: u32 = 0;
+// Wrong access codes a user may give in one window before join_room refuses
+const MAX_JOIN_FAILURES: u32 = 5;
+const JOIN_FAILURE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// Most points one selection can hold
+const MAX_SELECTION_POINTS: usize = 10_000;
+
+// Most decimal places canvas_settings.coordinate_precision may ask for
+const MAX_COORDINATE_PRECISION: u8 = 4;
+
+// Largest canvas_settings.min_point_spacing; any more would break strokes up
+const MAX_POINT_SPACING: f32 = 50.0;
+
+// Limits and default for canvas_settings.outlier_threshold
+const MIN_OUTLIER_THRESHOLD: f32 = 0.5;
+const MAX_OUTLIER_THRESHOLD: f32 = 10.0;
+const DEFAULT_OUTLIER_THRESHOLD: f32 = 3.0;
+
+// create_mirrored_copy refuses canvases with more points than this
+const MAX_MIRRORED_COPY_POINTS: usize = 20_000;
+
+// Zoom levels set_viewport accepts
+const MIN_VIEWPORT_ZOOM: f32 = 0.05;
+const MAX_VIEWPORT_ZOOM: f32 = 20.0;
+
+// Most points one saved state may hold; bigger canvases are refused or,
+// when asked, downsampled to fit
+const MAX_POINTS_PER_STATE: u64 = 50_000;
+// Color given to saved points whose color repair_canvas_state can't read
+const REPAIR_FALLBACK_COLOR: &str = "#000000";
+
+// Checks that a color is a "#rrggbb" hex string
+fn validate_color(color: &str) -> Result<(), String> {
+    let valid = color.len() == 7
+        && color.starts_with('#')
+        && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid color '{}', expected #rrggbb", color))
+    }
+}
+
+// Checks that a brush size is finite and within the global limits
+fn validate_size(size: f32) -> Result<(), String> {
+    if size.is_finite() && (MIN_BRUSH_SIZE..=MAX_BRUSH_SIZE).contains(&size) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid brush size {}, expected {} to {}",
+            size, MIN_BRUSH_SIZE, MAX_BRUSH_SIZE
+        ))
+    }
+}
+
+// Checks that an opacity is within 0.0..=1.0
+fn validate_opacity(opacity: f32) -> Result<(), String> {
+    if (0.0..=1.0).contains(&opacity) {
+        Ok(())
+    } else {
+        Err(format!("Invalid opacity {}, expected 0 to 1", opacity))
+    }
+}
+
+// Whether an eraser radius covers any area at all. Erase reducers return
+// before scanning the canvas when it doesn't.
+fn eraser_has_area(radius: f32) -> bool {
+    radius.is_finite() && radius > 0.0
+}
+
+// Whether the room lets an eraser centered at (x, y) run. With bound_erasers
+// set, only positions on the canvas do; otherwise every position does.
+fn eraser_in_bounds(ctx: &ReducerContext, x: f32, y: f32) -> bool {
+    !canvas_settings(ctx).bound_erasers || validate_position(x, y).is_ok()
+}
+
+// Returns true if a point's circle overlaps the circle at (x, y).
+// Brush `size` is a radius everywhere (the client draws each point as
+// `arc(x, y, size)` and erases with its brush size as `radius`), so two
+// circles overlap when their centers are within the sum of their radii.
+fn point_overlaps_circle(point: &CanvasPoint, x: f32, y: f32, radius: f32) -> bool {
+    let combined_radius = radius + point.size;
+    let dx = point.x - x;
+    let dy = point.y - y;
+
+    // Cheap per-axis rejection before computing the squared distance
+    if dx.abs() > combined_radius || dy.abs() > combined_radius {
+        return false;
+    }
+    dx * dx + dy * dy <= combined_radius * combined_radius
+}
+
+// Returns true if a point's circle overlaps the capsule swept by an eraser of
+// the given radius moving from (ax, ay) to (bx, by)
+fn point_overlaps_segment(point: &CanvasPoint, a: &PathPoint, b: &PathPoint, radius: f32) -> bool {
+    // Project the point onto the segment and test against the nearest spot
+    let (ax, ay) = (a.x, a.y);
+    let (sx, sy) = (b.x - ax, b.y - ay);
+    let length_sq = sx * sx + sy * sy;
+    let t = if length_sq > 0.0 {
+        (((point.x - ax) * sx + (point.y - ay) * sy) / length_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    point_overlaps_circle(point, ax + t * sx, ay + t * sy, radius)
+}
+
+// Returns true if a point's circle overlaps the axis-aligned square of side
+// 2 * `radius` centered on (x, y)
+fn point_overlaps_square(point: &CanvasPoint, x: f32, y: f32, radius: f32) -> bool {
+    // Distance from the point's center to the nearest spot in the square
+    let dx = (point.x - x).abs() - radius;
+    let dy = (point.y - y).abs() - radius;
+    let (dx, dy) = (dx.max(0.0), dy.max(0.0));
+    dx * dx + dy * dy <= point.size * point.size
+}
+
+// Footprint of the eraser around its center
+#[derive(Clone, Copy)]
+enum EraserShape {
+    Circle, // Radius `radius`
+    Square, // Side 2 * `radius`, axis-aligned
+}
+
+impl EraserShape {
+    // Returns true if a point's circle overlaps this eraser at (x, y)
+    fn overlaps(self, point: &CanvasPoint, x: f32, y: f32, radius: f32) -> bool {
+        match self {
+            EraserShape::Circle => point_overlaps_circle(point, x, y, radius),
+            EraserShape::Square => point_overlaps_square(point, x, y, radius),
+        }
+    }
+}
+
+// Returns the canvas meta row, creating it on first use
+fn canvas_meta(ctx: &ReducerContext) -> CanvasMeta {
+    ctx.db
+        .canvas_meta()
+        .id()
+        .find(CANVAS_META_ID)
+        .unwrap_or_else(|| {
+            ctx.db.canvas_meta().insert(CanvasMeta {
+                id: CANVAS_META_ID,
+                point_count: ctx.db.canvas_point().count(),
+                next_draw_order: ctx
+                    .db
+                    .canvas_point()
+                    .iter()
+                    .map(|point| point.draw_order + 1)
+                    .max()
+                    .unwrap_or(1),
+                revision: 0,
+                autosaved_revision: 0,
+                saved_revision: 0,
+                autosaves_written: 0,
+                history_seq: ctx
+                    .db
+                    .canvas_history()
+                    .iter()
+                    .map(|event| event.seq)
+                    .max()
+                    .unwrap_or(0),
+            })
+        })
+}
+
+// A change to canvas_point, as seen by canvas_meta.point_count
+#[derive(Clone, Copy, Debug)]
+enum PointCountChange {
+    Inserted,    // One point added
+    Deleted,     // One point removed
+    Cleared,     // Every point removed
+    Loaded(u64), // The canvas replaced by this many loaded points
+}
+
+// The point count after a change to canvas_point. A delete never takes the
+// count below zero, even if it had drifted.
+fn next_point_count(point_count: u64, change: PointCountChange) -> u64 {
+    match change {
+        PointCountChange::Inserted => point_count + 1,
+        PointCountChange::Deleted => point_count.saturating_sub(1),
+        PointCountChange::Cleared => 0,
+        PointCountChange::Loaded(loaded) => loaded,
+    }
+}
+
+// Stores a new point count in the canvas meta row and bumps the revision
+fn set_point_count(ctx: &ReducerContext, point_count: u64) {
+    let meta = canvas_meta(ctx);
+    ctx.db.canvas_meta().id().update(CanvasMeta {
+        point_count,
+        revision: meta.revision + 1,
+        ..meta
+    });
+}
+
+// Builds a fully opaque, loose point owned by the caller. Use struct update
+// syntax to override any other field before inserting it.
+fn new_canvas_point(ctx: &ReducerContext, x: f32, y: f32, color: String, size: f32) -> CanvasPoint {
+    CanvasPoint {
+        id: 0, // Will be auto-incremented
+        identity: ctx.sender,
+        x,
+        y,
+        color,
+        size,
+        opacity: 1.0,
+        draw_order: 0, // Assigned on insert
+        stroke_id: 0,
+        layer_id: BASE_LAYER_ID,
+        mirror_of: 0,
+        origin: ORIGIN_DRAWN.to_string(),
+        blend_mode: BLEND_MODE_NORMAL.to_string(),
+        timestamp: ctx.timestamp,
+        bucket_x: 0, // Assigned on insert
+        bucket_y: 0,
+    }
+}
+
+// Returns the stroke an identity is currently drawing, if any
+fn open_stroke(ctx: &ReducerContext, identity: Identity) -> Option<StrokeMeta> {
+    let active = ctx.db.active_stroke().identity().find(identity)?;
+    ctx.db
+        .stroke_meta()
+        .stroke_id()
+        .find(active.stroke_id)
+        .filter(|stroke| stroke.ended_at.is_none())
+}
+
+// Marks a stroke as finished, or deletes it if it has no points: a stroke
+// that ends empty (all erased, or never drawn) would only be orphaned metadata
+fn finish_stroke(ctx: &ReducerContext, stroke: StrokeMeta) {
+    let has_points = ctx
+        .db
+        .canvas_point()
+        .stroke_id()
+        .filter(stroke.stroke_id)
+        .next()
+        .is_some();
+    match finished_stroke(stroke, has_points, ctx.timestamp) {
+        FinishedStroke::Dropped(stroke) => {
+            ctx.db.stroke_meta().delete(stroke);
+        }
+        FinishedStroke::Ended(stroke) => {
+            ctx.db.stroke_meta().stroke_id().update(stroke);
+        }
+        FinishedStroke::Unchanged => {}
+    }
+}
+
+// What finishing a stroke does to its meta row
+enum FinishedStroke {
+    Dropped(StrokeMeta), // Had no points, so the row is deleted
+    Ended(StrokeMeta),   // Was open, and now ends at the finishing time
+    Unchanged,           // Had already ended
+}
+
+// Decides how finishing a stroke changes its meta row, given whether any
+// canvas points still belong to it
+fn finished_stroke(stroke: StrokeMeta, has_points: bool, now: Timestamp) -> FinishedStroke {
+    if !has_points {
+        FinishedStroke::Dropped(stroke)
+    } else if stroke.ended_at.is_none() {
+        FinishedStroke::Ended(StrokeMeta {
+            ended_at: Some(now),
+            ..stroke
+        })
+    } else {
+        FinishedStroke::Unchanged
+    }
+}
+
+// The strokes begin_stroke must finish before opening a new one: every one
+// of the caller's strokes that is still open
+fn dangling_strokes(strokes: impl IntoIterator<Item = StrokeMeta>) -> Vec<StrokeMeta> {
+    strokes
+        .into_iter()
+        .filter(|stroke| stroke.ended_at.is_none())
+        .collect()
+}
+
+// Appends a canvas_history event describing a point
+fn record_history(ctx: &ReducerContext, kind: &str, point: &CanvasPoint) {
+    let meta = canvas_meta(ctx);
+    let event = ctx.db.canvas_history().insert(CanvasHistory {
+        seq: 0, // Will be auto-incremented
+        kind: kind.to_string(),
+        point_id: point.id,
+        identity: point.identity,
+        x: point.x,
+        y: point.y,
+        color: point.color.clone(),
+        size: point.size,
+        opacity: point.opacity,
+        recorded_at: ctx.timestamp,
+    });
+    ctx.db.canvas_meta().id().update(CanvasMeta {
+        history_seq: event.seq,
+        ..meta
+    });
+}
+
+// The seq of the newest canvas_history event, or 0 when there is none
+fn latest_history_seq(ctx: &ReducerContext) -> u64 {
+    canvas_meta(ctx).history_seq
+}
+
+// The spatial bucket a canvas position falls in
+fn spatial_bucket(x: f32, y: f32) -> (u32, u32) {
+    let bucket = |v: f32| (v / SPATIAL_BUCKET_SIZE).floor().max(0.0) as u32;
+    (bucket(x), bucket(y))
+}
+
+// Canvas points whose centers may lie within `reach` of (x, y), found through
+// the spatial bucket index. Callers still test each point exactly.
+fn points_near(ctx: &ReducerContext, x: f32, y: f32, reach: f32) -> Vec<CanvasPoint> {
+    points_in_box(
+        ctx,
+        Bounds {
+            min_x: x - reach,
+            min_y: y - reach,
+            max_x: x + reach,
+            max_y: y + reach,
+        },
+    )
+}
+
+// Canvas points whose centers may lie within a box, found through the spatial
+// bucket index. Callers still test each point exactly.
+fn points_in_box(ctx: &ReducerContext, area: Bounds) -> Vec<CanvasPoint> {
+    let (min_bx, min_by) = spatial_bucket(area.min_x, area.min_y);
+    let (max_bx, max_by) = spatial_bucket(area.max_x, area.max_y);
+    ctx.db
+        .canvas_point()
+        .bucket_x()
+        .filter(min_bx..=max_bx)
+        .filter(|point| (min_by..=max_by).contains(&point.bucket_y))
+        .collect()
+}
+
+// Inserts a canvas point, assigns its draw order and spatial bucket and keeps
+// canvas_meta and canvas_history in sync. Every insert into canvas_point
+// should go through here.
+fn insert_canvas_point(ctx: &ReducerContext, point: CanvasPoint) -> CanvasPoint {
+    let meta = canvas_meta(ctx);
+    let (bucket_x, bucket_y) = spatial_bucket(point.x, point.y);
+    let point = ctx.db.canvas_point().insert(CanvasPoint {
+        draw_order: meta.next_draw_order,
+        bucket_x,
+        bucket_y,
+        ..point
+    });
+    ctx.db.canvas_meta().id().update(CanvasMeta {
+        point_count: next_point_count(meta.point_count, PointCountChange::Inserted),
+        next_draw_order: meta.next_draw_order + 1,
+        revision: meta.revision + 1,
+        ..meta
+    });
+    record_history(ctx, HISTORY_ADD, &point);
+    if point.stroke_id != 0 {
+        index_stroke_point(ctx, &point);
+    }
+    grow_canvas_bounds(ctx, &point);
+    point
+}
+
+// Returns the canvas bounds row, creating it from a scan on first use
+fn canvas_bounds(ctx: &ReducerContext) -> CanvasBounds {
+    ctx.db
+        .canvas_bounds()
+        .id()
+        .find(CANVAS_META_ID)
+        .unwrap_or_else(|| ctx.db.canvas_bounds().insert(scanned_canvas_bounds(ctx)))
+}
+
+// Computes the canvas bounds row from every point on the canvas
+fn scanned_canvas_bounds(ctx: &ReducerContext) -> CanvasBounds {
+    let bounds = Bounds::of_circles(ctx.db.canvas_point().iter().map(|p| (p.x, p.y, p.size)));
+    CanvasBounds {
+        id: CANVAS_META_ID,
+        has_points: bounds.is_some(),
+        min_x: bounds.map_or(0.0, |b| b.min_x),
+        min_y: bounds.map_or(0.0, |b| b.min_y),
+        max_x: bounds.map_or(0.0, |b| b.max_x),
+        max_y: bounds.map_or(0.0, |b| b.max_y),
+        stale: false,
+    }
+}
+
+// Rewrites the canvas bounds from a full scan of canvas_point
+fn recompute_canvas_bounds_row(ctx: &ReducerContext) -> CanvasBounds {
+    canvas_bounds(ctx);
+    ctx.db
+        .canvas_bounds()
+        .id()
+        .update(scanned_canvas_bounds(ctx))
+}
+
+// Rescans the canvas bounds if a removal left them stale
+fn refresh_canvas_bounds(ctx: &ReducerContext) {
+    if canvas_bounds(ctx).stale {
+        recompute_canvas_bounds_row(ctx);
+    }
+}
+
+// Widens the canvas bounds to take in a point's brush circle
+fn grow_canvas_bounds(ctx: &ReducerContext, point: &CanvasPoint) {
+    let bounds = canvas_bounds(ctx);
+    let (min_x, min_y) = (point.x - point.size, point.y - point.size);
+    let (max_x, max_y) = (point.x + point.size, point.y + point.size);
+    if !bounds.has_points {
+        ctx.db.canvas_bounds().id().update(CanvasBounds {
+            has_points: true,
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+            ..bounds
+        });
+    } else if min_x < bounds.min_x
+        || min_y < bounds.min_y
+        || max_x > bounds.max_x
+        || max_y > bounds.max_y
+    {
+        ctx.db.canvas_bounds().id().update(CanvasBounds {
+            min_x: bounds.min_x.min(min_x),
+            min_y: bounds.min_y.min(min_y),
+            max_x: bounds.max_x.max(max_x),
+            max_y: bounds.max_y.max(max_y),
+            ..bounds
+        });
+    }
+}
+
+// Marks the canvas bounds stale when a point leaving the canvas touched their
+// edge, since only a rescan can tell how far they shrink
+fn release_canvas_bounds(ctx: &ReducerContext, point: &CanvasPoint) {
+    let bounds = canvas_bounds(ctx);
+    let on_edge = point.x - point.size <= bounds.min_x
+        || point.y - point.size <= bounds.min_y
+        || point.x + point.size >= bounds.max_x
+        || point.y + point.size >= bounds.max_y;
+    if on_edge && !bounds.stale {
+        ctx.db.canvas_bounds().id().update(CanvasBounds {
+            stale: true,
+            ..bounds
+        });
+    }
+}
+
+// Grows a stroke's index entry to take in a newly inserted point
+fn index_stroke_point(ctx: &ReducerContext, point: &CanvasPoint) {
+    match ctx.db.stroke_index().stroke_id().find(point.stroke_id) {
+        Some(row) => {
+            ctx.db.stroke_index().stroke_id().update(StrokeIndex {
+                point_count: row.point_count + 1,
+                min_x: row.min_x.min(point.x - point.size),
+                min_y: row.min_y.min(point.y - point.size),
+                max_x: row.max_x.max(point.x + point.size),
+                max_y: row.max_y.max(point.y + point.size),
+                ..row
+            });
+        }
+        None => {
+            ctx.db.stroke_index().insert(StrokeIndex {
+                stroke_id: point.stroke_id,
+                identity: point.identity,
+                point_count: 1,
+                min_x: point.x - point.size,
+                min_y: point.y - point.size,
+                max_x: point.x + point.size,
+                max_y: point.y + point.size,
+                color: point.color.clone(),
+            });
+        }
+    }
+}
+
+// Recomputes a stroke's index entry from its points, dropping it once the
+// stroke has none
+fn reindex_stroke(ctx: &ReducerContext, stroke_id: u64) {
+    let mut points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .stroke_id()
+        .filter(stroke_id)
+        .collect();
+    ctx.db.stroke_index().stroke_id().delete(stroke_id);
+    let Some(bounds) = Bounds::of_circles(points.iter().map(|p| (p.x, p.y, p.size))) else {
+        // Nothing is left to name or hide once a stroke's points are gone
+        ctx.db.stroke_label().stroke_id().delete(stroke_id);
+        ctx.db.stroke_visibility().stroke_id().delete(stroke_id);
+        return;
+    };
+    points.sort_by_key(|point| point.draw_order);
+    let first = &points[0];
+    ctx.db.stroke_index().insert(StrokeIndex {
+        stroke_id,
+        identity: first.identity,
+        point_count: points.len() as u64,
+        min_x: bounds.min_x,
+        min_y: bounds.min_y,
+        max_x: bounds.max_x,
+        max_y: bounds.max_y,
+        color: first.color.clone(),
+    });
+}
+
+// Rewrites stroke_index from canvas_point in one pass over the points,
+// returning how many strokes were indexed
+fn rebuild_stroke_index_rows(ctx: &ReducerContext) -> usize {
+    let mut strokes: HashMap<u64, Vec<CanvasPoint>> = HashMap::new();
+    for point in ctx.db.canvas_point().iter() {
+        if point.stroke_id != 0 {
+            strokes.entry(point.stroke_id).or_default().push(point);
+        }
+    }
+
+    let indexed: Vec<StrokeIndex> = ctx.db.stroke_index().iter().collect();
+    for row in indexed {
+        ctx.db.stroke_index().delete(row);
+    }
+    let reindexed = strokes.len();
+    for (stroke_id, points) in strokes {
+        let bounds = Bounds::of_circles(points.iter().map(|p| (p.x, p.y, p.size)))
+            .expect("strokes here have points");
+        let first = points
+            .iter()
+            .min_by_key(|point| point.draw_order)
+            .expect("strokes here have points");
+        ctx.db.stroke_index().insert(StrokeIndex {
+            stroke_id,
+            identity: first.identity,
+            point_count: points.len() as u64,
+            min_x: bounds.min_x,
+            min_y: bounds.min_y,
+            max_x: bounds.max_x,
+            max_y: bounds.max_y,
+            color: first.color.clone(),
+        });
+    }
+    reindexed
+}
+
+// Writes back a single modified canvas point through a one-write PointBatch
+fn update_canvas_point(ctx: &ReducerContext, point: CanvasPoint) -> CanvasPoint {
+    let mut batch = PointBatch::new(ctx);
+    let point = batch.update(point);
+    batch.finish();
+    point
+}
+
+// A run of point updates and deletes that keeps canvas_meta, canvas_history
+// and the canvas bounds in sync on every write. Each stroke touched is
+// reindexed once, in `finish`, instead of reread after every write, so a
+// stroke-wide edit stays linear. Every update to and delete from canvas_point
+// should go through one.
+struct PointBatch<'a> {
+    ctx: &'a ReducerContext,
+    strokes: HashSet<u64>, // Strokes whose stroke_index entries are out of date
+}
+
+impl<'a> PointBatch<'a> {
+    fn new(ctx: &'a ReducerContext) -> PointBatch<'a> {
+        PointBatch {
+            ctx,
+            strokes: HashSet::new(),
+        }
+    }
+
+    // Writes back a modified canvas point
+    fn update(&mut self, point: CanvasPoint) -> CanvasPoint {
+        let ctx = self.ctx;
+        let old = ctx.db.canvas_point().id().find(point.id);
+        // A moved point may have crossed into another spatial bucket
+        let (bucket_x, bucket_y) = spatial_bucket(point.x, point.y);
+        let point = ctx.db.canvas_point().id().update(CanvasPoint {
+            bucket_x,
+            bucket_y,
+            ..point
+        });
+        set_point_count(ctx, canvas_meta(ctx).point_count);
+        record_history(ctx, HISTORY_UPDATE, &point);
+        if let Some(old) = &old {
+            release_canvas_bounds(ctx, old);
+            // A point moved out of a stroke changes that stroke's entry too
+            self.touch(old.stroke_id);
+        }
+        grow_canvas_bounds(ctx, &point);
+        self.touch(point.stroke_id);
+        point
+    }
+
+    // Deletes a canvas point
+    fn delete(&mut self, point: CanvasPoint) {
+        let ctx = self.ctx;
+        record_history(ctx, HISTORY_REMOVE, &point);
+        release_canvas_bounds(ctx, &point);
+        let stroke_id = point.stroke_id;
+        if ctx.db.canvas_point().delete(point) {
+            set_point_count(
+                ctx,
+                next_point_count(canvas_meta(ctx).point_count, PointCountChange::Deleted),
+            );
+            if stroke_id != 0 {
+                release_stroke_point(ctx, stroke_id);
+                self.touch(stroke_id);
+            }
+        }
+    }
+
+    fn touch(&mut self, stroke_id: u64) {
+        if stroke_id != 0 {
+            self.strokes.insert(stroke_id);
+        }
+    }
+
+    // Brings the index entry of every touched stroke up to date
+    fn finish(self) {
+        for stroke_id in self.strokes {
+            reindex_stroke(self.ctx, stroke_id);
+        }
+    }
+}
+
+// Accounts for a stroke losing a point. A finished stroke with no points left
+// is removed so its metadata isn't orphaned; an open one stays for its owner
+// to keep drawing into.
+fn release_stroke_point(ctx: &ReducerContext, stroke_id: u64) {
+    let Some(stroke) = ctx.db.stroke_meta().stroke_id().find(stroke_id) else {
+        return;
+    };
+    let has_points = ctx
+        .db
+        .canvas_point()
+        .stroke_id()
+        .filter(stroke_id)
+        .next()
+        .is_some();
+    if stroke_meta_orphaned(&stroke, has_points) {
+        ctx.db.stroke_meta().delete(stroke);
+    } else {
+        ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+            point_count: stroke.point_count.saturating_sub(1),
+            ..stroke
+        });
+    }
+}
+
+// Whether a stroke's metadata has outlived its points: a finished stroke
+// with none left is dropped, an open one is kept for more drawing
+fn stroke_meta_orphaned(stroke: &StrokeMeta, has_points: bool) -> bool {
+    !has_points && stroke.ended_at.is_some()
+}
+
+// Deletes every canvas point and resets the point count
+fn clear_canvas_points(ctx: &ReducerContext) {
+    for point in ctx.db.canvas_point().iter() {
+        record_history(ctx, HISTORY_REMOVE, &point);
+        ctx.db.canvas_point().delete(point);
+    }
+    set_point_count(
+        ctx,
+        next_point_count(canvas_meta(ctx).point_count, PointCountChange::Cleared),
+    );
+    recompute_canvas_bounds_row(ctx);
+
+    let indexed: Vec<StrokeIndex> = ctx.db.stroke_index().iter().collect();
+    for row in indexed {
+        ctx.db.stroke_index().delete(row);
+    }
+    let labels: Vec<StrokeLabel> = ctx.db.stroke_label().iter().collect();
+    for label in labels {
+        ctx.db.stroke_label().delete(label);
+    }
+    let visibility: Vec<StrokeVisibility> = ctx.db.stroke_visibility().iter().collect();
+    for row in visibility {
+        ctx.db.stroke_visibility().delete(row);
+    }
+
+    // Every stroke is empty now: drop the finished ones, reset the open ones
+    let strokes: Vec<StrokeMeta> = ctx.db.stroke_meta().iter().collect();
+    for stroke in strokes {
+        if stroke_meta_orphaned(&stroke, false) {
+            ctx.db.stroke_meta().delete(stroke);
+        } else if stroke.point_count != 0 {
+            ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+                point_count: 0,
+                ..stroke
+            });
+        }
+    }
+}
+
+// Axis-aligned extent of a set of brush circles
+#[derive(Clone, Copy)]
+struct Bounds {
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+}
+
+impl Bounds {
+    // Returns the extent covering every (x, y, radius) circle, or None if
+    // there are no circles
+    fn of_circles(circles: impl IntoIterator<Item = (f32, f32, f32)>) -> Option<Bounds> {
+        circles.into_iter().fold(None, |bounds, (x, y, r)| {
+            let circle = Bounds {
+                min_x: x - r,
+                min_y: y - r,
+                max_x: x + r,
+                max_y: y + r,
+            };
+            Some(match bounds {
+                Some(b) => Bounds {
+                    min_x: b.min_x.min(circle.min_x),
+                    min_y: b.min_y.min(circle.min_y),
+                    max_x: b.max_x.max(circle.max_x),
+                    max_y: b.max_y.max(circle.max_y),
+                },
+                None => circle,
+            })
+        })
+    }
+
+    fn width(&self) -> f32 {
+        self.max_x - self.min_x
+    }
+
+    fn height(&self) -> f32 {
+        self.max_y - self.min_y
+    }
+
+    fn center(&self) -> (f32, f32) {
+        (
+            (self.min_x + self.max_x) / 2.0,
+            (self.min_y + self.max_y) / 2.0,
+        )
+    }
+}
+
+// Splits the inside of an XML start tag into (name, value) attribute pairs
+fn parse_attributes(tag: &str) -> Vec<(&str, &str)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag;
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].split_whitespace().last().unwrap_or("");
+        let after = rest[eq + 1..].trim_start();
+        let Some(quote) = after.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            break;
+        };
+        let Some(end) = after[1..].find(quote) else {
+            break;
+        };
+        attributes.push((name, &after[1..end + 1]));
+        rest = &after[end + 2..];
+    }
+    attributes
+}
+
+// Converts an SVG <circle> start tag into a point owned by the caller,
+// returning None if it is missing attributes or has invalid values
+fn svg_circle_to_point(ctx: &ReducerContext, tag: &str) -> Option<CanvasPoint> {
+    let attributes = parse_attributes(tag);
+    let attr = |name: &str| {
+        attributes
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.trim())
+    };
+    let number = |name: &str| attr(name)?.parse::<f32>().ok().filter(|v| v.is_finite());
+
+    let x = number("cx")?;
+    let y = number("cy")?;
+    let size = number("r")?;
+    let color = attr("fill")?.to_ascii_lowercase();
+    let opacity = match attr("fill-opacity") {
+        Some(_) => number("fill-opacity")?,
+        None => 1.0,
+    };
+    validate_size(size).ok()?;
+    validate_color(&color).ok()?;
+    validate_opacity(opacity).ok()?;
+
+    Some(CanvasPoint {
+        opacity,
+        origin: ORIGIN_LOADED.to_string(),
+        ..new_canvas_point(ctx, x, y, color, size)
+    })
+}
+
+// Returns a saved state's points in the paint order they were saved in
+fn saved_points_in_order(ctx: &ReducerContext, state_id: u64) -> Vec<SavedCanvasPoint> {
+    let mut saved_points: Vec<SavedCanvasPoint> = ctx
+        .db
+        .saved_canvas_point()
+        .state_id()
+        .filter(state_id)
+        .collect();
+    saved_points.sort_by_key(|p| p.id);
+    saved_points
+}
+
+// Saves every live canvas point, in paint order, as a new canvas state
+fn save_canvas_points(
+    ctx: &ReducerContext,
+    name: String,
+    description: String,
+    is_autosave: bool,
+) -> CanvasState {
+    // Insert the state metadata
+    let state = ctx.db.canvas_state().insert(CanvasState {
+        id: 0, // Will be auto-incremented
+        name,
+        created_by: ctx.sender,
+        created_at: ctx.timestamp,
+        is_autosave,
+        is_locked: false,
+        point_count: ctx.db.canvas_point().count(),
+        description,
+        content_hash: String::new(), // Written once the points are saved
+        history_seq: latest_history_seq(ctx),
+    });
+
+    write_saved_points(ctx, state.id);
+    write_state_thumbnail(ctx, state.id);
+    write_state_content_hash(ctx, state)
+}
+
+// Creates a canvas state owned by the caller holding just the given points.
+// Unlike save_canvas_points this doesn't mark the live canvas as saved.
+fn save_points_as_state(
+    ctx: &ReducerContext,
+    name: String,
+    description: String,
+    points: Vec<CanvasPoint>,
+) -> CanvasState {
+    let state = ctx.db.canvas_state().insert(CanvasState {
+        id: 0, // Will be auto-incremented
+        name,
+        created_by: ctx.sender,
+        created_at: ctx.timestamp,
+        is_autosave: false,
+        is_locked: false,
+        point_count: points.len() as u64,
+        description,
+        content_hash: String::new(), // Written once the points are saved
+        history_seq: 0,
+    });
+    insert_saved_points(ctx, state.id, points);
+    write_state_thumbnail(ctx, state.id);
+    write_state_content_hash(ctx, state)
+}
+
+// Refuses to save the live canvas when it has more than MAX_POINTS_PER_STATE
+// points
+fn check_state_size(ctx: &ReducerContext) -> Result<(), String> {
+    let point_count = ctx.db.canvas_point().count();
+    if point_count > MAX_POINTS_PER_STATE {
+        return Err(format!(
+            "The canvas has {} points, more than the {} a saved state can hold",
+            point_count, MAX_POINTS_PER_STATE
+        ));
+    }
+    Ok(())
+}
+
+// Saves all current canvas points under a state, returning how many were
+// written. Points are written in paint order so loading recreates the same
+// stacking.
+fn write_saved_points(ctx: &ReducerContext, state_id: u64) -> usize {
+    let points: Vec<CanvasPoint> = ctx.db.canvas_point().iter().collect();
+    let point_count = insert_saved_points(ctx, state_id, points);
+    mark_canvas_saved(ctx);
+    point_count
+}
+
+// Saves the given canvas points under a state in paint order, returning how
+// many were written
+fn insert_saved_points(ctx: &ReducerContext, state_id: u64, mut points: Vec<CanvasPoint>) -> usize {
+    points.sort_by_key(|point| point.draw_order);
+    let point_count = points.len();
+    for point in points {
+        ctx.db
+            .saved_canvas_point()
+            .insert(to_saved_point(state_id, &point));
+    }
+    point_count
+}
+
+// The saved form of a canvas point, under the given state
+fn to_saved_point(state_id: u64, point: &CanvasPoint) -> SavedCanvasPoint {
+    SavedCanvasPoint {
+        id: 0, // Will be auto-incremented
+        state_id,
+        x: point.x,
+        y: point.y,
+        color: point.color.clone(),
+        size: point.size,
+        opacity: point.opacity,
+        origin: point.origin.clone(),
+        blend_mode: point.blend_mode.clone(),
+        drawn_at: point.timestamp,
+    }
+}
+
+// Replaces the live canvas with a saved state's points, returning how many
+// points were restored. The points are stamped with the load time unless
+// `preserve_timestamps` keeps the times they were originally drawn at.
+fn restore_canvas_state(
+    ctx: &ReducerContext,
+    state: &CanvasState,
+    preserve_timestamps: bool,
+) -> usize {
+    clear_canvas_points(ctx);
+
+    let saved_points = saved_points_in_order(ctx, state.id);
+    let point_count = saved_points.len();
+
+    // Recreate each saved point on the current canvas
+    for saved_point in saved_points {
+        // The person loading becomes the owner
+        insert_canvas_point(
+            ctx,
+            CanvasPoint {
+                opacity: saved_point.opacity,
+                origin: ORIGIN_LOADED.to_string(),
+                blend_mode: saved_point.blend_mode,
+                timestamp: if preserve_timestamps {
+                    saved_point.drawn_at
+                } else {
+                    ctx.timestamp
+                },
+                ..new_canvas_point(
+                    ctx,
+                    saved_point.x,
+                    saved_point.y,
+                    saved_point.color,
+                    saved_point.size,
+                )
+            },
+        );
+    }
+
+    // The load replaced the canvas, so its total is exactly what was loaded
+    set_point_count(
+        ctx,
+        next_point_count(
+            canvas_meta(ctx).point_count,
+            PointCountChange::Loaded(point_count as u64),
+        ),
+    );
+    mark_canvas_saved(ctx);
+    point_count
+}
+
+// Deletes a saved state together with all of its saved points
+fn delete_canvas_state_and_points(ctx: &ReducerContext, state: CanvasState) {
+    let points_to_delete: Vec<SavedCanvasPoint> = ctx
+        .db
+        .saved_canvas_point()
+        .state_id()
+        .filter(state.id)
+        .collect();
+
+    for point in points_to_delete {
+        ctx.db.saved_canvas_point().delete(point);
+    }
+
+    let loads: Vec<StateLoadLog> = ctx
+        .db
+        .state_load_log()
+        .state_id()
+        .filter(state.id)
+        .collect();
+    for load in loads {
+        ctx.db.state_load_log().delete(load);
+    }
+
+    ctx.db.state_thumbnail().state_id().delete(state.id);
+    ctx.db.canvas_state().delete(state);
+}
+
+// Loads the most recent autosave if the live canvas is empty, so a canvas
+// that was reset comes back as it was last autosaved
+fn restore_latest_autosave(ctx: &ReducerContext) {
+    if ctx.db.canvas_point().count() > 0 {
+        return;
+    }
+
+    let latest = ctx
+        .db
+        .canvas_state()
+        .iter()
+        .filter(|state| state.is_autosave)
+        .max_by_key(|state| state.created_at);
+    if let Some(state) = latest {
+        let restored = restore_canvas_state(ctx, &state, false);
+        log::info!(
+            "Restored {} points from autosave {} ({})",
+            restored,
+            state.id,
+            state.name
+        );
+    }
+}
+
+// Loads the room template onto a canvas nobody has ever drawn on. Once any
+// point has existed the canvas counts as used, so clearing it doesn't bring
+// the template back. The user who triggers this owns the template's points.
+fn apply_room_template(ctx: &ReducerContext) {
+    let Some(state_id) = canvas_settings(ctx).room_template_state_id else {
+        return;
+    };
+    let meta = canvas_meta(ctx);
+    if meta.point_count > 0 || meta.next_draw_order > 1 {
+        return;
+    }
+
+    match ctx.db.canvas_state().id().find(state_id) {
+        Some(state) => {
+            let restored = restore_canvas_state(ctx, &state, false);
+            log::info!(
+                "Started the canvas from template state {} with {} points",
+                state_id,
+                restored
+            );
+        }
+        None => log::warn!("Room template state {} no longer exists", state_id),
+    }
+}
+
+// Stores an export for the caller, replacing their previous one
+fn write_export(ctx: &ReducerContext, format: &str, content: String) {
+    let result = ExportResult {
+        identity: ctx.sender,
+        format: format.to_string(),
+        content,
+        created_at: ctx.timestamp,
+    };
+    if ctx.db.export_result().identity().find(ctx.sender).is_some() {
+        ctx.db.export_result().identity().update(result);
+    } else {
+        ctx.db.export_result().insert(result);
+    }
+}
+
+// Escapes a value for use inside an XML attribute
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Renders points as an SVG document cropped tightly to their extent.
+// Coordinates are translated so the top-left of the drawing is 0,0.
+fn render_svg(points: &[CanvasPoint]) -> String {
+    let Some(bounds) = Bounds::of_circles(points.iter().map(|p| (p.x, p.y, p.size))) else {
+        return r#"<svg xmlns="http://www.w3.org/2000/svg" width="0" height="0" viewBox="0 0 0 0"/>"#
+            .to_string();
+    };
+    let (min_x, min_y) = (bounds.min_x, bounds.min_y);
+    let (width, height) = (bounds.width(), bounds.height());
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+    for point in points {
+        svg.push_str(&format!(
+            r#"<circle cx="{}" cy="{}" r="{}" fill="{}" fill-opacity="{}"/>"#,
+            point.x - min_x,
+            point.y - min_y,
+            point.size,
+            xml_escape(&point.color),
+            point.opacity
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+// Removes every erase preview row belonging to an identity
+fn clear_erase_preview_for(ctx: &ReducerContext, identity: Identity) {
+    let rows: Vec<ErasePreview> = ctx.db.erase_preview().identity().filter(identity).collect();
+    for row in rows {
+        ctx.db.erase_preview().delete(row);
+    }
+}
+
+// Picks a palette color for the caller, starting from a slot derived from
+// their identity (so it is stable across reconnects) and skipping colors
+// other online users already have
+fn default_color_for(ctx: &ReducerContext) -> String {
+    let hash = ctx.sender.to_byte_array().iter().fold(0usize, |acc, b| {
+        acc.wrapping_mul(31).wrapping_add(*b as usize)
+    });
+    let start = hash % DEFAULT_COLOR_PALETTE.len();
+
+    let in_use: Vec<String> = ctx
+        .db
+        .cursor()
+        .iter()
+        .filter(|cursor| cursor.identity != ctx.sender)
+        .map(|cursor| cursor.color)
+        .collect();
+    (0..DEFAULT_COLOR_PALETTE.len())
+        .map(|offset| DEFAULT_COLOR_PALETTE[(start + offset) % DEFAULT_COLOR_PALETTE.len()])
+        .find(|color| !in_use.iter().any(|used| used == color))
+        .unwrap_or(DEFAULT_COLOR_PALETTE[start])
+        .to_string()
+}
+
+// Sets whether a user's cursor shows them as drawing
+fn set_cursor_drawing(ctx: &ReducerContext, identity: Identity, is_drawing: bool) {
+    if let Some(cursor) = ctx.db.cursor().identity().find(identity) {
+        if cursor.is_drawing != is_drawing {
+            ctx.db.cursor().identity().update(Cursor {
+                is_drawing,
+                last_updated: ctx.timestamp,
+                ..cursor
+            });
+        }
+    }
+}
+
+// Builds the caller's cursor at the origin with the default brush
+fn default_cursor(ctx: &ReducerContext) -> Cursor {
+    let settings = canvas_settings(ctx);
+    Cursor {
+        identity: ctx.sender,
+        x: 0.0,
+        y: 0.0,
+        // The room's default brush, falling back to a distinct color
+        color: settings
+            .default_brush_color
+            .unwrap_or_else(|| default_color_for(ctx)),
+        size: settings.default_brush_size.min(settings.max_brush_size),
+        last_updated: ctx.timestamp,
+        idle_hidden: false,
+        is_drawing: false,
+    }
+}
+
+// Deletes a chat message together with everything that references it
+fn delete_chat_message_and_refs(ctx: &ReducerContext, message: ChatMessage) {
+    let reactions: Vec<MessageReaction> = ctx
+        .db
+        .message_reaction()
+        .message_id()
+        .filter(message.id)
+        .collect();
+    for reaction in reactions {
+        ctx.db.message_reaction().delete(reaction);
+    }
+    if let Some(pin) = ctx.db.pinned_message().id().find(PINNED_MESSAGE_ID) {
+        if pin.message_id == message.id {
+            ctx.db.pinned_message().delete(pin);
+        }
+    }
+    ctx.db.chat_message().delete(message);
+}
+
+// Finds a user's reaction with a given emoji on a message
+fn find_reaction(
+    ctx: &ReducerContext,
+    message_id: u64,
+    identity: Identity,
+    emoji: &str,
+) -> Option<MessageReaction> {
+    ctx.db
+        .message_reaction()
+        .message_id()
+        .filter(message_id)
+        .find(|r| r.identity == identity && r.emoji == emoji)
+}
+
+// Strips control characters and collapses runs of whitespace into single
+// spaces, then checks the result is 1 to MAX_DISPLAY_NAME_LEN characters
+fn normalize_display_name(name: &str) -> Result<String, String> {
+    let cleaned: String = name.chars().filter(|c| !c.is_control()).collect();
+    let normalized = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+    let len = normalized.chars().count();
+    if len == 0 || len > MAX_DISPLAY_NAME_LEN {
+        return Err(format!(
+            "Display names must be 1 to {} characters",
+            MAX_DISPLAY_NAME_LEN
+        ));
+    }
+    Ok(normalized)
+}
+
+// Returns `name`, or `name (2)`, `name (3)`, ... if it is taken by another
+// user, trimming the base so the suffixed name still fits the length limit
+fn disambiguate_display_name(ctx: &ReducerContext, name: &str) -> String {
+    let is_free = |candidate: &str| {
+        ctx.db
+            .user_profile()
+            .name_key()
+            .find(candidate.to_lowercase())
+            .is_none_or(|profile| profile.identity == ctx.sender)
+    };
+    if is_free(name) {
+        return name.to_string();
+    }
+
+    (2..)
+        .map(|n| {
+            let suffix = format!(" ({})", n);
+            let base: String = name
+                .chars()
+                .take(MAX_DISPLAY_NAME_LEN - suffix.chars().count())
+                .collect();
+            format!("{}{}", base.trim_end(), suffix)
+        })
+        .find(|candidate| is_free(candidate))
+        .expect("an unused suffix always exists")
+}
+
+// Returns true if the caller is a room admin
+fn is_admin(ctx: &ReducerContext) -> bool {
+    ctx.db.room_admin().identity().find(ctx.sender).is_some()
+}
+
+// Rewrites color_histogram with the most used colors on the live canvas
+fn rebuild_color_histogram(ctx: &ReducerContext) {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for point in ctx.db.canvas_point().iter() {
+        *counts.entry(point.color).or_insert(0) += 1;
+    }
+
+    // Most used first, ties broken by color so the ranking is stable
+    let mut ranked: Vec<(String, u64)> = counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    for row in ctx.db.color_histogram().iter() {
+        ctx.db.color_histogram().delete(row);
+    }
+    for (rank, (color, count)) in ranked.into_iter().take(COLOR_HISTOGRAM_SIZE).enumerate() {
+        ctx.db.color_histogram().insert(ColorHistogram {
+            rank: rank as u32,
+            color,
+            count,
+        });
+    }
+}
+
+// Returns the room settings row, creating it with defaults on first use
+fn canvas_settings(ctx: &ReducerContext) -> CanvasSettings {
+    ctx.db
+        .canvas_settings()
+        .id()
+        .find(CANVAS_SETTINGS_ID)
+        .unwrap_or_else(|| {
+            ctx.db.canvas_settings().insert(CanvasSettings {
+                id: CANVAS_SETTINGS_ID,
+                erase_hidden_layers: false,
+                erase_hidden_strokes: false,
+                guest_expiry_seconds: DEFAULT_GUEST_EXPIRY_SECONDS,
+                default_brush_color: None,
+                default_brush_size: DEFAULT_BRUSH_SIZE,
+                erase_mode: ERASE_MODE_PIXEL.to_string(),
+                symmetry_axes: 0,
+                symmetry_center_x: CANVAS_WIDTH as f32 / 2.0,
+                symmetry_center_y: CANVAS_HEIGHT as f32 / 2.0,
+                max_brush_size: MAX_BRUSH_SIZE,
+                point_ttl_seconds: 0,
+                room_topic: String::new(),
+                autosave_name_template: AUTOSAVE_NAME.to_string(),
+                save_quiet_window_ms: 0,
+                canvas_frozen: false,
+                snap_grid_size: DEFAULT_SNAP_GRID_SIZE,
+                snap_shapes: false,
+                snap_freehand: false,
+                max_participants: None,
+                coordinate_scale: 1.0,
+                access_code_required: false,
+                coordinate_precision: None,
+                confirm_stroke_erase: false,
+                room_template_state_id: None,
+                min_point_spacing: 0.0,
+                outlier_threshold: DEFAULT_OUTLIER_THRESHOLD,
+                allowed_tools: TOOLS.iter().map(|tool| tool.to_string()).collect(),
+                archive_after_seconds: 0,
+                archived: false,
+                bound_erasers: false,
+                join_cooldown_seconds: 0,
+            })
+        })
+}
+
+// Whether the eraser must pass over a layer's points: locked layers always,
+// hidden ones unless the room lets hidden content be erased
+fn layer_blocks_erase(layer: &Layer, erase_hidden_layers: bool) -> bool {
+    layer.locked || (layer.hidden && !erase_hidden_layers)
+}
+
+// Decides which points an eraser is allowed to touch
+struct EraseGuard {
+    protected_layers: HashSet<u64>,  // Layers whose points can't be erased
+    protected_strokes: HashSet<u64>, // Hidden strokes the eraser passes over
+    eraser: Identity,                // Who is erasing
+    fresh_after: Timestamp,          // The eraser's own points newer than this are spared
+}
+
+impl EraseGuard {
+    fn new(ctx: &ReducerContext) -> EraseGuard {
+        let settings = canvas_settings(ctx);
+        let protected_layers = ctx
+            .db
+            .layer()
+            .iter()
+            .filter(|layer| layer_blocks_erase(layer, settings.erase_hidden_layers))
+            .map(|layer| layer.id)
+            .collect();
+        let protected_strokes = if settings.erase_hidden_strokes {
+            HashSet::new()
+        } else {
+            ctx.db
+                .stroke_visibility()
+                .iter()
+                .filter(|row| row.hidden)
+                .map(|row| row.stroke_id)
+                .collect()
+        };
+        EraseGuard {
+            protected_layers,
+            protected_strokes,
+            eraser: ctx.sender,
+            fresh_after: ctx.timestamp - SELF_ERASE_PROTECTION,
+        }
+    }
+
+    fn allows(&self, point: &CanvasPoint) -> bool {
+        // Drawing and erasing arrive as separate reducers, so a fast tool
+        // switch could otherwise erase the stroke still being drawn
+        let own_fresh_point = point.identity == self.eraser && point.timestamp > self.fresh_after;
+        !self.protected_layers.contains(&point.layer_id)
+            && !self.protected_strokes.contains(&point.stroke_id)
+            && !own_fresh_point
+    }
+}
+
+// Returns the points an eraser at (x, y) touches directly
+fn points_touching_eraser(
+    ctx: &ReducerContext,
+    x: f32,
+    y: f32,
+    radius: f32,
+    shape: EraserShape,
+) -> Vec<CanvasPoint> {
+    let guard = EraseGuard::new(ctx);
+    // No brush circle is wider than MAX_BRUSH_SIZE, so that bounds the search
+    points_near(ctx, x, y, radius + MAX_BRUSH_SIZE)
+        .into_iter()
+        .filter(|point| shape.overlaps(point, x, y, radius) && guard.allows(point))
+        .collect()
+}
+
+// In stroke erase mode, widens a set of touched points to the whole strokes
+// they belong to. Loose points and pixel mode are left as they are.
+fn widen_to_strokes(ctx: &ReducerContext, hits: Vec<CanvasPoint>) -> Vec<CanvasPoint> {
+    if canvas_settings(ctx).erase_mode != ERASE_MODE_STROKE {
+        return hits;
+    }
+
+    let guard = EraseGuard::new(ctx);
+    let stroke_ids: HashSet<u64> = hits
+        .iter()
+        .map(|point| point.stroke_id)
+        .filter(|&stroke_id| stroke_id != 0)
+        .collect();
+    let mut seen: HashSet<u64> = hits.iter().map(|point| point.id).collect();
+    let mut widened = hits;
+    for stroke_id in stroke_ids {
+        for point in ctx.db.canvas_point().stroke_id().filter(stroke_id) {
+            if guard.allows(&point) && seen.insert(point.id) {
+                widened.push(point);
+            }
+        }
+    }
+    widened
+}
+
+// Returns the points an eraser at (x, y) would remove
+fn points_under_eraser(
+    ctx: &ReducerContext,
+    x: f32,
+    y: f32,
+    radius: f32,
+    shape: EraserShape,
+) -> Vec<CanvasPoint> {
+    widen_to_strokes(ctx, points_touching_eraser(ctx, x, y, radius, shape))
+}
+
+// Returns the points an eraser dragged along a path would remove
+fn points_under_eraser_path(
+    ctx: &ReducerContext,
+    path: &[PathPoint],
+    radius: f32,
+) -> Vec<CanvasPoint> {
+    let guard = EraseGuard::new(ctx);
+    let hits_path = |point: &CanvasPoint| match path {
+        [only] => point_overlaps_circle(point, only.x, only.y, radius),
+        _ => path
+            .windows(2)
+            .any(|segment| point_overlaps_segment(point, &segment[0], &segment[1], radius)),
+    };
+    // Only points near the path's extent can be hit
+    let Some(extent) = Bounds::of_circles(
+        path.iter()
+            .map(|node| (node.x, node.y, radius + MAX_BRUSH_SIZE)),
+    ) else {
+        return Vec::new();
+    };
+    let hits = points_in_box(ctx, extent)
+        .into_iter()
+        .filter(|point| hits_path(point) && guard.allows(point))
+        .collect();
+    widen_to_strokes(ctx, hits)
+}
+
+// Finds a layer the caller may change: its creator or a room admin
+fn owned_layer(ctx: &ReducerContext, layer_id: u64) -> Result<Layer, String> {
+    let layer = ctx
+        .db
+        .layer()
+        .id()
+        .find(layer_id)
+        .ok_or_else(|| format!("Layer {} not found", layer_id))?;
+    if layer.created_by != ctx.sender && !is_admin(ctx) {
+        return Err("Only the layer's creator or an admin can change it".to_string());
+    }
+    Ok(layer)
+}
+
+// Trims a layer name and checks it is 1 to MAX_LAYER_NAME_LEN characters
+fn validate_layer_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_LAYER_NAME_LEN {
+        return Err(format!(
+            "Layer names must be 1 to {} characters",
+            MAX_LAYER_NAME_LEN
+        ));
+    }
+    Ok(name.to_string())
+}
+
+// Returns a layer's z-order, treating the base layer as 0
+fn layer_z_order(ctx: &ReducerContext, layer_id: u64) -> Option<u32> {
+    if layer_id == BASE_LAYER_ID {
+        return Some(0);
+    }
+    ctx.db
+        .layer()
+        .id()
+        .find(layer_id)
+        .map(|layer| layer.z_order)
+}
+
+// Renumbers layer z-orders to 1, 2, 3, ... keeping their stacking
+fn compact_layer_z_orders(ctx: &ReducerContext) {
+    let mut layers: Vec<Layer> = ctx.db.layer().iter().collect();
+    layers.sort_by_key(|layer| layer.z_order);
+    for (index, layer) in layers.into_iter().enumerate() {
+        let z_order = index as u32 + 1;
+        if layer.z_order != z_order {
+            ctx.db.layer().id().update(Layer { z_order, ..layer });
+        }
+    }
+}
+
+// Stores the outcome of a request for the caller, replacing their previous one
+fn write_result(ctx: &ReducerContext, action: &str, success: bool, message: String) {
+    let result = ActionResult {
+        identity: ctx.sender,
+        action: action.to_string(),
+        success,
+        message,
+        created_at: ctx.timestamp,
+    };
+    if ctx.db.action_result().identity().find(ctx.sender).is_some() {
+        ctx.db.action_result().identity().update(result);
+    } else {
+        ctx.db.action_result().insert(result);
+    }
+}
+
+// Refuses a drawing tool the room doesn't allow, writing a "tool not allowed"
+// action_result for `action`. Admins may use every tool.
+fn check_tool_allowed(ctx: &ReducerContext, action: &str, tool: &str) -> Result<(), String> {
+    if is_admin(ctx) || canvas_settings(ctx).allowed_tools.iter().any(|t| t == tool) {
+        return Ok(());
+    }
+    let message = format!(
+        "Tool not allowed: this room doesn't allow the {} tool",
+        tool
+    );
+    write_result(ctx, action, false, message.clone());
+    Err(message)
+}
+
+// How much of a `cooldown` starting at `joined_at` is left at `now`; zero
+// once the full cooldown has passed
+fn cooldown_remaining(joined_at: Timestamp, now: Timestamp, cooldown: Duration) -> Duration {
+    let waited = now.duration_since(joined_at).unwrap_or(Duration::ZERO);
+    cooldown.saturating_sub(waited)
+}
+
+// How long until the caller's join cooldown ends. Admins, the scheduler and
+// members who gave a private room's access code don't wait; a caller with no
+// join record counts as having just joined.
+fn join_cooldown_left(ctx: &ReducerContext) -> Duration {
+    let settings = canvas_settings(ctx);
+    let cooldown = Duration::from_secs(settings.join_cooldown_seconds);
+    // In an open room anyone can make themselves a member, so only a room
+    // with an access code has members worth trusting
+    let known_member =
+        settings.access_code_required && ctx.db.room_member().identity().find(ctx.sender).is_some();
+    if cooldown.is_zero() || ctx.sender == ctx.identity() || is_admin(ctx) || known_member {
+        return Duration::ZERO;
+    }
+    let joined_at = ctx
+        .db
+        .join_record()
+        .identity()
+        .find(ctx.sender)
+        .map_or(ctx.timestamp, |join| join.joined_at);
+    cooldown_remaining(joined_at, ctx.timestamp, cooldown)
+}
+
+// Refuses drawing during the caller's join cooldown, writing a "cooldown
+// active" action_result for `action` that says how long is left.
+// canvas_edit_blocked refuses the same edits without a result; drawing tools
+// call this first so the user learns why their strokes vanish.
+fn check_join_cooldown(ctx: &ReducerContext, action: &str) -> Result<(), String> {
+    let left = join_cooldown_left(ctx);
+    if left.is_zero() {
+        return Ok(());
+    }
+    let message = format!(
+        "Cooldown active: you can draw in {} seconds",
+        left.as_secs_f32().ceil()
+    );
+    write_result(ctx, action, false, message.clone());
+    Err(message)
+}
+
+// Finds a saved state the caller may modify: its creator or a room admin,
+// and only while it is unlocked
+fn editable_state(ctx: &ReducerContext, state_id: u64) -> Result<CanvasState, String> {
+    let state = ctx
+        .db
+        .canvas_state()
+        .id()
+        .find(state_id)
+        .ok_or_else(|| format!("Canvas state {} not found", state_id))?;
+    if state.created_by != ctx.sender && !is_admin(ctx) {
+        return Err("Only the state's creator or an admin can change it".to_string());
+    }
+    if state.is_locked {
+        return Err(format!("Canvas state {} is locked", state_id));
+    }
+    Ok(state)
+}
+
+// Refreshes last_seen on the caller's profile, if they have one
+fn touch_profile(ctx: &ReducerContext) {
+    if let Some(profile) = ctx.db.user_profile().identity().find(ctx.sender) {
+        ctx.db.user_profile().identity().update(UserProfile {
+            last_seen: ctx.timestamp,
+            ..profile
+        });
+    }
+}
+
+// Applies a change to the caller's stats row, creating it on first use
+fn bump_user_stats(ctx: &ReducerContext, change: impl FnOnce(&mut UserStats)) {
+    change_user_stats(ctx, ctx.sender, change);
+}
+
+// Applies a change to any user's stats row, creating it on first use
+fn change_user_stats(
+    ctx: &ReducerContext,
+    identity: Identity,
+    change: impl FnOnce(&mut UserStats),
+) {
+    match ctx.db.user_stats().identity().find(identity) {
+        Some(mut stats) => {
+            change(&mut stats);
+            ctx.db.user_stats().identity().update(stats);
+        }
+        None => {
+            let mut stats = UserStats {
+                identity,
+                points_drawn: 0,
+                strokes: 0,
+                erases: 0,
+                states_saved: 0,
+                ink_used: 0.0,
+            };
+            change(&mut stats);
+            ctx.db.user_stats().insert(stats);
+        }
+    }
+}
+
+// Fetches the room analytics row, creating it on first use
+fn room_analytics(ctx: &ReducerContext) -> RoomAnalytics {
+    ctx.db
+        .room_analytics()
+        .id()
+        .find(ROOM_ANALYTICS_ID)
+        .unwrap_or_else(|| {
+            ctx.db.room_analytics().insert(RoomAnalytics {
+                id: ROOM_ANALYTICS_ID,
+                total_points: 0,
+                total_strokes: 0,
+                active_users: 0,
+                last_activity: ctx.timestamp,
+            })
+        })
+}
+
+// Whether anyone has drawn, erased or moved their cursor within
+// ROOM_IDLE_TIMEOUT. Scheduled upkeep that only matters while people are
+// around skips its work otherwise, and resumes on the first new activity.
+fn is_room_active(ctx: &ReducerContext) -> bool {
+    let cutoff = ctx.timestamp - ROOM_IDLE_TIMEOUT;
+    room_analytics(ctx).last_activity >= cutoff
+        || ctx
+            .db
+            .cursor()
+            .iter()
+            .any(|cursor| cursor.last_updated >= cutoff)
+}
+
+// Adds to the room totals and marks the room as active now
+fn record_room_activity(ctx: &ReducerContext, points: u64, strokes: u64) {
+    let analytics = room_analytics(ctx);
+    ctx.db.room_analytics().id().update(RoomAnalytics {
+        total_points: analytics.total_points + points,
+        total_strokes: analytics.total_strokes + strokes,
+        last_activity: ctx.timestamp,
+        ..analytics
+    });
+}
+
+// Collects the points of one of the caller's strokes
+fn owned_stroke_points(ctx: &ReducerContext, stroke_id: u64) -> Result<Vec<CanvasPoint>, String> {
+    ctx.db
+        .stroke_meta()
+        .stroke_id()
+        .find(stroke_id)
+        .filter(|stroke| stroke.identity == ctx.sender)
+        .ok_or_else(|| format!("Stroke {} is not a stroke of yours", stroke_id))?;
+    Ok(ctx
+        .db
+        .canvas_point()
+        .stroke_id()
+        .filter(stroke_id)
+        .collect())
+}
+
+// Positions of the symmetry copies of (x, y), not including (x, y) itself.
+// `axes` mirror lines through the center, evenly spaced and starting with the
+// vertical one, give 2 * axes images in all.
+fn symmetry_copies(settings: &CanvasSettings, x: f32, y: f32) -> Vec<(f32, f32)> {
+    let axes = settings.symmetry_axes;
+    if axes == 0 {
+        return Vec::new();
+    }
+
+    let (center_x, center_y) = (settings.symmetry_center_x, settings.symmetry_center_y);
+    let (dx, dy) = (x - center_x, y - center_y);
+    let step = std::f32::consts::TAU / axes as f32;
+    let mut copies = Vec::new();
+    for k in 0..axes {
+        let (sin, cos) = (step * k as f32).sin_cos();
+        // The rotated original, then the rotated reflection across the
+        // vertical axis
+        for (px, py, reflected) in [(dx, dy, false), (-dx, dy, true)] {
+            if k == 0 && !reflected {
+                continue;
+            }
+            copies.push((
+                center_x + px * cos - py * sin,
+                center_y + px * sin + py * cos,
+            ));
+        }
+    }
+    copies
+}
+
+// Puts a previously removed point back on the canvas. Its layer and stroke
+// may have gone away in the meantime, leaving it loose on the base layer.
+fn reinsert_point(ctx: &ReducerContext, point: CanvasPoint) {
+    let layer_id = if ctx.db.layer().id().find(point.layer_id).is_some() {
+        point.layer_id
+    } else {
+        BASE_LAYER_ID
+    };
+    let stroke_id = if ctx
+        .db
+        .stroke_meta()
+        .stroke_id()
+        .find(point.stroke_id)
+        .is_some()
+    {
+        point.stroke_id
+    } else {
+        0
+    };
+
+    insert_canvas_point(
+        ctx,
+        CanvasPoint {
+            stroke_id,
+            layer_id,
+            ..point
+        },
+    );
+}
+
+// Deletes points for one of the caller's erases, replacing their erase undo
+// stash with them. An erase that found nothing keeps the previous stash.
+fn erase_canvas_points(ctx: &ReducerContext, points: Vec<CanvasPoint>) {
+    if points.is_empty() {
+        return;
+    }
+
+    let old_stash: Vec<EraseUndo> = ctx.db.erase_undo().erased_by().filter(ctx.sender).collect();
+    for row in old_stash {
+        ctx.db.erase_undo().delete(row);
+    }
+
+    let erased = points.len() as u64;
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        ctx.db.erase_undo().insert(EraseUndo {
+            id: 0, // Will be auto-incremented
+            erased_by: ctx.sender,
+            identity: point.identity,
+            x: point.x,
+            y: point.y,
+            color: point.color.clone(),
+            size: point.size,
+            opacity: point.opacity,
+            draw_order: point.draw_order,
+            stroke_id: point.stroke_id,
+            layer_id: point.layer_id,
+            origin: point.origin.clone(),
+            blend_mode: point.blend_mode.clone(),
+        });
+        batch.delete(point);
+    }
+    batch.finish();
+    refresh_canvas_bounds(ctx);
+    bump_user_stats(ctx, |stats| stats.erases += erased);
+    record_room_activity(ctx, 0, 0);
+}
+
+// Parses a "#rrggbb" color into its channels
+fn parse_hex_color(color: &str) -> Result<(u8, u8, u8), String> {
+    validate_color(color)?;
+    let channel = |i: usize| u8::from_str_radix(&color[i..i + 2], 16).map_err(|e| e.to_string());
+    Ok((channel(1)?, channel(3)?, channel(5)?))
+}
+
+// Converts RGB channels to (hue in degrees, saturation, lightness)
+fn rgb_to_hsl((r, g, b): (u8, u8, u8)) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return (0.0, 0.0, lightness);
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    (hue, saturation, lightness)
+}
+
+// Converts (hue in degrees, saturation, lightness) back to a "#rrggbb" color
+fn hsl_to_hex((hue, saturation, lightness): (f32, f32, f32)) -> String {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue.rem_euclid(360.0) / 60.0;
+    let second = chroma * (1.0 - (sector.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, second, 0.0),
+        1 => (second, chroma, 0.0),
+        2 => (0.0, chroma, second),
+        3 => (0.0, second, chroma),
+        4 => (second, 0.0, chroma),
+        _ => (chroma, 0.0, second),
+    };
+    let offset = lightness - chroma / 2.0;
+    let channel = |c: f32| ((c + offset) * 255.0).round().clamp(0.0, 255.0) as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
+
+// Hashes saved points in order, from their position, size, color, opacity
+// and blend mode. Who drew them and how they got there don't count.
+fn content_hash(points: &[SavedCanvasPoint]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for point in points {
+        hasher.update(&point.x.to_le_bytes());
+        hasher.update(&point.y.to_le_bytes());
+        hasher.update(&point.size.to_le_bytes());
+        hasher.update(&point.opacity.to_le_bytes());
+        // Length prefixes keep adjacent strings from running together
+        for text in [&point.color, &point.blend_mode] {
+            hasher.update(&(text.len() as u32).to_le_bytes());
+            hasher.update(text.as_bytes());
+        }
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+// Stores a state's content hash after its points were written, returning the
+// updated row
+fn write_state_content_hash(ctx: &ReducerContext, state: CanvasState) -> CanvasState {
+    let content_hash = content_hash(&saved_points_in_order(ctx, state.id));
+    ctx.db.canvas_state().id().update(CanvasState {
+        content_hash,
+        ..state
+    })
+}
+
+// Buckets a saved state's points into a THUMBNAIL_GRID_SIZE grid and stores
+// the most common color per cell as the state's thumbnail
+fn write_state_thumbnail(ctx: &ReducerContext, state_id: u64) {
+    let cell_width = CANVAS_WIDTH as f32 / THUMBNAIL_GRID_SIZE as f32;
+    let cell_height = CANVAS_HEIGHT as f32 / THUMBNAIL_GRID_SIZE as f32;
+    let mut cell_colors: Vec<HashMap<String, u32>> =
+        vec![HashMap::new(); THUMBNAIL_GRID_SIZE * THUMBNAIL_GRID_SIZE];
+    for point in saved_points_in_order(ctx, state_id) {
+        // Points past the edges count toward the nearest edge cell
+        let col = ((point.x / cell_width).max(0.0) as usize).min(THUMBNAIL_GRID_SIZE - 1);
+        let row = ((point.y / cell_height).max(0.0) as usize).min(THUMBNAIL_GRID_SIZE - 1);
+        *cell_colors[row * THUMBNAIL_GRID_SIZE + col]
+            .entry(point.color)
+            .or_insert(0) += 1;
+    }
+
+    // Ties go to the smaller color so the thumbnail is deterministic
+    let cells: Vec<Option<String>> = cell_colors
+        .into_iter()
+        .map(|counts| {
+            counts
+                .into_iter()
+                .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))
+                .map(|(color, _)| color)
+        })
+        .collect();
+    let grid = json!({
+        "cols": THUMBNAIL_GRID_SIZE,
+        "rows": THUMBNAIL_GRID_SIZE,
+        "cells": cells,
+    });
+
+    ctx.db.state_thumbnail().state_id().delete(state_id);
+    ctx.db.state_thumbnail().insert(StateThumbnail {
+        state_id,
+        grid: grid.to_string(),
+    });
+}
+
+// Clamps a brush radius into MIN_BRUSH_SIZE up to the room's maximum brush size
+fn clamp_brush_size(ctx: &ReducerContext, size: f32) -> f32 {
+    brush_size_within(size, canvas_settings(ctx).max_brush_size)
+}
+
+// Clamps a brush radius into MIN_BRUSH_SIZE..=max_size; NaN, which clamps to
+// nothing, becomes DEFAULT_BRUSH_SIZE
+fn brush_size_within(size: f32, max_size: f32) -> f32 {
+    let size = if size.is_nan() {
+        DEFAULT_BRUSH_SIZE
+    } else {
+        size
+    };
+    size.max(MIN_BRUSH_SIZE).min(max_size)
+}
+
+// The color the caller draws with: their assigned color if an admin locked
+// them to one, otherwise the color they asked for
+fn drawing_color_for(ctx: &ReducerContext, requested: String) -> String {
+    ctx.db
+        .color_assignment()
+        .identity()
+        .find(ctx.sender)
+        .map(|assignment| assignment.color)
+        .unwrap_or(requested)
+}
+
+// Records that the live canvas as it is now matches a saved state
+fn mark_canvas_saved(ctx: &ReducerContext) {
+    let meta = canvas_meta(ctx);
+    ctx.db.canvas_meta().id().update(CanvasMeta {
+        saved_revision: meta.revision,
+        ..meta
+    });
+}
+
+// Draws one point for the caller the way add_drawing_point does: applying
+// the room's brush limits, color assignment and symmetry, placing it on the
+// caller's active layer and grouping it under their open stroke
+fn draw_point(ctx: &ReducerContext, x: f32, y: f32, color: String, size: f32, blend_mode: &str) {
+    let size = clamp_brush_size(ctx, size);
+    let color = drawing_color_for(ctx, color);
+    let layer_id = active_layer(ctx);
+
+    // With symmetry on, every point also lands at its mirrored positions
+    let copies = symmetry_copies(&canvas_settings(ctx), x, y);
+
+    // Group the point under the caller's open stroke, if they have one
+    let stroke_id = match open_stroke(ctx, ctx.sender) {
+        Some(stroke) => {
+            let stroke_id = stroke.stroke_id;
+            ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+                point_count: stroke.point_count + 1 + copies.len() as u64,
+                ..stroke
+            });
+            stroke_id
+        }
+        None => 0,
+    };
+
+    let original = insert_canvas_point(
+        ctx,
+        CanvasPoint {
+            stroke_id,
+            layer_id,
+            blend_mode: blend_mode.to_string(),
+            ..new_canvas_point(ctx, x, y, color.clone(), size)
+        },
+    );
+    for (copy_x, copy_y) in copies {
+        insert_canvas_point(
+            ctx,
+            CanvasPoint {
+                stroke_id,
+                layer_id,
+                mirror_of: original.id,
+                blend_mode: blend_mode.to_string(),
+                ..new_canvas_point(ctx, copy_x, copy_y, color.clone(), size)
+            },
+        );
+    }
+    if let Some(active) = ctx.db.active_stroke().identity().find(ctx.sender) {
+        if active.stroke_id == stroke_id {
+            ctx.db.active_stroke().identity().update(ActiveStroke {
+                last_point_id: original.id,
+                ..active
+            });
+        }
+    }
+    bump_user_stats(ctx, |stats| stats.points_drawn += 1);
+    record_room_activity(ctx, 1, 0);
+}
+
+// Whether a freehand point at (x, y) lands closer than the room's
+// min_point_spacing to the last point of the caller's open stroke
+fn too_close_to_last_point(ctx: &ReducerContext, x: f32, y: f32) -> bool {
+    let spacing = canvas_settings(ctx).min_point_spacing;
+    if spacing <= 0.0 || open_stroke(ctx, ctx.sender).is_none() {
+        return false;
+    }
+    ctx.db
+        .active_stroke()
+        .identity()
+        .find(ctx.sender)
+        .and_then(|active| ctx.db.canvas_point().id().find(active.last_point_id))
+        .is_some_and(|last| within_spacing(&last, x, y, spacing))
+}
+
+// Whether (x, y) is strictly closer than `spacing` to a point's center. A
+// spacing of 0 or less never thins anything.
+fn within_spacing(last: &CanvasPoint, x: f32, y: f32, spacing: f32) -> bool {
+    spacing > 0.0 && (last.x - x).powi(2) + (last.y - y).powi(2) < spacing * spacing
+}
+
+// Positions along the segment from (x0, y0) to (x1, y1), spaced half a brush
+// radius apart so the circles join into a solid line
+fn segment_positions(x0: f32, y0: f32, x1: f32, y1: f32, size: f32) -> Vec<(f32, f32)> {
+    let length = ((x1 - x0).powi(2) + (y1 - y0).powi(2)).sqrt();
+    let steps = ((length / (size / 2.0)).ceil() as usize).clamp(1, MAX_SEGMENT_POINTS - 1);
+    (0..=steps)
+        .map(|i| {
+            let t = i as f32 / steps as f32;
+            (x0 + (x1 - x0) * t, y0 + (y1 - y0) * t)
+        })
+        .collect()
+}
+
+// Rounds a position to the nearest grid intersection when the room snaps
+// this kind of input (`shape` for segment endpoints, otherwise freehand)
+fn snap_to_grid(ctx: &ReducerContext, x: f32, y: f32, shape: bool) -> (f32, f32) {
+    let settings = canvas_settings(ctx);
+    let snaps = if shape {
+        settings.snap_shapes
+    } else {
+        settings.snap_freehand
+    };
+    if !snaps {
+        return (x, y);
+    }
+
+    let grid = settings.snap_grid_size;
+    (
+        ((x / grid).round() * grid).clamp(0.0, CANVAS_WIDTH as f32),
+        ((y / grid).round() * grid).clamp(0.0, CANVAS_HEIGHT as f32),
+    )
+}
+
+// Rounds a drawn position to the room's coordinate precision, if it has one
+fn round_to_precision(ctx: &ReducerContext, x: f32, y: f32) -> (f32, f32) {
+    match canvas_settings(ctx).coordinate_precision {
+        Some(places) => {
+            let scale = 10f32.powi(places as i32);
+            ((x * scale).round() / scale, (y * scale).round() / scale)
+        }
+        None => (x, y),
+    }
+}
+
+// Checks a blend mode against BLEND_MODES, treating an empty one as normal
+fn validate_blend_mode(blend_mode: &str) -> Result<String, String> {
+    if blend_mode.is_empty() {
+        return Ok(BLEND_MODE_NORMAL.to_string());
+    }
+    if !BLEND_MODES.contains(&blend_mode) {
+        return Err(format!(
+            "Unknown blend mode {:?}, expected one of {}",
+            blend_mode,
+            BLEND_MODES.join(", ")
+        ));
+    }
+    Ok(blend_mode.to_string())
+}
+
+// Checks that a position is finite and on the canvas
+fn validate_position(x: f32, y: f32) -> Result<(), String> {
+    let on_canvas =
+        (0.0..=CANVAS_WIDTH as f32).contains(&x) && (0.0..=CANVAS_HEIGHT as f32).contains(&y);
+    if on_canvas {
+        Ok(())
+    } else {
+        Err(format!("Position ({}, {}) is outside the canvas", x, y))
+    }
+}
+
+// Mixes two "#rrggbb" colors in RGB, `t` of the way from `from` to `to`
+fn blend_hex_colors(from: (u8, u8, u8), to: (u8, u8, u8), t: f32) -> String {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        mix(from.0, to.0),
+        mix(from.1, to.1),
+        mix(from.2, to.2)
+    )
+}
+
+// How far along a path each of its points is, from 0.0 at the first to 1.0
+// at the last, measured by distance so uneven sampling doesn't skew it
+fn path_progress(points: &[PathPoint]) -> Vec<f32> {
+    let mut travelled = vec![0.0; points.len()];
+    for i in 1..points.len() {
+        let (dx, dy) = (points[i].x - points[i - 1].x, points[i].y - points[i - 1].y);
+        travelled[i] = travelled[i - 1] + (dx * dx + dy * dy).sqrt();
+    }
+    let total = travelled.last().copied().unwrap_or(0.0);
+    travelled
+        .into_iter()
+        .map(|distance| if total > 0.0 { distance / total } else { 0.0 })
+        .collect()
+}
+
+// Appends an audit_log entry for a change made by the caller
+fn audit(ctx: &ReducerContext, action: &str, detail: String) {
+    ctx.db.audit_log().insert(AuditLog {
+        id: 0, // Will be auto-incremented
+        actor: ctx.sender,
+        action: action.to_string(),
+        detail,
+        recorded_at: ctx.timestamp,
+    });
+}
+
+// A saved state's points as JSON objects, in paint order
+fn saved_points_json(ctx: &ReducerContext, state_id: u64) -> Vec<serde_json::Value> {
+    saved_points_in_order(ctx, state_id)
+        .iter()
+        .map(saved_point_json)
+        .collect()
+}
+
+// One saved point as a JSON object
+fn saved_point_json(p: &SavedCanvasPoint) -> serde_json::Value {
+    json!({
+        "x": p.x,
+        "y": p.y,
+        "size": p.size,
+        "color": p.color,
+        "opacity": p.opacity,
+        "origin": p.origin,
+        "blend_mode": p.blend_mode,
+        "drawn_at_micros": p.drawn_at.to_micros_since_unix_epoch(),
+    })
+}
+
+// Drops stray points from an export: those whose distance from the centroid
+// is more than `threshold` standard deviations, taking the deviation as the
+// root mean square distance. Order is kept.
+fn without_outliers<T>(
+    items: Vec<T>,
+    position: impl Fn(&T) -> (f32, f32),
+    threshold: f32,
+) -> Vec<T> {
+    if items.len() < 2 {
+        return items;
+    }
+    let count = items.len() as f32;
+    let (sum_x, sum_y) = items
+        .iter()
+        .map(&position)
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (cx, cy) = (sum_x / count, sum_y / count);
+    let squared = |item: &T| {
+        let (x, y) = position(item);
+        (x - cx).powi(2) + (y - cy).powi(2)
+    };
+    let deviation = (items.iter().map(squared).sum::<f32>() / count).sqrt();
+    let limit = threshold * deviation;
+    items
+        .into_iter()
+        .filter(|item| squared(item).sqrt() <= limit)
+        .collect()
+}
+
+// Checks and trims a saved state name
+fn validate_state_name(name: &str) -> Result<String, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_STATE_NAME_LEN {
+        return Err(format!(
+            "State names must be 1 to {} characters",
+            MAX_STATE_NAME_LEN
+        ));
+    }
+    Ok(name)
+}
+
+// Trims a state description and checks it is at most MAX_STATE_DESCRIPTION_LEN
+// characters; an empty description is allowed
+fn validate_state_description(description: &str) -> Result<String, String> {
+    let description = description.trim().to_string();
+    if description.chars().count() > MAX_STATE_DESCRIPTION_LEN {
+        return Err(format!(
+            "State descriptions are limited to {} characters",
+            MAX_STATE_DESCRIPTION_LEN
+        ));
+    }
+    Ok(description)
+}
+
+// Reads one bundled point, as written by saved_points_json, into a saved
+// point that still needs its state_id
+fn bundled_point(value: &serde_json::Value, now: Timestamp) -> Result<SavedCanvasPoint, String> {
+    let number = |key: &str| {
+        value[key]
+            .as_f64()
+            .map(|n| n as f32)
+            .filter(|n| n.is_finite())
+            .ok_or_else(|| format!("Point is missing a numeric {:?}", key))
+    };
+    let color = value["color"]
+        .as_str()
+        .ok_or("Point is missing a color")?
+        .to_string();
+    let (x, y, size, opacity) = (
+        number("x")?,
+        number("y")?,
+        number("size")?,
+        number("opacity")?,
+    );
+    validate_color(&color)?;
+    validate_size(size)?;
+    validate_opacity(opacity)?;
+    // Bundles from before point origins were tracked hold only drawn points
+    let origin = match value["origin"].as_str() {
+        Some(ORIGIN_LOADED) => ORIGIN_LOADED,
+        _ => ORIGIN_DRAWN,
+    };
+    // Older bundles have no blend modes, so their points are normal
+    let blend_mode = validate_blend_mode(value["blend_mode"].as_str().unwrap_or(""))?;
+    // Nor do they have draw times; their points count as drawn on import
+    let drawn_at = value["drawn_at_micros"]
+        .as_i64()
+        .map(Timestamp::from_micros_since_unix_epoch)
+        .unwrap_or(now);
+    Ok(SavedCanvasPoint {
+        id: 0,       // Will be auto-incremented
+        state_id: 0, // Set by the caller
+        x,
+        y,
+        color,
+        size,
+        opacity,
+        origin: origin.to_string(),
+        blend_mode,
+        drawn_at,
+    })
+}
+
+// Recreates one bundled state, owned by the caller with fresh ids. Nothing is
+// written unless the whole state is valid.
+fn import_bundled_state(ctx: &ReducerContext, value: &serde_json::Value) -> Result<(), String> {
+    let name = validate_state_name(value["name"].as_str().ok_or("State is missing a name")?)?;
+    let description = validate_state_description(value["description"].as_str().unwrap_or(""))?;
+    let points = value["points"]
+        .as_array()
+        .ok_or("State is missing its points")?;
+    // The same cap a save of the live canvas is held to
+    if points.len() as u64 > MAX_POINTS_PER_STATE {
+        return Err(format!(
+            "State has {} points, over the {} point cap",
+            points.len(),
+            MAX_POINTS_PER_STATE
+        ));
+    }
+    let points = points
+        .iter()
+        .map(|point| bundled_point(point, ctx.timestamp))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let state = ctx.db.canvas_state().insert(CanvasState {
+        id: 0, // Will be auto-incremented
+        name,
+        created_by: ctx.sender,
+        created_at: ctx.timestamp,
+        is_autosave: false,
+        is_locked: value["is_locked"].as_bool().unwrap_or(false),
+        point_count: points.len() as u64,
+        description,
+        content_hash: String::new(), // Written once the points are saved
+        history_seq: 0,
+    });
+    for point in points {
+        ctx.db.saved_canvas_point().insert(SavedCanvasPoint {
+            state_id: state.id,
+            ..point
+        });
+    }
+    write_state_thumbnail(ctx, state.id);
+    write_state_content_hash(ctx, state);
+    Ok(())
+}
+
+// Finds a point the caller may edit: their own, or any point for an admin
+fn editable_point(ctx: &ReducerContext, point_id: u64) -> Result<CanvasPoint, String> {
+    let point = ctx
+        .db
+        .canvas_point()
+        .id()
+        .find(point_id)
+        .ok_or_else(|| format!("Point {} not found", point_id))?;
+    if point.identity != ctx.sender && !is_admin(ctx) {
+        return Err(format!("Point {} is not yours", point_id));
+    }
+    Ok(point)
+}
+
+// Fills in the room's autosave name template for the next autosave
+fn autosave_name(ctx: &ReducerContext, index: u64) -> String {
+    let template = canvas_settings(ctx).autosave_name_template;
+    let template = if AUTOSAVE_PLACEHOLDERS.iter().any(|p| template.contains(p)) {
+        template
+    } else {
+        format!("{}-{{time}}", template)
+    };
+    template
+        .replace("{index}", &index.to_string())
+        .replace("{time}", &ctx.timestamp.to_string())
+}
+
+// Why the caller can't change the canvas right now, if they can't: an admin
+// froze it, the caller joined a full room as a spectator, the room has an
+// access code the caller hasn't given, or the caller is still inside the
+// room's join cooldown. Every reducer
+// that changes canvas points checks this first and does nothing when blocked.
+fn canvas_edit_blocked(ctx: &ReducerContext) -> Option<&'static str> {
+    if canvas_settings(ctx).canvas_frozen {
+        Some(CANVAS_FROZEN)
+    } else if ctx.db.spectator().identity().find(ctx.sender).is_some() {
+        Some(SPECTATORS_CANT_DRAW)
+    } else if !is_room_member(ctx) {
+        Some(MEMBERS_ONLY)
+    } else if !join_cooldown_left(ctx).is_zero() {
+        Some(COOLDOWN_ACTIVE)
+    } else {
+        None
+    }
+}
+
+// Whether the caller may draw in a room with an access code: always true for
+// open rooms and admins
+fn is_room_member(ctx: &ReducerContext) -> bool {
+    !canvas_settings(ctx).access_code_required
+        || is_admin(ctx)
+        || ctx.db.room_member().identity().find(ctx.sender).is_some()
+}
+
+// Hashes an access code with its salt over ACCESS_CODE_HASH_ROUNDS rounds,
+// so each guess costs real work even if the hash ever leaked
+fn hash_access_code(salt: &str, code: &str) -> String {
+    let mut digest = *blake3::hash(code.as_bytes()).as_bytes();
+    for _ in 0..ACCESS_CODE_HASH_ROUNDS {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(salt.as_bytes());
+        hasher.update(&digest);
+        hasher.update(code.as_bytes());
+        digest = *hasher.finalize().as_bytes();
+    }
+    blake3::Hash::from(digest).to_hex().to_string()
+}
+
+// Connected users who can draw, i.e. have a cursor and aren't spectators
+fn participant_count(ctx: &ReducerContext) -> usize {
+    ctx.db
+        .cursor()
+        .iter()
+        .filter(|cursor| {
+            ctx.db
+                .spectator()
+                .identity()
+                .find(cursor.identity)
+                .is_none()
+        })
+        .count()
+}
+
+// Admits the caller as they get a cursor: past the participant limit,
+// newcomers other than admins only watch
+fn admit_participant(ctx: &ReducerContext) {
+    if ctx.db.spectator().identity().find(ctx.sender).is_some() {
+        return;
+    }
+    if let Some(limit) = canvas_settings(ctx).max_participants {
+        if participant_count(ctx) >= limit as usize && !is_admin(ctx) {
+            ctx.db.spectator().insert(Spectator {
+                identity: ctx.sender,
+                joined_at: ctx.timestamp,
+            });
+            write_result(
+                ctx,
+                "join",
+                false,
+                "The room is full; you joined as a spectator".to_string(),
+            );
+        }
+    }
+}
+
+// Lets the longest-waiting spectator draw once the room has a free place
+fn promote_spectator(ctx: &ReducerContext) {
+    if let Some(limit) = canvas_settings(ctx).max_participants {
+        if participant_count(ctx) >= limit as usize {
+            return;
+        }
+    }
+    if let Some(next) = ctx.db.spectator().iter().min_by_key(|s| s.joined_at) {
+        ctx.db.spectator().delete(next);
+    }
+}
+
+// Shared body of freeze_canvas and unfreeze_canvas
+fn set_canvas_frozen(ctx: &ReducerContext, frozen: bool) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can freeze the canvas".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    if settings.canvas_frozen != frozen {
+        let action = if frozen {
+            "freeze_canvas"
+        } else {
+            "unfreeze_canvas"
+        };
+        let detail = if frozen {
+            "Froze the canvas"
+        } else {
+            "Unfroze the canvas"
+        };
+        audit(ctx, action, detail.to_string());
+        ctx.db.canvas_settings().id().update(CanvasSettings {
+            canvas_frozen: frozen,
+            ..settings
+        });
+    }
+    Ok(())
+}
+
+// Whether two stroke index rows have the same color and bounding boxes that
+// agree to within DEDUP_TOLERANCE on every edge
+fn strokes_roughly_coincide(a: &StrokeIndex, b: &StrokeIndex) -> bool {
+    a.color == b.color
+        && [
+            (a.min_x, b.min_x),
+            (a.min_y, b.min_y),
+            (a.max_x, b.max_x),
+            (a.max_y, b.max_y),
+        ]
+        .iter()
+        .all(|(p, q)| (p - q).abs() <= DEDUP_TOLERANCE)
+}
+
+// Whether every point of each set has a point of the other set within
+// DEDUP_TOLERANCE
+fn point_sets_match(a: &[CanvasPoint], b: &[CanvasPoint]) -> bool {
+    let covered = |from: &[CanvasPoint], to: &[CanvasPoint]| {
+        from.iter().all(|p| {
+            to.iter().any(|q| {
+                (p.x - q.x).powi(2) + (p.y - q.y).powi(2) <= DEDUP_TOLERANCE * DEDUP_TOLERANCE
+            })
+        })
+    };
+    covered(a, b) && covered(b, a)
+}
+
+// The layer the caller's new drawing goes on. A chosen layer that has since
+// been deleted falls back to the base layer.
+fn active_layer(ctx: &ReducerContext) -> u64 {
+    ctx.db
+        .user_active_layer()
+        .identity()
+        .find(ctx.sender)
+        .map(|row| row.layer_id)
+        .filter(|&layer_id| ctx.db.layer().id().find(layer_id).is_some())
+        .unwrap_or(BASE_LAYER_ID)
+}
+
+// The palette entry closest to `rgb` by squared RGB distance; earlier
+// entries win ties. The palette must not be empty.
+fn nearest_palette_color(rgb: (u8, u8, u8), palette: &[(String, (u8, u8, u8))]) -> &str {
+    let distance = |(r, g, b): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, rgb.0) + d(g, rgb.1) + d(b, rgb.2)
+    };
+    palette
+        .iter()
+        .min_by_key(|(_, entry)| distance(*entry))
+        .map(|(color, _)| color.as_str())
+        .expect("palette is not empty")
+}
+
+// Removes every selection row belonging to an identity
+fn clear_selection_for(ctx: &ReducerContext, identity: Identity) {
+    let rows: Vec<SelectedPoint> = ctx
+        .db
+        .selected_point()
+        .identity()
+        .filter(identity)
+        .collect();
+    for row in rows {
+        ctx.db.selected_point().delete(row);
+    }
+}
+
+// Removes every stroke erase candidate belonging to an identity
+fn clear_stroke_erase_candidates(ctx: &ReducerContext, identity: Identity) {
+    let rows: Vec<StrokeEraseCandidate> = ctx
+        .db
+        .stroke_erase_candidate()
+        .identity()
+        .filter(identity)
+        .collect();
+    for row in rows {
+        ctx.db.stroke_erase_candidate().delete(row);
+    }
+}
+
+// The caller's selected points that are still on the canvas
+fn selected_points(ctx: &ReducerContext) -> Vec<CanvasPoint> {
+    ctx.db
+        .selected_point()
+        .identity()
+        .filter(ctx.sender)
+        .filter_map(|row| ctx.db.canvas_point().id().find(row.point_id))
+        .collect()
+}
+
+// Fetches the caller's session row, or a fresh one at the default view
+fn session_state(ctx: &ReducerContext) -> SessionState {
+    ctx.db
+        .session_state()
+        .identity()
+        .find(ctx.sender)
+        .unwrap_or(SessionState {
+            identity: ctx.sender,
+            viewport_x: 0.0,
+            viewport_y: 0.0,
+            viewport_zoom: 1.0,
+            brush_color: None,
+            brush_size: None,
+            updated_at: ctx.timestamp,
+        })
+}
+
+// Inserts or replaces the caller's session row
+fn store_session_state(ctx: &ReducerContext, session: SessionState) {
+    if ctx.db.session_state().identity().find(ctx.sender).is_some() {
+        ctx.db.session_state().identity().update(session);
+    } else {
+        ctx.db.session_state().insert(session);
+    }
+}
+
+// Appends a usage_report row with the current table sizes, dropping the
+// oldest reports past MAX_USAGE_REPORTS
+fn write_usage_report(ctx: &ReducerContext) {
+    ctx.db.usage_report().insert(UsageReport {
+        id: 0, // Will be auto-incremented
+        recorded_at: ctx.timestamp,
+        canvas_points: ctx.db.canvas_point().count(),
+        canvas_states: ctx.db.canvas_state().count(),
+        saved_points: ctx.db.saved_canvas_point().count(),
+        cursors: ctx.db.cursor().count(),
+    });
+
+    let mut reports: Vec<UsageReport> = ctx.db.usage_report().iter().collect();
+    if reports.len() > MAX_USAGE_REPORTS {
+        reports.sort_by_key(|report| report.id);
+        let excess = reports.len() - MAX_USAGE_REPORTS;
+        for report in reports.into_iter().take(excess) {
+            ctx.db.usage_report().delete(report);
+        }
+    }
+}
+
+#[reducer(init)]
+// Runs once when the module is first published
+pub fn init(ctx: &ReducerContext) {
+    // The publisher becomes the first room admin
+    ctx.db.room_admin().insert(RoomAdmin {
+        identity: ctx.sender,
+        granted_at: ctx.timestamp,
+    });
+
+    ctx.db
+        .history_prune_schedule()
+        .insert(HistoryPruneSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: HISTORY_PRUNE_INTERVAL.into(),
+        });
+    ctx.db
+        .cursor_reconcile_schedule()
+        .insert(CursorReconcileSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: CURSOR_RECONCILE_INTERVAL.into(),
+        });
+    ctx.db
+        .drawing_sweep_schedule()
+        .insert(DrawingSweepSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: DRAWING_SWEEP_INTERVAL.into(),
+        });
+    ctx.db
+        .typing_cleanup_schedule()
+        .insert(TypingCleanupSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: TYPING_CLEANUP_INTERVAL.into(),
+        });
+    ctx.db.autosave_schedule().insert(AutosaveSchedule {
+        scheduled_id: 0, // Will be auto-incremented
+        scheduled_at: AUTOSAVE_INTERVAL.into(),
+    });
+
+    // Bring back the last autosave if the canvas starts out empty
+    restore_latest_autosave(ctx);
+    ctx.db
+        .guest_cleanup_schedule()
+        .insert(GuestCleanupSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: GUEST_CLEANUP_INTERVAL.into(),
+        });
+    ctx.db.analytics_schedule().insert(AnalyticsSchedule {
+        scheduled_id: 0, // Will be auto-incremented
+        scheduled_at: ANALYTICS_INTERVAL.into(),
+    });
+    ctx.db.point_expiry_schedule().insert(PointExpirySchedule {
+        scheduled_id: 0, // Will be auto-incremented
+        scheduled_at: POINT_EXPIRY_INTERVAL.into(),
+    });
+    ctx.db.usage_report_schedule().insert(UsageReportSchedule {
+        scheduled_id: 0, // Will be auto-incremented
+        scheduled_at: USAGE_REPORT_INTERVAL.into(),
+    });
+    ctx.db
+        .highlight_cleanup_schedule()
+        .insert(HighlightCleanupSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: HIGHLIGHT_CLEANUP_INTERVAL.into(),
+        });
+    ctx.db
+        .archive_check_schedule()
+        .insert(ArchiveCheckSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: ARCHIVE_CHECK_INTERVAL.into(),
+        });
+    ctx.db
+        .state_link_prune_schedule()
+        .insert(StateLinkPruneSchedule {
+            scheduled_id: 0, // Will be auto-incremented
+            scheduled_at: STATE_LINK_PRUNE_INTERVAL.into(),
+        });
+}
+
+#[reducer(client_connected)]
+// Handles a new client connection
+pub fn identity_connected(ctx: &ReducerContext) {
+    touch_profile(ctx);
+    // Another connection (a second tab) keeps the original join time
+    match ctx.db.join_record().identity().find(ctx.sender) {
+        Some(join) => {
+            ctx.db.join_record().identity().update(JoinRecord {
+                connections: join.connections + 1,
+                ..join
+            });
+        }
+        None => {
+            ctx.db.join_record().insert(JoinRecord {
+                identity: ctx.sender,
+                joined_at: ctx.timestamp,
+                connections: 1,
+            });
+        }
+    }
+
+    // A repeated connect or a missed disconnect can leave a cursor behind,
+    // so refresh it rather than inserting a duplicate
+    if let Some(cursor) = ctx.db.cursor().identity().find(ctx.sender) {
+        ctx.db.cursor().identity().update(Cursor {
+            last_updated: ctx.timestamp,
+            idle_hidden: false,
+            ..cursor
+        });
+        return;
+    }
+
+    // The first user into a fresh room starts it from the template
+    apply_room_template(ctx);
+
+    admit_participant(ctx);
+
+    // Returning users pick up the brush they left with, still subject to the
+    // room's current size limit and color assignments
+    let session = session_state(ctx);
+    let default = default_cursor(ctx);
+    ctx.db.cursor().insert(Cursor {
+        color: match session.brush_color {
+            Some(color) => drawing_color_for(ctx, color),
+            None => default.color.clone(),
+        },
+        size: match session.brush_size {
+            Some(size) => clamp_brush_size(ctx, size),
+            None => default.size,
+        },
+        ..default
+    });
+}
+
+#[reducer(client_disconnected)]
+// Handles a client disconnection
+pub fn identity_disconnected(ctx: &ReducerContext) {
+    // Remove the cursor when a user disconnects, remembering their brush
+    if let Some(cursor) = ctx.db.cursor().identity().find(ctx.sender) {
+        store_session_state(
+            ctx,
+            SessionState {
+                brush_color: Some(cursor.color.clone()),
+                brush_size: Some(cursor.size),
+                updated_at: ctx.timestamp,
+                ..session_state(ctx)
+            },
+        );
+        ctx.db.cursor().delete(cursor);
+    }
+
+    // Drop the per-connection state the user left behind: erase preview,
+    // selection, unconfirmed stroke erase and view range
+    clear_erase_preview_for(ctx, ctx.sender);
+    clear_selection_for(ctx, ctx.sender);
+    clear_stroke_erase_candidates(ctx, ctx.sender);
+    ctx.db.view_buckets().identity().delete(ctx.sender);
+
+    // A disconnected user is no longer typing
+    ctx.db.typing_indicator().identity().delete(ctx.sender);
+
+    // Once their last connection closes, rejoining starts a new cooldown
+    if let Some(join) = ctx.db.join_record().identity().find(ctx.sender) {
+        if join.connections <= 1 {
+            ctx.db.join_record().delete(join);
+        } else {
+            ctx.db.join_record().identity().update(JoinRecord {
+                connections: join.connections - 1,
+                ..join
+            });
+        }
+    }
+
+    // Spectating ends with the connection, and any place freed goes to the
+    // next spectator in line
+    ctx.db.spectator().identity().delete(ctx.sender);
+    promote_spectator(ctx);
+
+    touch_profile(ctx);
+}
+
+#[reducer]
+// Updates a user's cursor position and brush settings
+pub fn update_cursor(ctx: &ReducerContext, x: f32, y: f32, color: String, size: f32) {
+    if !x.is_finite() || !y.is_finite() {
+        log::warn!(
+            "Dropped a cursor update from {}: invalid position ({}, {})",
+            ctx.sender,
+            x,
+            y
+        );
+        return;
+    }
+    if let Err(message) = validate_color(&color) {
+        log::warn!("Dropped a cursor update from {}: {}", ctx.sender, message);
+        return;
+    }
+    let cursor = Cursor {
+        identity: ctx.sender,
+        x,
+        y,
+        color: drawing_color_for(ctx, color),
+        size: clamp_brush_size(ctx, size),
+        last_updated: ctx.timestamp,
+        idle_hidden: false,
+        // Moving keeps an open stroke showing as drawing
+        is_drawing: ctx.db.active_stroke().identity().find(ctx.sender).is_some(),
+    };
+    if ctx.db.cursor().identity().find(ctx.sender).is_some() {
+        ctx.db.cursor().identity().update(cursor);
+    } else if ctx.db.join_record().identity().find(ctx.sender).is_some() {
+        // A connected user whose cursor went missing gets it back, admitted
+        // the way identity_connected admits them
+        admit_participant(ctx);
+        ctx.db.cursor().insert(cursor);
+    } else {
+        log::warn!("Dropped a cursor update from {}: not connected", ctx.sender);
+    }
+}
+
+#[reducer]
+// Records the part of the canvas the caller is looking at, for their client
+// to restore after a reconnect
+pub fn set_viewport(ctx: &ReducerContext, x: f32, y: f32, zoom: f32) -> Result<(), String> {
+    if !x.is_finite() || !y.is_finite() {
+        return Err(format!("Invalid viewport position ({}, {})", x, y));
+    }
+    if !(MIN_VIEWPORT_ZOOM..=MAX_VIEWPORT_ZOOM).contains(&zoom) {
+        return Err(format!(
+            "Zoom must be between {} and {}",
+            MIN_VIEWPORT_ZOOM, MAX_VIEWPORT_ZOOM
+        ));
+    }
+
+    store_session_state(
+        ctx,
+        SessionState {
+            viewport_x: x,
+            viewport_y: y,
+            viewport_zoom: zoom,
+            updated_at: ctx.timestamp,
+            ..session_state(ctx)
+        },
+    );
+    Ok(())
+}
+
+#[reducer]
+// Records the range of spatial buckets the caller's client is showing, as
+// bucket column and row numbers; see view_buckets for the matching
+// subscription
+pub fn set_view_buckets(
+    ctx: &ReducerContext,
+    min_cx: u32,
+    min_cy: u32,
+    max_cx: u32,
+    max_cy: u32,
+) -> Result<(), String> {
+    if min_cx > max_cx || min_cy > max_cy {
+        return Err(format!(
+            "Invalid bucket range ({}, {}) to ({}, {})",
+            min_cx, min_cy, max_cx, max_cy
+        ));
+    }
+
+    let view = ViewBuckets {
+        identity: ctx.sender,
+        min_cx,
+        min_cy,
+        max_cx,
+        max_cy,
+        updated_at: ctx.timestamp,
+    };
+    if ctx.db.view_buckets().identity().find(ctx.sender).is_some() {
+        ctx.db.view_buckets().identity().update(view);
+    } else {
+        ctx.db.view_buckets().insert(view);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Counts the points whose brush circle overlaps a circle, using the eraser's
+// hit test without erasing anything, into the caller's density_result row
+pub fn count_points_near(ctx: &ReducerContext, x: f32, y: f32, radius: f32) -> Result<(), String> {
+    if !x.is_finite() || !y.is_finite() {
+        return Err(format!("Invalid position ({}, {})", x, y));
+    }
+    if !radius.is_finite() || radius < 0.0 {
+        return Err(format!("Invalid radius {}", radius));
+    }
+
+    // No brush circle is wider than MAX_BRUSH_SIZE, so that bounds the search
+    let count = points_near(ctx, x, y, radius + MAX_BRUSH_SIZE)
+        .iter()
+        .filter(|point| point_overlaps_circle(point, x, y, radius))
+        .count() as u64;
+
+    let result = DensityResult {
+        identity: ctx.sender,
+        x,
+        y,
+        radius,
+        count,
+        counted_at: ctx.timestamp,
+    };
+    if ctx
+        .db
+        .density_result()
+        .identity()
+        .find(ctx.sender)
+        .is_some()
+    {
+        ctx.db.density_result().identity().update(result);
+    } else {
+        ctx.db.density_result().insert(result);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Lists the tiles of side `tile_size` that hold any drawing, replacing the
+// caller's tile_manifest rows, so clients can render big boards tile by tile
+// and skip empty regions
+pub fn list_populated_tiles(ctx: &ReducerContext, tile_size: f32) -> Result<(), String> {
+    if !tile_size.is_finite() || tile_size < MIN_TILE_SIZE {
+        return Err(format!("Tile size must be at least {}", MIN_TILE_SIZE));
+    }
+
+    let tile = |v: f32| (v / tile_size).floor() as i32;
+    let mut tiles: HashMap<(i32, i32), u64> = HashMap::new();
+    for point in ctx.db.canvas_point().iter() {
+        for tx in tile(point.x - point.size)..=tile(point.x + point.size) {
+            for ty in tile(point.y - point.size)..=tile(point.y + point.size) {
+                *tiles.entry((tx, ty)).or_insert(0) += 1;
+            }
+        }
+        if tiles.len() > MAX_MANIFEST_TILES {
+            return Err(format!(
+                "More than {} tiles hold drawing at that size; use larger tiles",
+                MAX_MANIFEST_TILES
+            ));
+        }
+    }
+
+    let old_rows: Vec<TileManifest> = ctx
+        .db
+        .tile_manifest()
+        .identity()
+        .filter(ctx.sender)
+        .collect();
+    for row in old_rows {
+        ctx.db.tile_manifest().delete(row);
+    }
+    let mut tiles: Vec<((i32, i32), u64)> = tiles.into_iter().collect();
+    tiles.sort_by_key(|&((tx, ty), _)| (ty, tx));
+    for ((tile_x, tile_y), point_count) in tiles {
+        ctx.db.tile_manifest().insert(TileManifest {
+            id: 0, // Will be auto-incremented
+            identity: ctx.sender,
+            tile_size,
+            tile_x,
+            tile_y,
+            point_count,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Moves the caller's cursor back to the origin, keeping their brush
+pub fn reset_cursor(ctx: &ReducerContext) {
+    match ctx.db.cursor().identity().find(ctx.sender) {
+        Some(cursor) => {
+            ctx.db.cursor().identity().update(Cursor {
+                x: 0.0,
+                y: 0.0,
+                last_updated: ctx.timestamp,
+                idle_hidden: false,
+                ..cursor
+            });
+        }
+        None => {
+            ctx.db.cursor().insert(default_cursor(ctx));
+        }
+    }
+}
+
+#[reducer]
+// Adds a new drawing point to the canvas. An empty `blend_mode` means normal;
+// points with an unknown one are dropped.
+pub fn add_drawing_point(
+    ctx: &ReducerContext,
+    x: f32,
+    y: f32,
+    color: String,
+    size: f32,
+    blend_mode: String,
+) {
+    if check_join_cooldown(ctx, "add_drawing_point").is_err()
+        || canvas_edit_blocked(ctx).is_some()
+        || check_tool_allowed(ctx, "add_drawing_point", TOOL_FREEHAND).is_err()
+    {
+        return;
+    }
+    let validated = validate_position(x, y)
+        .and_then(|_| validate_color(&color))
+        .and_then(|_| validate_size(size))
+        .and_then(|_| validate_blend_mode(&blend_mode));
+    let blend_mode = match validated {
+        Ok(blend_mode) => blend_mode,
+        Err(message) => {
+            log::warn!("Dropped a point from {}: {}", ctx.sender, message);
+            return;
+        }
+    };
+    let (x, y) = snap_to_grid(ctx, x, y, false);
+    let (x, y) = round_to_precision(ctx, x, y);
+    // Thin out dense strokes: skip points crowding the stroke's last one
+    if too_close_to_last_point(ctx, x, y) {
+        return;
+    }
+    draw_point(ctx, x, y, color, size, &blend_mode);
+}
+
+#[reducer]
+// Starts a new stroke with a server-allocated id, reported back through
+// stroke_alloc; the caller's drawing points are grouped under it until
+// end_stroke is called. Any stroke the caller left open is ended first.
+pub fn begin_stroke(ctx: &ReducerContext) {
+    let dangling = dangling_strokes(ctx.db.stroke_meta().identity().filter(ctx.sender));
+    for stroke in dangling {
+        finish_stroke(ctx, stroke);
+    }
+
+    let stroke = ctx.db.stroke_meta().insert(StrokeMeta {
+        stroke_id: 0, // Will be auto-incremented
+        identity: ctx.sender,
+        started_at: ctx.timestamp,
+        ended_at: None,
+        point_count: 0,
+    });
+
+    let alloc = StrokeAlloc {
+        identity: ctx.sender,
+        stroke_id: stroke.stroke_id,
+        allocated_at: ctx.timestamp,
+    };
+    if ctx.db.stroke_alloc().identity().find(ctx.sender).is_some() {
+        ctx.db.stroke_alloc().identity().update(alloc);
+    } else {
+        ctx.db.stroke_alloc().insert(alloc);
+    }
+
+    ctx.db.active_stroke().identity().delete(ctx.sender);
+    ctx.db.active_stroke().insert(ActiveStroke {
+        identity: ctx.sender,
+        stroke_id: stroke.stroke_id,
+        last_point_id: 0,
+    });
+    set_cursor_drawing(ctx, ctx.sender, true);
+
+    bump_user_stats(ctx, |stats| stats.strokes += 1);
+    record_room_activity(ctx, 0, 1);
+}
+
+#[reducer]
+// Marks one of the caller's strokes as finished
+pub fn end_stroke(ctx: &ReducerContext, stroke_id: u64) -> Result<(), String> {
+    let stroke = ctx
+        .db
+        .stroke_meta()
+        .stroke_id()
+        .find(stroke_id)
+        .filter(|stroke| stroke.identity == ctx.sender)
+        .ok_or_else(|| format!("Stroke {} is not a stroke of yours", stroke_id))?;
+
+    finish_stroke(ctx, stroke);
+    let is_active = ctx
+        .db
+        .active_stroke()
+        .identity()
+        .find(ctx.sender)
+        .is_some_and(|active| active.stroke_id == stroke_id);
+    if is_active {
+        ctx.db.active_stroke().identity().delete(ctx.sender);
+        set_cursor_drawing(ctx, ctx.sender, false);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Erases points near the given coordinates
+pub fn erase_points(ctx: &ReducerContext, x: f32, y: f32, radius: f32) {
+    if canvas_edit_blocked(ctx).is_some()
+        || !eraser_has_area(radius)
+        || !eraser_in_bounds(ctx, x, y)
+    {
+        return;
+    }
+
+    let settings = canvas_settings(ctx);
+    if settings.erase_mode == ERASE_MODE_STROKE && settings.confirm_stroke_erase {
+        // Loose points go at once; touched strokes wait for confirmation
+        let (stroke_hits, loose): (Vec<CanvasPoint>, Vec<CanvasPoint>) =
+            points_touching_eraser(ctx, x, y, radius, EraserShape::Circle)
+                .into_iter()
+                .partition(|point| point.stroke_id != 0);
+        let mut pending: HashSet<u64> = ctx
+            .db
+            .stroke_erase_candidate()
+            .identity()
+            .filter(ctx.sender)
+            .map(|row| row.stroke_id)
+            .collect();
+        for point in stroke_hits {
+            if pending.insert(point.stroke_id) {
+                ctx.db
+                    .stroke_erase_candidate()
+                    .insert(StrokeEraseCandidate {
+                        id: 0, // Will be auto-incremented
+                        identity: ctx.sender,
+                        stroke_id: point.stroke_id,
+                    });
+            }
+        }
+        erase_canvas_points(ctx, loose);
+        return;
+    }
+
+    // Remove a point if the eraser circle overlaps with the point's circle
+    let points_to_erase = points_under_eraser(ctx, x, y, radius, EraserShape::Circle);
+    erase_canvas_points(ctx, points_to_erase);
+}
+
+#[reducer]
+// Finishes a stroke erase held back by the confirm_stroke_erase setting:
+// with `confirm` the caller's candidate strokes are erased, otherwise they
+// are just forgotten
+pub fn confirm_stroke_erase(ctx: &ReducerContext, confirm: bool) -> Result<(), String> {
+    if confirm {
+        if let Some(reason) = canvas_edit_blocked(ctx) {
+            return Err(reason.to_string());
+        }
+        let guard = EraseGuard::new(ctx);
+        let points: Vec<CanvasPoint> = ctx
+            .db
+            .stroke_erase_candidate()
+            .identity()
+            .filter(ctx.sender)
+            .flat_map(|row| ctx.db.canvas_point().stroke_id().filter(row.stroke_id))
+            .filter(|point| guard.allows(point))
+            .collect();
+        erase_canvas_points(ctx, points);
+    }
+    clear_stroke_erase_candidates(ctx, ctx.sender);
+    Ok(())
+}
+
+#[reducer]
+// Removes strokes that duplicate an older one: same color and, within
+// DEDUP_TOLERANCE, the same bounding box and points. With `own_only` only the
+// caller's strokes are compared; comparing everyone's needs an admin. The
+// removed points go to the caller's erase undo stash.
+pub fn dedup_strokes(ctx: &ReducerContext, own_only: bool) {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        write_result(ctx, "dedup_strokes", false, reason.to_string());
+        return;
+    }
+    if !own_only && !is_admin(ctx) {
+        write_result(
+            ctx,
+            "dedup_strokes",
+            false,
+            "Only room admins can dedup everyone's strokes".to_string(),
+        );
+        return;
+    }
+
+    let mut candidates: Vec<StrokeIndex> = if own_only {
+        ctx.db
+            .stroke_index()
+            .identity()
+            .filter(ctx.sender)
+            .collect()
+    } else {
+        ctx.db.stroke_index().iter().collect()
+    };
+    candidates.sort_by_key(|row| row.stroke_id);
+
+    // Oldest first, so the first copy of each stroke is the one kept. Points
+    // are only loaded once the bounding boxes already agree.
+    let mut kept: Vec<(StrokeIndex, Option<Vec<CanvasPoint>>)> = Vec::new();
+    let mut duplicates: Vec<CanvasPoint> = Vec::new();
+    let (mut removed, mut protected) = (0, 0);
+    let guard = EraseGuard::new(ctx);
+    for row in candidates {
+        let load = |stroke_id: u64| -> Vec<CanvasPoint> {
+            ctx.db
+                .canvas_point()
+                .stroke_id()
+                .filter(stroke_id)
+                .collect()
+        };
+        let mut points: Option<Vec<CanvasPoint>> = None;
+        let mut duplicate = false;
+        for (other, other_points) in kept.iter_mut() {
+            if !strokes_roughly_coincide(&row, other) {
+                continue;
+            }
+            let other_points = other_points.get_or_insert_with(|| load(other.stroke_id));
+            let points = points.get_or_insert_with(|| load(row.stroke_id));
+            if point_sets_match(points, other_points) {
+                duplicate = true;
+                break;
+            }
+        }
+
+        if duplicate {
+            // A duplicate the eraser couldn't fully remove is left whole
+            let points = points.unwrap_or_else(|| load(row.stroke_id));
+            if points.iter().all(|point| guard.allows(point)) {
+                duplicates.extend(points);
+                removed += 1;
+            } else {
+                protected += 1;
+            }
+        } else {
+            kept.push((row, points));
+        }
+    }
+
+    erase_canvas_points(ctx, duplicates);
+    write_result(
+        ctx,
+        "dedup_strokes",
+        true,
+        format!(
+            "Removed {} duplicate strokes, kept {} on locked layers or hidden",
+            removed, protected
+        ),
+    );
+}
+
+#[reducer]
+// Erases points within the square of side 2 * `radius` centered on the given
+// coordinates, for a rectangular eraser tip
+pub fn erase_square(ctx: &ReducerContext, x: f32, y: f32, radius: f32) {
+    if canvas_edit_blocked(ctx).is_some()
+        || !eraser_has_area(radius)
+        || !eraser_in_bounds(ctx, x, y)
+    {
+        return;
+    }
+    let points_to_erase = points_under_eraser(ctx, x, y, radius, EraserShape::Square);
+    erase_canvas_points(ctx, points_to_erase);
+}
+
+#[reducer]
+// Erases everything the eraser touches while dragged along a path, covering
+// the gaps between samples that separate erase_points calls would leave
+pub fn erase_along_path(
+    ctx: &ReducerContext,
+    points: Vec<PathPoint>,
+    radius: f32,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if points.is_empty() || !eraser_has_area(radius) {
+        return Ok(());
+    }
+    if points.len() > MAX_ERASE_PATH_POINTS {
+        return Err(format!(
+            "Erase paths are limited to {} points",
+            MAX_ERASE_PATH_POINTS
+        ));
+    }
+    if let Some(point) = points.iter().find(|p| !eraser_in_bounds(ctx, p.x, p.y)) {
+        return Err(format!(
+            "Erase path leaves the canvas at ({}, {})",
+            point.x, point.y
+        ));
+    }
+
+    let points_to_erase = points_under_eraser_path(ctx, &points, radius);
+    erase_canvas_points(ctx, points_to_erase);
+    Ok(())
+}
+
+#[reducer]
+// Fades points near the given coordinates instead of deleting them outright
+pub fn soft_erase(ctx: &ReducerContext, x: f32, y: f32, radius: f32) {
+    if canvas_edit_blocked(ctx).is_some()
+        || !eraser_has_area(radius)
+        || !eraser_in_bounds(ctx, x, y)
+    {
+        return;
+    }
+    // Fading depends on distance, so soft erasing always works point by point
+    let points_to_fade = points_touching_eraser(ctx, x, y, radius, EraserShape::Circle);
+    let mut erased = 0;
+
+    let mut batch = PointBatch::new(ctx);
+    for point in points_to_fade {
+        // Points at the eraser center lose the most opacity, fading out
+        // linearly to no effect where the two circles just touch
+        let combined_radius = radius + point.size;
+        let dist = ((point.x - x).powi(2) + (point.y - y).powi(2)).sqrt();
+        let falloff = if combined_radius > 0.0 {
+            (1.0 - dist / combined_radius).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        let opacity = (point.opacity - SOFT_ERASE_STRENGTH * falloff).clamp(0.0, 1.0);
+
+        if opacity < MIN_VISIBLE_OPACITY {
+            batch.delete(point);
+            erased += 1;
+        } else {
+            batch.update(CanvasPoint { opacity, ..point });
+        }
+    }
+    batch.finish();
+    if erased > 0 {
+        bump_user_stats(ctx, |stats| stats.erases += erased);
+        record_room_activity(ctx, 0, 0);
+    }
+}
+
+#[reducer]
+// Records which points an erase at the given coordinates would remove
+pub fn preview_erase(ctx: &ReducerContext, x: f32, y: f32, radius: f32) {
+    // Each preview replaces the previous one for this user
+    clear_erase_preview_for(ctx, ctx.sender);
+    if !eraser_has_area(radius) || !eraser_in_bounds(ctx, x, y) {
+        return;
+    }
+
+    let point_ids = points_under_eraser(ctx, x, y, radius, EraserShape::Circle)
+        .into_iter()
+        .map(|point| point.id);
+
+    for point_id in point_ids {
+        ctx.db.erase_preview().insert(ErasePreview {
+            id: 0, // Will be auto-incremented
+            identity: ctx.sender,
+            point_id,
+        });
+    }
+}
+
+#[reducer]
+// Erases the points in the caller's current erase preview
+pub fn commit_erase(ctx: &ReducerContext) {
+    if canvas_edit_blocked(ctx).is_some() {
+        return;
+    }
+    // A layer may have been locked since the preview was taken
+    let guard = EraseGuard::new(ctx);
+    let previewed: Vec<ErasePreview> = ctx
+        .db
+        .erase_preview()
+        .identity()
+        .filter(ctx.sender)
+        .collect();
+    let mut points_to_erase = Vec::new();
+
+    for row in previewed {
+        // The point may already be gone if someone else erased it
+        if let Some(point) = ctx.db.canvas_point().id().find(row.point_id) {
+            if guard.allows(&point) {
+                points_to_erase.push(point);
+            }
+        }
+        ctx.db.erase_preview().delete(row);
+    }
+    erase_canvas_points(ctx, points_to_erase);
+}
+
+#[reducer]
+// Discards the caller's erase preview without erasing anything
+pub fn clear_erase_preview(ctx: &ReducerContext) {
+    clear_erase_preview_for(ctx, ctx.sender);
+}
+
+#[reducer]
+// Saves the current canvas state with a given name and optional description.
+// A canvas over MAX_POINTS_PER_STATE points is refused, unless `downsample` is
+// set: then every Nth point in paint order is kept so the save fits. With
+// `skip_duplicates`, a save matching the content hash of one of the caller's
+// states isn't made and the result names that state instead.
+pub fn save_canvas_state(
+    ctx: &ReducerContext,
+    name: String,
+    description: Option<String>,
+    downsample: bool,
+    skip_duplicates: bool,
+) {
+    let validated = validate_state_name(&name).and_then(|name| {
+        validate_state_description(&description.unwrap_or_default())
+            .map(|description| (name, description))
+    });
+    let (name, description) = match validated {
+        Ok(validated) => validated,
+        Err(message) => {
+            write_result(ctx, "save_canvas_state", false, message);
+            return;
+        }
+    };
+
+    // Don't snapshot half-drawn strokes: with a quiet window set, wait until
+    // nobody has drawn for that long. Loaded points don't count as drawing.
+    let quiet_window = canvas_settings(ctx).save_quiet_window_ms;
+    if quiet_window > 0 {
+        let quiet_since = ctx.timestamp - Duration::from_millis(quiet_window);
+        if room_analytics(ctx).last_activity > quiet_since {
+            write_result(ctx, "save_canvas_state", false, SAVE_BUSY.to_string());
+            return;
+        }
+    }
+
+    let mut points: Vec<CanvasPoint> = ctx.db.canvas_point().iter().collect();
+    points.sort_by_key(|point| point.draw_order);
+    let point_count = points.len() as u64;
+    let over_cap = point_count > MAX_POINTS_PER_STATE;
+    if over_cap {
+        if !downsample {
+            log::warn!(
+                "Refused a save of {} points from {}, over the {} point cap",
+                point_count,
+                ctx.sender,
+                MAX_POINTS_PER_STATE
+            );
+            if let Err(message) = check_state_size(ctx) {
+                write_result(ctx, "save_canvas_state", false, message);
+            }
+            return;
+        }
+
+        let (kept, stride) = downsample_to_cap(points, MAX_POINTS_PER_STATE);
+        points = kept;
+        log::info!(
+            "Downsampled a save from {}: kept {} of {} points (every {})",
+            ctx.sender,
+            points.len(),
+            point_count,
+            stride
+        );
+    }
+
+    if skip_duplicates {
+        let saved: Vec<SavedCanvasPoint> = points
+            .iter()
+            .map(|point| to_saved_point(0, point))
+            .collect();
+        let hash = content_hash(&saved);
+        let duplicate = ctx
+            .db
+            .canvas_state()
+            .iter()
+            .filter(|state| state.created_by == ctx.sender && state.content_hash == hash)
+            .min_by_key(|state| state.id);
+        if let Some(duplicate) = duplicate {
+            // An exact copy of the live canvas is already saved
+            if !over_cap {
+                mark_canvas_saved(ctx);
+            }
+            write_result(
+                ctx,
+                "save_canvas_state",
+                true,
+                format!(
+                    "Canvas state {} already holds this drawing, so no new state was saved",
+                    duplicate.id
+                ),
+            );
+            return;
+        }
+    }
+
+    if over_cap {
+        let state = save_points_as_state(ctx, name, description, points);
+        write_result(
+            ctx,
+            "save_canvas_state",
+            true,
+            format!(
+                "Saved canvas state {} downsampled to {} of {} points",
+                state.id, state.point_count, point_count
+            ),
+        );
+    } else {
+        save_canvas_points(ctx, name, description, false);
+    }
+    bump_user_stats(ctx, |stats| stats.states_saved += 1);
+}
+
+// Keeps every Nth item, with N the smallest stride that brings the list down
+// to `cap`, and returns the kept items with that stride. A list already
+// within the cap comes back whole, with a stride of 1.
+fn downsample_to_cap<T>(items: Vec<T>, cap: u64) -> (Vec<T>, usize) {
+    let stride = (items.len() as u64).div_ceil(cap.max(1)).max(1) as usize;
+    (items.into_iter().step_by(stride).collect(), stride)
+}
+
+#[reducer]
+// Replaces the caller's selection with the given points. Ids of points that
+// don't exist are skipped.
+pub fn set_selection(ctx: &ReducerContext, point_ids: Vec<u64>) -> Result<(), String> {
+    if point_ids.len() > MAX_SELECTION_POINTS {
+        return Err(format!(
+            "Selections are limited to {} points",
+            MAX_SELECTION_POINTS
+        ));
+    }
+
+    clear_selection_for(ctx, ctx.sender);
+    let unique: HashSet<u64> = point_ids.into_iter().collect();
+    for point_id in unique {
+        if ctx.db.canvas_point().id().find(point_id).is_some() {
+            ctx.db.selected_point().insert(SelectedPoint {
+                id: 0, // Will be auto-incremented
+                identity: ctx.sender,
+                point_id,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+// Sets the opacity of every selected point the caller owns, clamped to 0..=1;
+// other users' points in the selection are left alone
+pub fn set_selection_opacity(ctx: &ReducerContext, opacity: f32) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if !opacity.is_finite() {
+        return Err(format!("Invalid opacity {}", opacity));
+    }
+    let opacity = opacity.clamp(0.0, 1.0);
+
+    let mut batch = PointBatch::new(ctx);
+    for point in selected_points(ctx) {
+        if point.identity == ctx.sender && point.opacity != opacity {
+            batch.update(CanvasPoint { opacity, ..point });
+        }
+    }
+    batch.finish();
+    Ok(())
+}
+
+#[reducer]
+// Saves just the caller's selected points as a new canvas state and reports
+// its id. The live canvas still counts as unsaved.
+pub fn save_selection_as_state(ctx: &ReducerContext, name: String) {
+    let outcome = validate_state_name(&name).and_then(|name| {
+        let points = selected_points(ctx);
+        if points.is_empty() {
+            return Err("Select some points to save first".to_string());
+        }
+
+        if points.len() as u64 > MAX_POINTS_PER_STATE {
+            return Err(format!(
+                "Selections over {} points are too large to save",
+                MAX_POINTS_PER_STATE
+            ));
+        }
+
+        let state = save_points_as_state(ctx, name, String::new(), points);
+        bump_user_stats(ctx, |stats| stats.states_saved += 1);
+        Ok(format!("Saved the selection as canvas state {}", state.id))
+    });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "save_selection_as_state", true, message),
+        Err(message) => write_result(ctx, "save_selection_as_state", false, message),
+    }
+}
+
+#[reducer]
+// Clears all drawing points from the canvas
+pub fn clear_canvas(ctx: &ReducerContext) {
+    if canvas_edit_blocked(ctx).is_some() {
+        return;
+    }
+    backup_canvas_for_clear(ctx);
+    clear_canvas_points(ctx);
+}
+
+// Replaces clear_backup with a copy of the live canvas, so undo_clear can
+// bring it back. Backing up an empty canvas keeps the previous backup around.
+fn backup_canvas_for_clear(ctx: &ReducerContext) {
+    if ctx.db.canvas_point().count() > 0 {
+        let old_backup: Vec<ClearBackup> = ctx.db.clear_backup().iter().collect();
+        for row in old_backup {
+            ctx.db.clear_backup().delete(row);
+        }
+        let old_strokes: Vec<ClearStrokeBackup> = ctx.db.clear_stroke_backup().iter().collect();
+        for row in old_strokes {
+            ctx.db.clear_stroke_backup().delete(row);
+        }
+
+        let mut stroke_ids = HashSet::new();
+        for point in ctx.db.canvas_point().iter() {
+            if point.stroke_id != 0 {
+                stroke_ids.insert(point.stroke_id);
+            }
+            ctx.db.clear_backup().insert(ClearBackup {
+                id: 0, // Will be auto-incremented
+                cleared_by: ctx.sender,
+                identity: point.identity,
+                x: point.x,
+                y: point.y,
+                color: point.color,
+                size: point.size,
+                opacity: point.opacity,
+                draw_order: point.draw_order,
+                stroke_id: point.stroke_id,
+                layer_id: point.layer_id,
+                origin: point.origin,
+                blend_mode: point.blend_mode,
+            });
+        }
+        for stroke_id in stroke_ids {
+            let Some(stroke) = ctx.db.stroke_meta().stroke_id().find(stroke_id) else {
+                continue;
+            };
+            ctx.db.clear_stroke_backup().insert(ClearStrokeBackup {
+                stroke_id,
+                identity: stroke.identity,
+                started_at: stroke.started_at,
+                ended_at: stroke.ended_at,
+                point_count: stroke.point_count,
+                label: ctx
+                    .db
+                    .stroke_label()
+                    .stroke_id()
+                    .find(stroke_id)
+                    .map(|row| row.label),
+                hidden: ctx
+                    .db
+                    .stroke_visibility()
+                    .stroke_id()
+                    .find(stroke_id)
+                    .is_some_and(|row| row.hidden),
+            });
+        }
+    }
+}
+
+#[reducer]
+// Clears the canvas gradually over `steps` scheduled ticks, removing an even
+// share of the remaining points each tick so the drawing dissolves. The
+// canvas is backed up for undo_clear first, as with clear_canvas. Starting a
+// new fade replaces one that is still running.
+pub fn fade_clear(ctx: &ReducerContext, steps: u32) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can fade out the canvas".to_string());
+    }
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if steps == 0 || steps > MAX_FADE_CLEAR_STEPS {
+        return Err(format!(
+            "Steps must be between 1 and {}",
+            MAX_FADE_CLEAR_STEPS
+        ));
+    }
+
+    let running: Vec<FadeClearSchedule> = ctx.db.fade_clear_schedule().iter().collect();
+    for row in running {
+        ctx.db.fade_clear_schedule().delete(row);
+    }
+    backup_canvas_for_clear(ctx);
+    schedule_fade_clear_step(ctx, steps, ctx.sender);
+    log::info!("User {} started a {} step fade clear", ctx.sender, steps);
+    Ok(())
+}
+
+// Queues a fade clear step to run one FADE_CLEAR_TICK from now
+fn schedule_fade_clear_step(ctx: &ReducerContext, steps_left: u32, started_by: Identity) {
+    ctx.db.fade_clear_schedule().insert(FadeClearSchedule {
+        scheduled_id: 0, // Will be auto-incremented
+        scheduled_at: (ctx.timestamp + FADE_CLEAR_TICK).into(),
+        steps_left,
+        started_by,
+    });
+}
+
+#[reducer]
+// Scheduled: removes one step's share of the canvas for fade_clear. Points
+// are picked in a scrambled order so the fade is spread over the canvas.
+pub fn fade_clear_step(ctx: &ReducerContext, step: FadeClearSchedule) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("fade_clear_step may only be run by the scheduler".to_string());
+    }
+
+    // A frozen canvas can't change, so the fade waits for it to thaw
+    if canvas_settings(ctx).canvas_frozen {
+        schedule_fade_clear_step(ctx, step.steps_left, step.started_by);
+        return Ok(());
+    }
+
+    if step.steps_left <= 1 {
+        // Clearing also resets canvas_bounds to empty
+        clear_canvas_points(ctx);
+        log::info!("Fade clear started by {} finished", step.started_by);
+        return Ok(());
+    }
+
+    let mut points: Vec<CanvasPoint> = ctx.db.canvas_point().iter().collect();
+    let share = points.len().div_ceil(step.steps_left as usize);
+    points.sort_by_key(|point| point.id.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    let mut batch = PointBatch::new(ctx);
+    for point in points.into_iter().take(share) {
+        batch.delete(point);
+    }
+    batch.finish();
+    // Shrink canvas_bounds to what is left, as an erase would
+    refresh_canvas_bounds(ctx);
+    schedule_fade_clear_step(ctx, step.steps_left - 1, step.started_by);
+    Ok(())
+}
+
+#[reducer]
+// Loads a saved canvas state by its ID. Unless `force` is set, a canvas with
+// changes no save has captured is left alone and the caller gets a
+// "confirm_needed" action_result, so they can ask the user and retry.
+// Loaded points normally count as drawn now, so the point TTL and the
+// eraser's fresh-point protection start over for them. `preserve_timestamps`
+// gives them back their original draw times instead: old points may then
+// expire right after loading, and canvas_history records them at load time
+// while their timestamps say otherwise.
+// The clear and restore run in this reducer's single transaction, so other
+// users' drawing lands wholly before or after the load, never in between.
+pub fn load_canvas_state(
+    ctx: &ReducerContext,
+    state_id: u64,
+    force: bool,
+    preserve_timestamps: bool,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    // An unknown state leaves the canvas alone rather than wiping it
+    let state = ctx
+        .db
+        .canvas_state()
+        .id()
+        .find(state_id)
+        .ok_or_else(|| format!("Canvas state {} not found", state_id))?;
+    let meta = canvas_meta(ctx);
+    if !force && meta.point_count > 0 && meta.revision != meta.saved_revision {
+        write_result(ctx, "load_canvas_state", false, CONFIRM_NEEDED.to_string());
+        return Ok(());
+    }
+
+    let point_count = restore_canvas_state(ctx, &state, preserve_timestamps);
+    ctx.db.state_load_log().insert(StateLoadLog {
+        id: 0, // Will be auto-incremented
+        state_id: state.id,
+        loaded_by: ctx.sender,
+        loaded_at: ctx.timestamp,
+    });
+
+    log::info!(
+        "User {} loaded canvas state {} ({}) with {} points",
+        ctx.sender,
+        state.id,
+        state.name,
+        point_count
+    );
+    Ok(())
+}
+
+// Hashes a state link token for storage, so the state_link table never holds
+// a usable token
+fn hash_state_link_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+#[reducer]
+// Creates a share link for a saved state, allowed for its creator and room
+// admins. The client generates `token` with a secure random source and keeps
+// it for the URL; the server's own RNG is seeded from the reducer timestamp
+// and would make tokens predictable. The link expires after
+// `expires_after_seconds`, or never when that is None.
+pub fn create_state_link(
+    ctx: &ReducerContext,
+    state_id: u64,
+    token: String,
+    expires_after_seconds: Option<u64>,
+) -> Result<(), String> {
+    let well_formed = (MIN_STATE_LINK_TOKEN_LEN..=MAX_STATE_LINK_TOKEN_LEN).contains(&token.len())
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !well_formed {
+        return Err(format!(
+            "Link tokens must be {} to {} URL-safe characters",
+            MIN_STATE_LINK_TOKEN_LEN, MAX_STATE_LINK_TOKEN_LEN
+        ));
+    }
+    let state = ctx
+        .db
+        .canvas_state()
+        .id()
+        .find(state_id)
+        .ok_or_else(|| format!("Canvas state {} not found", state_id))?;
+    if state.created_by != ctx.sender && !is_admin(ctx) {
+        return Err("Only the state's creator or an admin can share it".to_string());
+    }
+
+    let token_hash = hash_state_link_token(&token);
+    if ctx.db.state_link().token_hash().find(&token_hash).is_some() {
+        return Err("That link token is already in use".to_string());
+    }
+    let expires_at =
+        expires_after_seconds.map(|seconds| ctx.timestamp + Duration::from_secs(seconds));
+    ctx.db.state_link().insert(StateLink {
+        token_hash,
+        state_id,
+        created_by: ctx.sender,
+        created_at: ctx.timestamp,
+        expires_at,
+    });
+    log::info!("User {} shared canvas state {}", ctx.sender, state_id);
+    Ok(())
+}
+
+#[reducer]
+// Loads the state a share link points to, like load_canvas_state. A link
+// that is unknown, expired or whose state was deleted is refused through
+// action_result and leaves the canvas alone, as is a load the caller can't
+// make right now (e.g. a frozen canvas).
+pub fn load_by_link(ctx: &ReducerContext, token: String, force: bool) {
+    let state_id = ctx
+        .db
+        .state_link()
+        .token_hash()
+        .find(hash_state_link_token(&token))
+        .filter(|link| link.expires_at.is_none_or(|at| at > ctx.timestamp))
+        .map(|link| link.state_id)
+        .filter(|&state_id| ctx.db.canvas_state().id().find(state_id).is_some());
+    match state_id {
+        Some(state_id) => {
+            if let Err(message) = load_canvas_state(ctx, state_id, force, false) {
+                write_result(ctx, "load_by_link", false, message);
+            }
+        }
+        None => write_result(
+            ctx,
+            "load_by_link",
+            false,
+            "This link is invalid or has expired".to_string(),
+        ),
+    }
+}
+
+#[reducer]
+// Scheduled: removes state links that have expired or whose state is gone
+pub fn prune_state_links(
+    ctx: &ReducerContext,
+    _schedule: StateLinkPruneSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("prune_state_links may only be run by the scheduler".to_string());
+    }
+
+    let stale: Vec<StateLink> = ctx
+        .db
+        .state_link()
+        .iter()
+        .filter(|link| {
+            link.expires_at.is_some_and(|at| at <= ctx.timestamp)
+                || ctx.db.canvas_state().id().find(link.state_id).is_none()
+        })
+        .collect();
+    let pruned = stale.len();
+    for link in stale {
+        ctx.db.state_link().delete(link);
+    }
+    if pruned > 0 {
+        log::info!("Pruned {} state links", pruned);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Deletes a saved canvas state by its ID
+pub fn delete_canvas_state(ctx: &ReducerContext, state_id: u64) {
+    // Check if the state exists
+    if let Some(state) = ctx.db.canvas_state().id().find(state_id) {
+        // Only allow deletion by the creator or if the state exists
+        if state.created_by == ctx.sender {
+            if state.is_locked {
+                write_result(
+                    ctx,
+                    "delete_canvas_state",
+                    false,
+                    format!("Canvas state {} is locked", state_id),
+                );
+                return;
+            }
+
+            // Store the name before deleting
+            let state_name = state.name.clone();
+
+            // Delete the state and all saved points associated with it
+            delete_canvas_state_and_points(ctx, state);
+
+            log::info!(
+                "User {} deleted canvas state {} ({})",
+                ctx.sender,
+                state_id,
+                state_name
+            );
+        }
+    }
+}
+
+#[reducer]
+// Creates a named brush preset available to the whole room
+pub fn create_brush_preset(
+    ctx: &ReducerContext,
+    name: String,
+    color: String,
+    size: f32,
+    opacity: f32,
+) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can create brush presets".to_string());
+    }
+
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_PRESET_NAME_LEN {
+        return Err(format!(
+            "Preset name must be 1 to {} characters",
+            MAX_PRESET_NAME_LEN
+        ));
+    }
+    if ctx.db.brush_preset().name().find(&name).is_some() {
+        return Err(format!("A preset named '{}' already exists", name));
+    }
+    validate_color(&color)?;
+    validate_size(size)?;
+    validate_opacity(opacity)?;
+
+    ctx.db.brush_preset().insert(BrushPreset {
+        id: 0, // Will be auto-incremented
+        name,
+        color,
+        size,
+        opacity,
+        created_by: ctx.sender,
+    });
+    Ok(())
+}
+
+#[reducer]
+// Deletes a brush preset by its ID
+pub fn delete_brush_preset(ctx: &ReducerContext, preset_id: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can delete brush presets".to_string());
+    }
+
+    match ctx.db.brush_preset().id().find(preset_id) {
+        Some(preset) => {
+            ctx.db.brush_preset().delete(preset);
+            Ok(())
+        }
+        None => Err(format!("Brush preset {} not found", preset_id)),
+    }
+}
+
+#[reducer]
+// Replaces the caller's quick-switch brush sizes; an empty list removes them
+pub fn set_size_presets(ctx: &ReducerContext, sizes: Vec<f32>) -> Result<(), String> {
+    if sizes.len() > MAX_SIZE_PRESETS {
+        return Err(format!(
+            "At most {} size presets are allowed",
+            MAX_SIZE_PRESETS
+        ));
+    }
+    for &size in &sizes {
+        validate_size(size)?;
+    }
+
+    if sizes.is_empty() {
+        ctx.db.size_preset().identity().delete(ctx.sender);
+        return Ok(());
+    }
+    let presets = SizePreset {
+        identity: ctx.sender,
+        sizes,
+        updated_at: ctx.timestamp,
+    };
+    if ctx.db.size_preset().identity().find(ctx.sender).is_some() {
+        ctx.db.size_preset().identity().update(presets);
+    } else {
+        ctx.db.size_preset().insert(presets);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Rebuilds the color histogram from the current canvas points
+pub fn recompute_color_histogram(ctx: &ReducerContext) {
+    rebuild_color_histogram(ctx);
+}
+
+#[reducer]
+// Exports the live canvas as an SVG trimmed to the drawing's bounding box.
+// With `trim_outliers`, stray points past the room's outlier_threshold are
+// left out before the bounds are taken; the canvas itself keeps them.
+pub fn export_canvas_svg(ctx: &ReducerContext, trim_outliers: bool) {
+    let mut points: Vec<CanvasPoint> = ctx.db.canvas_point().iter().collect();
+    points.sort_by_key(|point| point.draw_order);
+    if trim_outliers {
+        let threshold = canvas_settings(ctx).outlier_threshold;
+        points = without_outliers(points, |p| (p.x, p.y), threshold);
+    }
+
+    write_export(ctx, "svg", render_svg(&points));
+}
+
+#[reducer]
+// Exports a saved state as a JSON manifest for a client-side PNG renderer.
+// With `trim_outliers`, stray points past the room's outlier_threshold are
+// left out of the manifest; the stored state keeps them.
+pub fn export_render_manifest(
+    ctx: &ReducerContext,
+    state_id: u64,
+    trim_outliers: bool,
+) -> Result<(), String> {
+    let state = ctx
+        .db
+        .canvas_state()
+        .id()
+        .find(state_id)
+        .ok_or_else(|| format!("Canvas state {} not found", state_id))?;
+
+    let mut points = saved_points_in_order(ctx, state_id);
+    if trim_outliers {
+        let threshold = canvas_settings(ctx).outlier_threshold;
+        points = without_outliers(points, |p| (p.x, p.y), threshold);
+    }
+    let points: Vec<serde_json::Value> = points.iter().map(saved_point_json).collect();
+
+    let manifest = json!({
+        "version": RENDER_MANIFEST_VERSION,
+        "state_id": state.id,
+        "name": state.name,
+        "description": state.description,
+        "width": CANVAS_WIDTH,
+        "height": CANVAS_HEIGHT,
+        "background": CANVAS_BACKGROUND,
+        "points": points,
+    });
+
+    write_export(ctx, "render-manifest", manifest.to_string());
+    Ok(())
+}
+
+#[reducer]
+// Exports the canvas_history events that built a saved state, oldest first,
+// as versioned JSON for a client to play back as a time-lapse. The events
+// start after the canvas was last empty before the save. States that weren't
+// saved from the live canvas, or whose history has been pruned, can't be
+// played back.
+pub fn export_state_timelapse(ctx: &ReducerContext, state_id: u64) -> Result<(), String> {
+    let state = ctx
+        .db
+        .canvas_state()
+        .id()
+        .find(state_id)
+        .ok_or_else(|| format!("Canvas state {} not found", state_id))?;
+    if state.history_seq == 0 {
+        return Err(format!(
+            "Canvas state {} wasn't saved from the live canvas, so it has no history",
+            state_id
+        ));
+    }
+
+    let mut events: Vec<CanvasHistory> = ctx
+        .db
+        .canvas_history()
+        .iter()
+        .filter(|event| event.seq <= state.history_seq)
+        .collect();
+    events.sort_by_key(|event| event.seq);
+
+    // Replay to find where the canvas was last empty, and to check that the
+    // kept history really rebuilds the save
+    let mut live: HashSet<u64> = HashSet::new();
+    let mut start = 0;
+    for (i, event) in events.iter().enumerate() {
+        match event.kind.as_str() {
+            HISTORY_ADD => {
+                live.insert(event.point_id);
+            }
+            HISTORY_REMOVE => {
+                live.remove(&event.point_id);
+            }
+            _ => {}
+        }
+        if live.is_empty() {
+            start = i + 1;
+        }
+    }
+    if live.len() as u64 != state.point_count {
+        return Err(format!(
+            "The history behind canvas state {} has been pruned",
+            state_id
+        ));
+    }
+
+    let events = &events[start..];
+    if events.len() > MAX_TIMELAPSE_EVENTS {
+        return Err(format!(
+            "Canvas state {} took {} events to build, more than the {} a time-lapse can hold",
+            state_id,
+            events.len(),
+            MAX_TIMELAPSE_EVENTS
+        ));
+    }
+    let events: Vec<serde_json::Value> = events
+        .iter()
+        .map(|event| {
+            json!({
+                "seq": event.seq,
+                "kind": event.kind,
+                "point_id": event.point_id,
+                "x": event.x,
+                "y": event.y,
+                "color": event.color,
+                "size": event.size,
+                "opacity": event.opacity,
+                "recorded_at_micros": event.recorded_at.to_micros_since_unix_epoch(),
+            })
+        })
+        .collect();
+
+    let timelapse = json!({
+        "version": TIMELAPSE_VERSION,
+        "state_id": state.id,
+        "name": state.name,
+        "width": CANVAS_WIDTH,
+        "height": CANVAS_HEIGHT,
+        "background": CANVAS_BACKGROUND,
+        "events": events,
+    });
+
+    write_export(ctx, "timelapse", timelapse.to_string());
+    Ok(())
+}
+
+#[reducer]
+// Imports <circle> elements from an SVG document as points owned by the caller
+pub fn import_svg(ctx: &ReducerContext, svg: String) {
+    if check_join_cooldown(ctx, "import_svg").is_err()
+        || canvas_edit_blocked(ctx).is_some()
+        || check_tool_allowed(ctx, "import_svg", TOOL_SVG_IMPORT).is_err()
+    {
+        return;
+    }
+    let mut imported = 0;
+    let mut skipped = 0;
+
+    // Walk every <circle ...> start tag in document order
+    for chunk in svg.split("<circle").skip(1).take(MAX_IMPORT_ELEMENTS) {
+        let tag = chunk.split('>').next().unwrap_or("");
+        match svg_circle_to_point(ctx, tag) {
+            Some(point) => {
+                insert_canvas_point(ctx, point);
+                imported += 1;
+            }
+            None => skipped += 1,
+        }
+    }
+    if imported > 0 {
+        record_room_activity(ctx, imported, 0);
+    }
+
+    log::info!(
+        "User {} imported {} points from SVG ({} circles skipped)",
+        ctx.sender,
+        imported,
+        skipped
+    );
+}
+
+#[reducer]
+// Scheduled: deletes canvas_history events and state_load_log entries older
+// than their retention periods
+pub fn prune_canvas_history(
+    ctx: &ReducerContext,
+    _schedule: HistoryPruneSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("prune_canvas_history may only be run by the scheduler".to_string());
+    }
+    if !is_room_active(ctx) {
+        return Ok(());
+    }
+
+    let cutoff = ctx.timestamp - HISTORY_RETENTION;
+    let expired: Vec<CanvasHistory> = ctx
+        .db
+        .canvas_history()
+        .iter()
+        .filter(|event| event.recorded_at < cutoff)
+        .collect();
+
+    let pruned = expired.len();
+    for event in expired {
+        ctx.db.canvas_history().delete(event);
+    }
+
+    let load_cutoff = ctx.timestamp - STATE_LOAD_LOG_RETENTION;
+    let expired_loads: Vec<StateLoadLog> = ctx
+        .db
+        .state_load_log()
+        .iter()
+        .filter(|load| load.loaded_at < load_cutoff)
+        .collect();
+    let pruned_loads = expired_loads.len();
+    for load in expired_loads {
+        ctx.db.state_load_log().delete(load);
+    }
+
+    log::info!(
+        "Pruned {} canvas history events and {} state load log entries",
+        pruned,
+        pruned_loads
+    );
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: hides cursors that have gone idle and removes those whose user
+// has no open connection, as left behind by a missed disconnect. join_record
+// counts each identity's connections, so it decides who is connected.
+pub fn reconcile_cursors(
+    ctx: &ReducerContext,
+    _schedule: CursorReconcileSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("reconcile_cursors may only be run by the scheduler".to_string());
+    }
+
+    let ghosts: Vec<Cursor> = ctx
+        .db
+        .cursor()
+        .iter()
+        .filter(|cursor| {
+            ctx.db
+                .join_record()
+                .identity()
+                .find(cursor.identity)
+                .is_none()
+        })
+        .collect();
+
+    let reaped = !ghosts.is_empty();
+    for cursor in ghosts {
+        log::info!("Removing ghost cursor for {}", cursor.identity);
+        ctx.db.spectator().identity().delete(cursor.identity);
+        ctx.db.cursor().delete(cursor);
+    }
+    if reaped {
+        // Places held by ghosts go to whoever is waiting
+        promote_spectator(ctx);
+    }
+
+    let idle_cutoff = ctx.timestamp - CURSOR_IDLE_TIMEOUT;
+    let idle: Vec<Cursor> = ctx
+        .db
+        .cursor()
+        .iter()
+        .filter(|cursor| cursor.last_updated < idle_cutoff && !cursor.idle_hidden)
+        .collect();
+    for cursor in idle {
+        ctx.db.cursor().identity().update(Cursor {
+            idle_hidden: true,
+            ..cursor
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: stops showing cursors as drawing once they have sat still for
+// DRAWING_IDLE_TIMEOUT, or once their user has no stroke open
+pub fn clear_idle_drawing(
+    ctx: &ReducerContext,
+    _schedule: DrawingSweepSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("clear_idle_drawing may only be run by the scheduler".to_string());
+    }
+
+    let cutoff = ctx.timestamp - DRAWING_IDLE_TIMEOUT;
+    let stale: Vec<Cursor> = ctx
+        .db
+        .cursor()
+        .iter()
+        .filter(|cursor| {
+            cursor.is_drawing
+                && (cursor.last_updated < cutoff
+                    || ctx
+                        .db
+                        .active_stroke()
+                        .identity()
+                        .find(cursor.identity)
+                        .is_none())
+        })
+        .collect();
+    for cursor in stale {
+        ctx.db.cursor().identity().update(Cursor {
+            is_drawing: false,
+            ..cursor
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Shows or hides the caller's "is typing" indicator
+pub fn set_typing(ctx: &ReducerContext, is_typing: bool) {
+    let existing = ctx.db.typing_indicator().identity().find(ctx.sender);
+    match (is_typing, existing) {
+        // Refresh the start time so ongoing typing doesn't expire
+        (true, Some(indicator)) => {
+            ctx.db
+                .typing_indicator()
+                .identity()
+                .update(TypingIndicator {
+                    started_at: ctx.timestamp,
+                    ..indicator
+                });
+        }
+        (true, None) => {
+            ctx.db.typing_indicator().insert(TypingIndicator {
+                identity: ctx.sender,
+                started_at: ctx.timestamp,
+            });
+        }
+        (false, Some(indicator)) => {
+            ctx.db.typing_indicator().delete(indicator);
+        }
+        (false, None) => {}
+    }
+}
+
+#[reducer]
+// Scheduled: removes typing indicators that haven't been refreshed recently
+pub fn clear_stale_typing(
+    ctx: &ReducerContext,
+    _schedule: TypingCleanupSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("clear_stale_typing may only be run by the scheduler".to_string());
+    }
+
+    let cutoff = ctx.timestamp - TYPING_TIMEOUT;
+    let stale: Vec<TypingIndicator> = ctx
+        .db
+        .typing_indicator()
+        .iter()
+        .filter(|indicator| indicator.started_at < cutoff)
+        .collect();
+
+    for indicator in stale {
+        ctx.db.typing_indicator().delete(indicator);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Highlights the target's FLASH_STROKE_COUNT most recent strokes for the
+// caller, replacing the caller's previous highlight. The rows expire after
+// HIGHLIGHT_DURATION; the canvas itself is untouched.
+pub fn flash_user_strokes(ctx: &ReducerContext, target: Identity) {
+    let old_rows: Vec<HighlightResult> = ctx
+        .db
+        .highlight_result()
+        .identity()
+        .filter(ctx.sender)
+        .collect();
+    for row in old_rows {
+        ctx.db.highlight_result().delete(row);
+    }
+
+    // Stroke ids are allocated in order, so the highest are the newest
+    let mut stroke_ids: Vec<u64> = ctx
+        .db
+        .stroke_index()
+        .identity()
+        .filter(target)
+        .map(|row| row.stroke_id)
+        .collect();
+    stroke_ids.sort_unstable_by(|a, b| b.cmp(a));
+    let expires_at = ctx.timestamp + HIGHLIGHT_DURATION;
+    for stroke_id in stroke_ids.into_iter().take(FLASH_STROKE_COUNT) {
+        ctx.db.highlight_result().insert(HighlightResult {
+            id: 0, // Will be auto-incremented
+            identity: ctx.sender,
+            target,
+            stroke_id,
+            expires_at,
+        });
+    }
+}
+
+#[reducer]
+// Scheduled: removes stroke highlights that have expired
+pub fn clear_expired_highlights(
+    ctx: &ReducerContext,
+    _schedule: HighlightCleanupSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("clear_expired_highlights may only be run by the scheduler".to_string());
+    }
+
+    let expired: Vec<HighlightResult> = ctx
+        .db
+        .highlight_result()
+        .iter()
+        .filter(|row| row.expires_at <= ctx.timestamp)
+        .collect();
+    for row in expired {
+        ctx.db.highlight_result().delete(row);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Sends a chat message to the room
+pub fn send_chat_message(ctx: &ReducerContext, text: String) -> Result<(), String> {
+    let text = text.trim().to_string();
+    if text.is_empty() || text.chars().count() > MAX_CHAT_MESSAGE_LEN {
+        return Err(format!(
+            "Chat messages must be 1 to {} characters",
+            MAX_CHAT_MESSAGE_LEN
+        ));
+    }
+
+    ctx.db.chat_message().insert(ChatMessage {
+        id: 0, // Will be auto-incremented
+        sender: ctx.sender,
+        text,
+        sent_at: ctx.timestamp,
+    });
+
+    // Sending ends the caller's typing indicator
+    ctx.db.typing_indicator().identity().delete(ctx.sender);
+    Ok(())
+}
+
+#[reducer]
+// Deletes a chat message; allowed for its sender and room admins
+pub fn delete_chat_message(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    let message = ctx
+        .db
+        .chat_message()
+        .id()
+        .find(message_id)
+        .ok_or_else(|| format!("Chat message {} not found", message_id))?;
+    if message.sender != ctx.sender && !is_admin(ctx) {
+        return Err("Only the sender or an admin can delete a message".to_string());
+    }
+
+    delete_chat_message_and_refs(ctx, message);
+    Ok(())
+}
+
+#[reducer]
+// Adds the caller's emoji reaction to a chat message
+pub fn react_to_message(
+    ctx: &ReducerContext,
+    message_id: u64,
+    emoji: String,
+) -> Result<(), String> {
+    if ctx.db.chat_message().id().find(message_id).is_none() {
+        return Err(format!("Chat message {} not found", message_id));
+    }
+    let emoji = emoji.trim().to_string();
+    if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_LEN {
+        return Err("Invalid reaction emoji".to_string());
+    }
+
+    // Reacting twice with the same emoji is a no-op
+    if find_reaction(ctx, message_id, ctx.sender, &emoji).is_none() {
+        ctx.db.message_reaction().insert(MessageReaction {
+            id: 0, // Will be auto-incremented
+            message_id,
+            identity: ctx.sender,
+            emoji,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Removes the caller's emoji reaction from a chat message
+pub fn unreact(ctx: &ReducerContext, message_id: u64, emoji: String) {
+    if let Some(reaction) = find_reaction(ctx, message_id, ctx.sender, emoji.trim()) {
+        ctx.db.message_reaction().delete(reaction);
+    }
+}
+
+#[reducer]
+// Pins a chat message above the chat, replacing any existing pin
+pub fn pin_message(ctx: &ReducerContext, message_id: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can pin messages".to_string());
+    }
+    if ctx.db.chat_message().id().find(message_id).is_none() {
+        return Err(format!("Chat message {} not found", message_id));
+    }
+
+    let pin = PinnedMessage {
+        id: PINNED_MESSAGE_ID,
+        message_id,
+        pinned_by: ctx.sender,
+        pinned_at: ctx.timestamp,
+    };
+    if ctx
+        .db
+        .pinned_message()
+        .id()
+        .find(PINNED_MESSAGE_ID)
+        .is_some()
+    {
+        ctx.db.pinned_message().id().update(pin);
+    } else {
+        ctx.db.pinned_message().insert(pin);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Removes the pinned chat message
+pub fn unpin_message(ctx: &ReducerContext) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can unpin messages".to_string());
+    }
+
+    ctx.db.pinned_message().id().delete(PINNED_MESSAGE_ID);
+    Ok(())
+}
+
+#[reducer]
+// Sets the caller's display name, adding a suffix if another user has it
+pub fn set_display_name(ctx: &ReducerContext, name: String) -> Result<(), String> {
+    let name = normalize_display_name(&name)?;
+    let display_name = disambiguate_display_name(ctx, &name);
+
+    let profile = UserProfile {
+        identity: ctx.sender,
+        name_key: display_name.to_lowercase(),
+        display_name,
+        updated_at: ctx.timestamp,
+        last_seen: ctx.timestamp,
+    };
+    if ctx.db.user_profile().identity().find(ctx.sender).is_some() {
+        ctx.db.user_profile().identity().update(profile);
+    } else {
+        ctx.db.user_profile().insert(profile);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: saves the live canvas as an autosave state if it changed,
+// keeping only the most recent autosaves
+pub fn autosave_canvas(ctx: &ReducerContext, _schedule: AutosaveSchedule) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("autosave_canvas may only be run by the scheduler".to_string());
+    }
+    if !is_room_active(ctx) {
+        return Ok(());
+    }
+
+    let meta = canvas_meta(ctx);
+    if meta.revision == meta.autosaved_revision || meta.point_count == 0 {
+        return Ok(());
+    }
+
+    if let Err(message) = check_state_size(ctx) {
+        log::warn!("Skipped an autosave: {}", message);
+        return Ok(());
+    }
+
+    let index = meta.autosaves_written + 1;
+    save_canvas_points(ctx, autosave_name(ctx, index), String::new(), true);
+    // Saving marked the canvas saved, so re-read the meta row before updating
+    let meta = canvas_meta(ctx);
+    ctx.db.canvas_meta().id().update(CanvasMeta {
+        autosaved_revision: meta.revision,
+        autosaves_written: index,
+        ..meta
+    });
+
+    // Drop the oldest autosaves beyond the retention count
+    let mut autosaves: Vec<CanvasState> = ctx
+        .db
+        .canvas_state()
+        .iter()
+        .filter(|state| state.is_autosave && !state.is_locked)
+        .collect();
+    autosaves.sort_by_key(|state| std::cmp::Reverse(state.created_at));
+    for state in autosaves.into_iter().skip(AUTOSAVES_KEPT) {
+        delete_canvas_state_and_points(ctx, state);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Recomputes every table derived from canvas_point, for recovering from drift
+pub fn rebuild_derived_tables(ctx: &ReducerContext) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can rebuild derived tables".to_string());
+    }
+
+    // canvas_meta: the point count and the next draw order
+    let meta = canvas_meta(ctx);
+    let point_count = ctx.db.canvas_point().count();
+    let next_draw_order = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .map(|point| point.draw_order + 1)
+        .max()
+        .unwrap_or(1)
+        .max(meta.next_draw_order);
+    log::info!(
+        "canvas_meta point_count {} -> {}, next_draw_order {} -> {}",
+        meta.point_count,
+        point_count,
+        meta.next_draw_order,
+        next_draw_order
+    );
+    ctx.db.canvas_meta().id().update(CanvasMeta {
+        point_count,
+        next_draw_order,
+        revision: meta.revision + 1,
+        ..meta
+    });
+
+    // stroke_meta: the number of live points in each stroke
+    let mut stroke_counts: HashMap<u64, u64> = HashMap::new();
+    for point in ctx.db.canvas_point().iter().filter(|p| p.stroke_id != 0) {
+        *stroke_counts.entry(point.stroke_id).or_insert(0) += 1;
+    }
+    for stroke in ctx.db.stroke_meta().iter() {
+        let point_count = stroke_counts.get(&stroke.stroke_id).copied().unwrap_or(0);
+        if point_count != stroke.point_count {
+            log::info!(
+                "stroke_meta {} point_count {} -> {}",
+                stroke.stroke_id,
+                stroke.point_count,
+                point_count
+            );
+            ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+                point_count,
+                ..stroke
+            });
+        }
+    }
+
+    // stroke_index: rebuilt for every stroke that has points
+    let reindexed = rebuild_stroke_index_rows(ctx);
+    log::info!("stroke_index rebuilt for {} strokes", reindexed);
+
+    // canvas_bounds: rescanned from every point
+    let bounds = recompute_canvas_bounds_row(ctx);
+    log::info!(
+        "canvas_bounds rescanned: ({}, {}) to ({}, {})",
+        bounds.min_x,
+        bounds.min_y,
+        bounds.max_x,
+        bounds.max_y
+    );
+
+    // color_histogram: rebuilt from scratch
+    let before = ctx.db.color_histogram().count();
+    rebuild_color_histogram(ctx);
+    log::info!(
+        "color_histogram rows {} -> {}",
+        before,
+        ctx.db.color_histogram().count()
+    );
+    Ok(())
+}
+
+#[reducer]
+// Rescans the canvas bounds from canvas_point, for correcting a stale or
+// drifted extent
+pub fn recompute_canvas_bounds(ctx: &ReducerContext) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can recompute the canvas bounds".to_string());
+    }
+
+    let before = canvas_bounds(ctx);
+    let after = recompute_canvas_bounds_row(ctx);
+    log::info!(
+        "User {} recomputed canvas_bounds: ({}, {}) to ({}, {}), was ({}, {}) to ({}, {})",
+        ctx.sender,
+        after.min_x,
+        after.min_y,
+        after.max_x,
+        after.max_y,
+        before.min_x,
+        before.min_y,
+        before.max_x,
+        before.max_y
+    );
+    Ok(())
+}
+
+#[reducer]
+// Recomputes every stroke's bounding box and point count in stroke_index, for
+// recovering from missed incremental updates
+pub fn rebuild_stroke_index(ctx: &ReducerContext) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can rebuild the stroke index".to_string());
+    }
+
+    let before = ctx.db.stroke_index().count();
+    let reindexed = rebuild_stroke_index_rows(ctx);
+    log::info!(
+        "User {} rebuilt stroke_index: {} strokes reindexed, {} rows before",
+        ctx.sender,
+        reindexed,
+        before
+    );
+    Ok(())
+}
+
+#[reducer]
+// Creates a new layer on top of the layer stack
+pub fn create_layer(ctx: &ReducerContext, name: String) -> Result<(), String> {
+    let name = validate_layer_name(&name)?;
+
+    let z_order = ctx.db.layer().iter().map(|l| l.z_order).max().unwrap_or(0) + 1;
+    ctx.db.layer().insert(Layer {
+        id: 0, // Will be auto-incremented
+        name,
+        z_order,
+        locked: false,
+        hidden: false,
+        created_by: ctx.sender,
+    });
+    Ok(())
+}
+
+#[reducer]
+// Chooses the layer the caller's new drawing lands on
+pub fn set_active_layer(ctx: &ReducerContext, layer_id: u64) -> Result<(), String> {
+    if layer_z_order(ctx, layer_id).is_none() {
+        return Err(format!("Layer {} not found", layer_id));
+    }
+
+    ctx.db.user_active_layer().identity().delete(ctx.sender);
+    if layer_id != BASE_LAYER_ID {
+        ctx.db.user_active_layer().insert(UserActiveLayer {
+            identity: ctx.sender,
+            layer_id,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Locks or unlocks a layer against erasing
+pub fn set_layer_locked(ctx: &ReducerContext, layer_id: u64, locked: bool) -> Result<(), String> {
+    let layer = owned_layer(ctx, layer_id)?;
+    ctx.db.layer().id().update(Layer { locked, ..layer });
+    Ok(())
+}
+
+#[reducer]
+// Hides or shows a layer
+pub fn set_layer_hidden(ctx: &ReducerContext, layer_id: u64, hidden: bool) -> Result<(), String> {
+    let layer = owned_layer(ctx, layer_id)?;
+    ctx.db.layer().id().update(Layer { hidden, ..layer });
+    Ok(())
+}
+
+#[reducer]
+// Chooses whether the eraser reaches points on hidden layers
+pub fn set_erase_hidden_layers(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        erase_hidden_layers: enabled,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Chooses whether the eraser reaches strokes hidden with set_stroke_visibility
+pub fn set_erase_hidden_strokes(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        erase_hidden_strokes: enabled,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Chooses whether the eraser removes touched points or whole touched strokes
+pub fn set_erase_mode(ctx: &ReducerContext, mode: String) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if mode != ERASE_MODE_PIXEL && mode != ERASE_MODE_STROKE {
+        return Err(format!(
+            "Unknown erase mode {:?}, expected {:?} or {:?}",
+            mode, ERASE_MODE_PIXEL, ERASE_MODE_STROKE
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        erase_mode: mode,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Chooses whether erasers centered off the canvas are refused, matching how
+// drawing is kept on the canvas
+pub fn set_bound_erasers(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        bound_erasers: enabled,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Chooses whether stroke mode erasing waits for confirm_stroke_erase before
+// removing whole strokes
+pub fn set_confirm_stroke_erase(ctx: &ReducerContext, enabled: bool) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        confirm_stroke_erase: enabled,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets the saved state a never-drawn-on canvas starts from, or None for an
+// empty start
+pub fn set_room_template(ctx: &ReducerContext, state_id: Option<u64>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if let Some(state_id) = state_id {
+        if ctx.db.canvas_state().id().find(state_id).is_none() {
+            return Err(format!("Canvas state {} not found", state_id));
+        }
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        room_template_state_id: state_id,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Turns symmetry drawing on with the given number of mirror axes through
+// (center_x, center_y), or off when `axes` is 0
+pub fn set_symmetry(
+    ctx: &ReducerContext,
+    axes: u32,
+    center_x: f32,
+    center_y: f32,
+) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if axes > MAX_SYMMETRY_AXES {
+        return Err(format!("Symmetry is limited to {} axes", MAX_SYMMETRY_AXES));
+    }
+    if !(0.0..=CANVAS_WIDTH as f32).contains(&center_x)
+        || !(0.0..=CANVAS_HEIGHT as f32).contains(&center_y)
+    {
+        return Err(format!(
+            "Symmetry center ({}, {}) is outside the canvas",
+            center_x, center_y
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        symmetry_axes: axes,
+        symmetry_center_x: center_x,
+        symmetry_center_y: center_y,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets the largest brush radius this room allows for drawing and cursors
+pub fn set_max_brush_size(ctx: &ReducerContext, size: f32) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    validate_size(size)?;
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        max_brush_size: size,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets how autosaves are named, e.g. "autosave-{index}"; the template must
+// use at least one of AUTOSAVE_PLACEHOLDERS
+pub fn set_autosave_name_template(ctx: &ReducerContext, template: String) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    let template = template.trim().to_string();
+    if !AUTOSAVE_PLACEHOLDERS.iter().any(|p| template.contains(p)) {
+        return Err(format!(
+            "Autosave name templates must contain one of {}",
+            AUTOSAVE_PLACEHOLDERS.join(", ")
+        ));
+    }
+    if template.chars().count() > MAX_STATE_NAME_LEN {
+        return Err(format!(
+            "Autosave name templates are limited to {} characters",
+            MAX_STATE_NAME_LEN
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        autosave_name_template: template,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Makes saves wait until nothing has been drawn for `millis` milliseconds
+// (they report "busy, retry" until then), or turns the wait off with 0
+pub fn set_save_quiet_window(ctx: &ReducerContext, millis: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        save_quiet_window_ms: millis,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Caps how many users can draw at once, or removes the cap with None. Users
+// joining a full room become spectators; admins always get in.
+pub fn set_max_participants(ctx: &ReducerContext, limit: Option<u32>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if limit == Some(0) {
+        return Err("The participant limit must be positive".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        max_participants: limit,
+        ..settings
+    });
+
+    // A raised or removed limit lets waiting spectators in
+    while ctx.db.spectator().count() > 0 {
+        let before = ctx.db.spectator().count();
+        promote_spectator(ctx);
+        if ctx.db.spectator().count() == before {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+// Sets how many client pixels make one canvas unit. Stored coordinates are
+// left as they are; only how clients map pixels to them changes.
+pub fn set_coordinate_scale(ctx: &ReducerContext, scale: f32) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if !(MIN_COORDINATE_SCALE..=MAX_COORDINATE_SCALE).contains(&scale) {
+        return Err(format!(
+            "Coordinate scale must be between {} and {}",
+            MIN_COORDINATE_SCALE, MAX_COORDINATE_SCALE
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        coordinate_scale: scale,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Chooses which drawing tools non-admins may use, by name from TOOLS. An
+// empty list leaves drawing to admins.
+pub fn set_allowed_tools(ctx: &ReducerContext, tools: Vec<String>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    let mut allowed: Vec<String> = Vec::new();
+    for tool in tools {
+        if !TOOLS.contains(&tool.as_str()) {
+            return Err(format!(
+                "Unknown tool {:?}, expected one of {}",
+                tool,
+                TOOLS.join(", ")
+            ));
+        }
+        if !allowed.contains(&tool) {
+            allowed.push(tool);
+        }
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        allowed_tools: allowed,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets how many standard deviations from the centroid a point may stray
+// before exports with `trim_outliers` leave it out
+pub fn set_outlier_threshold(ctx: &ReducerContext, threshold: f32) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if !threshold.is_finite()
+        || !(MIN_OUTLIER_THRESHOLD..=MAX_OUTLIER_THRESHOLD).contains(&threshold)
+    {
+        return Err(format!(
+            "Outlier threshold must be between {} and {}",
+            MIN_OUTLIER_THRESHOLD, MAX_OUTLIER_THRESHOLD
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        outlier_threshold: threshold,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets the least distance a freehand stroke point must be from the stroke's
+// previous point to be kept, or 0 to keep every point
+pub fn set_min_point_spacing(ctx: &ReducerContext, spacing: f32) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if !spacing.is_finite() || !(0.0..=MAX_POINT_SPACING).contains(&spacing) {
+        return Err(format!(
+            "Point spacing must be between 0 and {}",
+            MAX_POINT_SPACING
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        min_point_spacing: spacing,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Rounds the positions of newly drawn points to `places` decimal places, or
+// keeps full precision with None
+pub fn set_coordinate_precision(ctx: &ReducerContext, places: Option<u8>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if places.is_some_and(|places| places > MAX_COORDINATE_PRECISION) {
+        return Err(format!(
+            "Coordinate precision is limited to {} decimal places",
+            MAX_COORDINATE_PRECISION
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        coordinate_precision: places,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets the snapping grid spacing and whether straight segments and freehand
+// drawing snap to it; shapes usually snap while sketching stays smooth
+pub fn set_grid_snap(
+    ctx: &ReducerContext,
+    grid_size: f32,
+    snap_shapes: bool,
+    snap_freehand: bool,
+) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if !(MIN_SNAP_GRID_SIZE..=MAX_SNAP_GRID_SIZE).contains(&grid_size) {
+        return Err(format!(
+            "Grid size must be between {} and {}",
+            MIN_SNAP_GRID_SIZE, MAX_SNAP_GRID_SIZE
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        snap_grid_size: grid_size,
+        snap_shapes,
+        snap_freehand,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets the brush new cursors start with. Without a color each user still gets
+// a distinct palette color.
+pub fn set_default_brush(
+    ctx: &ReducerContext,
+    color: Option<String>,
+    size: f32,
+) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if let Some(color) = &color {
+        validate_color(color)?;
+    }
+    validate_size(size)?;
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        default_brush_color: color,
+        default_brush_size: size,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Deletes the points on one layer. With `own_only` anyone may clear just
+// their own points; clearing everyone's needs the layer's creator or an admin.
+pub fn clear_layer(ctx: &ReducerContext, layer_id: u64, own_only: bool) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if !own_only {
+        if layer_id == BASE_LAYER_ID {
+            if !is_admin(ctx) {
+                return Err("Only room admins can clear the base layer".to_string());
+            }
+        } else {
+            owned_layer(ctx, layer_id)?;
+        }
+    } else if layer_id != BASE_LAYER_ID && ctx.db.layer().id().find(layer_id).is_none() {
+        return Err(format!("Layer {} not found", layer_id));
+    }
+    // Locked layers can't be erased, and clearing is erasing
+    if ctx
+        .db
+        .layer()
+        .id()
+        .find(layer_id)
+        .is_some_and(|layer| layer.locked)
+    {
+        return Err(format!("Layer {} is locked", layer_id));
+    }
+
+    let points_to_clear: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| point.layer_id == layer_id && (!own_only || point.identity == ctx.sender))
+        .collect();
+
+    let cleared = points_to_clear.len();
+    let mut batch = PointBatch::new(ctx);
+    for point in points_to_clear {
+        batch.delete(point);
+    }
+    batch.finish();
+
+    log::info!(
+        "User {} cleared {} points from layer {}",
+        ctx.sender,
+        cleared,
+        layer_id
+    );
+    Ok(())
+}
+
+#[reducer]
+// Copies a layer and all of its points into a new layer just above it
+pub fn duplicate_layer(
+    ctx: &ReducerContext,
+    layer_id: u64,
+    new_name: String,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let name = validate_layer_name(&new_name)?;
+    let source_z =
+        layer_z_order(ctx, layer_id).ok_or_else(|| format!("Layer {} not found", layer_id))?;
+
+    // Make room directly above the source layer
+    let layers_above: Vec<Layer> = ctx
+        .db
+        .layer()
+        .iter()
+        .filter(|layer| layer.z_order > source_z)
+        .collect();
+    for layer in layers_above {
+        ctx.db.layer().id().update(Layer {
+            z_order: layer.z_order + 1,
+            ..layer
+        });
+    }
+
+    let copy = ctx.db.layer().insert(Layer {
+        id: 0, // Will be auto-incremented
+        name,
+        z_order: source_z + 1,
+        locked: false,
+        hidden: false,
+        created_by: ctx.sender,
+    });
+
+    // Copy the points in paint order; the copies are loose points of the caller's
+    let mut points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| point.layer_id == layer_id)
+        .collect();
+    points.sort_by_key(|point| point.draw_order);
+    for point in points {
+        insert_canvas_point(
+            ctx,
+            CanvasPoint {
+                id: 0, // Will be auto-incremented
+                identity: ctx.sender,
+                stroke_id: 0,
+                layer_id: copy.id,
+                timestamp: ctx.timestamp,
+                ..point
+            },
+        );
+    }
+    Ok(())
+}
+
+#[reducer]
+// Moves a layer's points onto the layer below it and deletes the layer
+pub fn merge_layer_down(ctx: &ReducerContext, layer_id: u64) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if layer_id == BASE_LAYER_ID {
+        return Err("The base layer has no layer below it".to_string());
+    }
+    let layer = owned_layer(ctx, layer_id)?;
+
+    // The nearest layer below, falling back to the base layer
+    let target_id = ctx
+        .db
+        .layer()
+        .iter()
+        .filter(|other| other.z_order < layer.z_order)
+        .max_by_key(|other| other.z_order)
+        .map(|other| other.id)
+        .unwrap_or(BASE_LAYER_ID);
+
+    let points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| point.layer_id == layer_id)
+        .collect();
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        batch.update(CanvasPoint {
+            layer_id: target_id,
+            ..point
+        });
+    }
+    batch.finish();
+
+    ctx.db.layer().delete(layer);
+    compact_layer_z_orders(ctx);
+    Ok(())
+}
+
+#[reducer]
+// Moves some of the caller's points onto another layer. Ids of points that
+// are missing or belong to someone else are skipped.
+pub fn move_points_to_layer(
+    ctx: &ReducerContext,
+    point_ids: Vec<u64>,
+    layer_id: u64,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if layer_z_order(ctx, layer_id).is_none() {
+        return Err(format!("Layer {} not found", layer_id));
+    }
+
+    let selected = point_ids
+        .into_iter()
+        .filter_map(|point_id| ctx.db.canvas_point().id().find(point_id));
+    let points = points_moved_to_layer(selected, ctx.sender, layer_id);
+    let moved = points.len();
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        batch.update(point);
+    }
+    batch.finish();
+
+    log::info!(
+        "User {} moved {} points to layer {}",
+        ctx.sender,
+        moved,
+        layer_id
+    );
+    Ok(())
+}
+
+// The selected points move_points_to_layer rewrites, already on their new
+// layer: the mover's own points not on it yet, each once however often it
+// was selected
+fn points_moved_to_layer(
+    selected: impl IntoIterator<Item = CanvasPoint>,
+    mover: Identity,
+    layer_id: u64,
+) -> Vec<CanvasPoint> {
+    let mut seen = HashSet::new();
+    selected
+        .into_iter()
+        .filter(|point| point.identity == mover && point.layer_id != layer_id)
+        .filter(|point| seen.insert(point.id))
+        .map(|point| CanvasPoint { layer_id, ..point })
+        .collect()
+}
+
+#[reducer]
+// Makes the caller the owner of the given points, for fixing attribution
+// after a load handed everything to the loader. Rewriting authorship needs a
+// room admin. Ids of points that don't exist are skipped.
+pub fn claim_points(ctx: &ReducerContext, point_ids: Vec<u64>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can reassign points".to_string());
+    }
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if point_ids.len() > MAX_SELECTION_POINTS {
+        return Err(format!(
+            "Can't claim more than {} points at once",
+            MAX_SELECTION_POINTS
+        ));
+    }
+
+    let mut claimed = 0;
+    let mut batch = PointBatch::new(ctx);
+    for point_id in point_ids {
+        let Some(point) = ctx.db.canvas_point().id().find(point_id) else {
+            continue;
+        };
+        if point.identity == ctx.sender {
+            continue;
+        }
+        batch.update(CanvasPoint {
+            identity: ctx.sender,
+            ..point
+        });
+        claimed += 1;
+    }
+    batch.finish();
+
+    log::info!("User {} claimed {} points", ctx.sender, claimed);
+    audit(ctx, "claim_points", format!("Claimed {} points", claimed));
+    Ok(())
+}
+
+#[reducer]
+// Locks or unlocks a saved state; allowed for its creator and room admins
+pub fn set_state_lock(ctx: &ReducerContext, state_id: u64, locked: bool) -> Result<(), String> {
+    let state = ctx
+        .db
+        .canvas_state()
+        .id()
+        .find(state_id)
+        .ok_or_else(|| format!("Canvas state {} not found", state_id))?;
+    if state.created_by != ctx.sender && !is_admin(ctx) {
+        return Err("Only the state's creator or an admin can lock it".to_string());
+    }
+
+    ctx.db.canvas_state().id().update(CanvasState {
+        is_locked: locked,
+        ..state
+    });
+    Ok(())
+}
+
+#[reducer]
+// Makes an old save safe to load: points with non-finite coordinates are
+// dropped, and invalid colors, sizes, opacities and blend modes are replaced
+// or clamped. How many points were fixed and dropped is reported through
+// action_result.
+pub fn repair_canvas_state(ctx: &ReducerContext, state_id: u64) {
+    let outcome = editable_state(ctx, state_id).map(|state| {
+        let points = saved_points_in_order(ctx, state_id);
+        let (mut fixed, mut dropped) = (0u64, 0u64);
+        for point in points {
+            match repair_saved_point(&point) {
+                SavedPointRepair::Intact => {}
+                SavedPointRepair::Fixed(repaired) => {
+                    ctx.db.saved_canvas_point().id().update(repaired);
+                    fixed += 1;
+                }
+                SavedPointRepair::Dropped => {
+                    ctx.db.saved_canvas_point().delete(point);
+                    dropped += 1;
+                }
+            }
+        }
+
+        if fixed + dropped > 0 {
+            let state = ctx.db.canvas_state().id().update(CanvasState {
+                point_count: state.point_count.saturating_sub(dropped),
+                ..state
+            });
+            write_state_thumbnail(ctx, state.id);
+            write_state_content_hash(ctx, state);
+        }
+        log::info!(
+            "User {} repaired canvas state {}: {} points fixed, {} dropped",
+            ctx.sender,
+            state_id,
+            fixed,
+            dropped
+        );
+        format!(
+            "Repaired canvas state {}: {} points fixed, {} dropped",
+            state_id, fixed, dropped
+        )
+    });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "repair_canvas_state", true, message),
+        Err(message) => write_result(ctx, "repair_canvas_state", false, message),
+    }
+}
+
+// What repair_canvas_state does with one saved point
+enum SavedPointRepair {
+    Intact,                  // Already safe to load
+    Fixed(SavedCanvasPoint), // Rewritten with its bad fields normalized
+    Dropped,                 // Has no usable position and is removed
+}
+
+// Normalizes a saved point's color, size, opacity and blend mode, or drops it
+// when its position isn't finite
+fn repair_saved_point(point: &SavedCanvasPoint) -> SavedPointRepair {
+    if !point.x.is_finite() || !point.y.is_finite() {
+        return SavedPointRepair::Dropped;
+    }
+    let repaired = SavedCanvasPoint {
+        id: point.id,
+        state_id: point.state_id,
+        x: point.x,
+        y: point.y,
+        color: if validate_color(&point.color).is_ok() {
+            point.color.clone()
+        } else {
+            REPAIR_FALLBACK_COLOR.to_string()
+        },
+        size: if point.size.is_finite() {
+            point.size.clamp(MIN_BRUSH_SIZE, MAX_BRUSH_SIZE)
+        } else {
+            DEFAULT_BRUSH_SIZE
+        },
+        opacity: if point.opacity.is_finite() {
+            point.opacity.clamp(0.0, 1.0)
+        } else {
+            1.0
+        },
+        origin: point.origin.clone(),
+        blend_mode: validate_blend_mode(&point.blend_mode)
+            .unwrap_or_else(|_| BLEND_MODE_NORMAL.to_string()),
+        drawn_at: point.drawn_at,
+    };
+    let changed = repaired.color != point.color
+        || repaired.size != point.size
+        || repaired.opacity != point.opacity
+        || repaired.blend_mode != point.blend_mode;
+    if changed {
+        SavedPointRepair::Fixed(repaired)
+    } else {
+        SavedPointRepair::Intact
+    }
+}
+
+#[reducer]
+// Renames a saved state unless it is locked
+pub fn rename_canvas_state(ctx: &ReducerContext, state_id: u64, name: String) {
+    let outcome = editable_state(ctx, state_id).and_then(|state| {
+        let name = validate_state_name(&name)?;
+        ctx.db
+            .canvas_state()
+            .id()
+            .update(CanvasState { name, ..state });
+        Ok(format!("Renamed canvas state {}", state_id))
+    });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "rename_canvas_state", true, message),
+        Err(message) => write_result(ctx, "rename_canvas_state", false, message),
+    }
+}
+
+#[reducer]
+// Sets the notes shown in a saved state's details; an empty description
+// removes them
+pub fn set_state_description(ctx: &ReducerContext, state_id: u64, description: String) {
+    let outcome = editable_state(ctx, state_id).and_then(|state| {
+        let description = validate_state_description(&description)?;
+        ctx.db.canvas_state().id().update(CanvasState {
+            description,
+            ..state
+        });
+        Ok(format!(
+            "Updated the description of canvas state {}",
+            state_id
+        ))
+    });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "set_state_description", true, message),
+        Err(message) => write_result(ctx, "set_state_description", false, message),
+    }
+}
+
+#[reducer]
+// Replaces a saved state's points with the live canvas unless it is locked
+pub fn overwrite_canvas_state(ctx: &ReducerContext, state_id: u64) {
+    let outcome = editable_state(ctx, state_id).and_then(|state| {
+        check_state_size(ctx)?;
+        let old_points: Vec<SavedCanvasPoint> = ctx
+            .db
+            .saved_canvas_point()
+            .state_id()
+            .filter(state.id)
+            .collect();
+        for point in old_points {
+            ctx.db.saved_canvas_point().delete(point);
+        }
+
+        let point_count = write_saved_points(ctx, state.id);
+        write_state_thumbnail(ctx, state.id);
+        let state = ctx.db.canvas_state().id().update(CanvasState {
+            point_count: point_count as u64,
+            history_seq: latest_history_seq(ctx),
+            ..state
+        });
+        write_state_content_hash(ctx, state);
+        Ok(format!(
+            "Overwrote canvas state {} with {} points",
+            state_id, point_count
+        ))
+    });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "overwrite_canvas_state", true, message),
+        Err(message) => write_result(ctx, "overwrite_canvas_state", false, message),
+    }
+}
+
+#[reducer]
+// Sets how long an offline identity is kept before its per-user rows are removed
+pub fn set_guest_expiry(ctx: &ReducerContext, seconds: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if seconds == 0 {
+        return Err("Guest expiry must be at least one second".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        guest_expiry_seconds: seconds,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets how long the room may go without drawing, strokes or erasing before
+// it is archived, or 0 to never archive it
+pub fn set_archive_after(ctx: &ReducerContext, seconds: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        archive_after_seconds: seconds,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Sets how long non-members must wait after joining before they can draw, or
+// 0 to let them draw at once
+pub fn set_join_cooldown(ctx: &ReducerContext, seconds: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        join_cooldown_seconds: seconds,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: archives the room once room_analytics.last_activity is older
+// than archive_after_seconds. Archiving only hides the room from the lobby.
+pub fn archive_inactive_room(
+    ctx: &ReducerContext,
+    _schedule: ArchiveCheckSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("archive_inactive_room may only be run by the scheduler".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    if settings.archived || settings.archive_after_seconds == 0 {
+        return Ok(());
+    }
+    let cutoff = ctx.timestamp - Duration::from_secs(settings.archive_after_seconds);
+    let last_activity = room_analytics(ctx).last_activity;
+    if last_activity < cutoff {
+        ctx.db.canvas_settings().id().update(CanvasSettings {
+            archived: true,
+            ..settings
+        });
+        log::info!("Archived the room, inactive since {:?}", last_activity);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Brings an archived room back to the lobby. Its activity clock restarts so
+// the next check doesn't archive it again straight away.
+pub fn unarchive_room(ctx: &ReducerContext) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can unarchive the room".to_string());
+    }
+    let settings = canvas_settings(ctx);
+    if !settings.archived {
+        return Err("The room isn't archived".to_string());
+    }
+
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        archived: false,
+        ..settings
+    });
+    record_room_activity(ctx, 0, 0);
+    audit(ctx, "unarchive_room", "Unarchived the room".to_string());
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: removes per-user rows of identities that are offline and have
+// been inactive past the guest expiry. Identities that own saved states keep
+// their rows so those states still resolve to a profile.
+pub fn cleanup_expired_guests(
+    ctx: &ReducerContext,
+    _schedule: GuestCleanupSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("cleanup_expired_guests may only be run by the scheduler".to_string());
+    }
+    if !is_room_active(ctx) {
+        return Ok(());
+    }
+
+    let expiry = Duration::from_secs(canvas_settings(ctx).guest_expiry_seconds);
+    let cutoff = ctx.timestamp - expiry;
+    let state_owners: HashSet<Identity> = ctx
+        .db
+        .canvas_state()
+        .iter()
+        .map(|state| state.created_by)
+        .collect();
+    let expired = |identity: &Identity, last_active: Timestamp| {
+        last_active < cutoff
+            && !state_owners.contains(identity)
+            && ctx.db.cursor().identity().find(*identity).is_none()
+    };
+
+    let profiles: Vec<UserProfile> = ctx
+        .db
+        .user_profile()
+        .iter()
+        .filter(|profile| expired(&profile.identity, profile.last_seen))
+        .collect();
+    let removed = profiles.len();
+    for profile in profiles {
+        clear_erase_preview_for(ctx, profile.identity);
+        ctx.db.user_stats().identity().delete(profile.identity);
+        ctx.db
+            .user_active_layer()
+            .identity()
+            .delete(profile.identity);
+        ctx.db.stroke_alloc().identity().delete(profile.identity);
+        ctx.db.active_stroke().identity().delete(profile.identity);
+        ctx.db.room_member().identity().delete(profile.identity);
+        ctx.db.session_state().identity().delete(profile.identity);
+        ctx.db.size_preset().identity().delete(profile.identity);
+        ctx.db
+            .typing_indicator()
+            .identity()
+            .delete(profile.identity);
+        ctx.db.user_profile().delete(profile);
+    }
+
+    // Result rows are written on request, so they age by their own timestamp
+    let exports: Vec<ExportResult> = ctx
+        .db
+        .export_result()
+        .iter()
+        .filter(|row| expired(&row.identity, row.created_at))
+        .collect();
+    for row in exports {
+        ctx.db.export_result().delete(row);
+    }
+    let results: Vec<ActionResult> = ctx
+        .db
+        .action_result()
+        .iter()
+        .filter(|row| expired(&row.identity, row.created_at))
+        .collect();
+    for row in results {
+        ctx.db.action_result().delete(row);
+    }
+
+    log::info!("Removed {} expired guest profiles", removed);
+    Ok(())
+}
+
+#[reducer]
+// Resets one user's stats, or everyone's when no identity is given
+pub fn reset_user_stats(ctx: &ReducerContext, identity: Option<Identity>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can reset user stats".to_string());
+    }
+
+    match identity {
+        Some(identity) => {
+            ctx.db.user_stats().identity().delete(identity);
+        }
+        None => {
+            let all: Vec<UserStats> = ctx.db.user_stats().iter().collect();
+            for stats in all {
+                ctx.db.user_stats().delete(stats);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[reducer]
+// Recomputes every user's ink_used from the strokes on the canvas: the
+// distance between consecutive points of each stroke, times their brush size.
+// Loose points and mirrored copies don't count.
+pub fn recompute_ink_usage(ctx: &ReducerContext) {
+    let mut strokes: HashMap<u64, Vec<CanvasPoint>> = HashMap::new();
+    for point in ctx.db.canvas_point().iter() {
+        if point.stroke_id != 0 && point.mirror_of == 0 {
+            strokes.entry(point.stroke_id).or_default().push(point);
+        }
+    }
+
+    let mut ink: HashMap<Identity, f64> = HashMap::new();
+    for mut points in strokes.into_values() {
+        points.sort_by_key(|point| point.draw_order);
+        for pair in points.windows(2) {
+            let (from, to) = (&pair[0], &pair[1]);
+            let distance = ((to.x - from.x).powi(2) + (to.y - from.y).powi(2)).sqrt();
+            let size = (from.size + to.size) / 2.0;
+            *ink.entry(to.identity).or_default() += (distance * size) as f64;
+        }
+    }
+
+    // Users whose strokes are gone drop back to zero
+    let existing: Vec<Identity> = ctx.db.user_stats().iter().map(|s| s.identity).collect();
+    for identity in existing {
+        ink.entry(identity).or_default();
+    }
+    for (identity, ink_used) in ink {
+        change_user_stats(ctx, identity, |stats| stats.ink_used = ink_used);
+    }
+}
+
+#[reducer]
+// Scheduled: recounts room_analytics.active_users from recent cursor activity
+pub fn recompute_active_users(
+    ctx: &ReducerContext,
+    _schedule: AnalyticsSchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("recompute_active_users may only be run by the scheduler".to_string());
+    }
+    // An idle room has nobody to count once it has been counted down to zero
+    if !is_room_active(ctx) && room_analytics(ctx).active_users == 0 {
+        return Ok(());
+    }
+
+    let cutoff = ctx.timestamp - ACTIVE_USER_WINDOW;
+    let active_users = ctx
+        .db
+        .cursor()
+        .iter()
+        .filter(|cursor| cursor.last_updated >= cutoff)
+        .count() as u32;
+
+    let analytics = room_analytics(ctx);
+    ctx.db.room_analytics().id().update(RoomAnalytics {
+        active_users,
+        ..analytics
+    });
+    Ok(())
+}
+
+#[reducer]
+// Restores the points removed by the most recent clear_canvas. Anything drawn
+// since the clear is kept; the restored points are added alongside it.
+pub fn undo_clear(ctx: &ReducerContext) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let mut backup: Vec<ClearBackup> = ctx.db.clear_backup().iter().collect();
+    let cleared_by = backup
+        .first()
+        .map(|row| row.cleared_by)
+        .ok_or("There is no clear to undo")?;
+    if cleared_by != ctx.sender && !is_admin(ctx) {
+        return Err("Only whoever cleared the canvas or an admin can undo it".to_string());
+    }
+
+    // Strokes first, so the restored points can rejoin them. A stroke left
+    // open across the clear kept its row and gets its old points counted back.
+    let strokes: Vec<ClearStrokeBackup> = ctx.db.clear_stroke_backup().iter().collect();
+    for stroke in &strokes {
+        match ctx.db.stroke_meta().stroke_id().find(stroke.stroke_id) {
+            Some(meta) => {
+                ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+                    point_count: meta.point_count + stroke.point_count,
+                    ..meta
+                });
+            }
+            None => {
+                ctx.db.stroke_meta().insert(StrokeMeta {
+                    stroke_id: stroke.stroke_id,
+                    identity: stroke.identity,
+                    started_at: stroke.started_at,
+                    ended_at: stroke.ended_at,
+                    point_count: stroke.point_count,
+                });
+            }
+        }
+    }
+
+    backup.sort_by_key(|row| row.draw_order);
+    let restored = backup.len();
+    for row in backup {
+        reinsert_point(
+            ctx,
+            CanvasPoint {
+                identity: row.identity,
+                opacity: row.opacity,
+                stroke_id: row.stroke_id,
+                layer_id: row.layer_id,
+                origin: row.origin.clone(),
+                blend_mode: row.blend_mode.clone(),
+                ..new_canvas_point(ctx, row.x, row.y, row.color.clone(), row.size)
+            },
+        );
+        ctx.db.clear_backup().delete(row);
+    }
+
+    // Labels and visibility last: they only last while a stroke has points
+    for stroke in strokes {
+        if let Some(label) = stroke.label.clone() {
+            if ctx
+                .db
+                .stroke_label()
+                .stroke_id()
+                .find(stroke.stroke_id)
+                .is_none()
+            {
+                ctx.db.stroke_label().insert(StrokeLabel {
+                    stroke_id: stroke.stroke_id,
+                    label,
+                });
+            }
+        }
+        if stroke.hidden
+            && ctx
+                .db
+                .stroke_visibility()
+                .stroke_id()
+                .find(stroke.stroke_id)
+                .is_none()
+        {
+            ctx.db.stroke_visibility().insert(StrokeVisibility {
+                stroke_id: stroke.stroke_id,
+                hidden: true,
+            });
+        }
+        ctx.db.clear_stroke_backup().delete(stroke);
+    }
+
+    log::info!("User {} restored {} cleared points", ctx.sender, restored);
+    Ok(())
+}
+
+#[reducer]
+// Mirrors one of the caller's strokes across the center of its bounding box,
+// left-to-right when `horizontal` is set and top-to-bottom otherwise
+pub fn flip_stroke(ctx: &ReducerContext, stroke_id: u64, horizontal: bool) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let points = owned_stroke_points(ctx, stroke_id)?;
+    let Some(bounds) = Bounds::of_circles(points.iter().map(|p| (p.x, p.y, p.size))) else {
+        return Ok(());
+    };
+    let center = bounds.center();
+
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        batch.update(flip_point(point, center, horizontal));
+    }
+    batch.finish();
+    Ok(())
+}
+
+// Mirrors a point across the vertical (when `horizontal`) or horizontal axis
+// through `center`, leaving everything but its position alone
+fn flip_point(point: CanvasPoint, center: (f32, f32), horizontal: bool) -> CanvasPoint {
+    let (center_x, center_y) = center;
+    if horizontal {
+        CanvasPoint {
+            x: 2.0 * center_x - point.x,
+            ..point
+        }
+    } else {
+        CanvasPoint {
+            y: 2.0 * center_y - point.y,
+            ..point
+        }
+    }
+}
+
+#[reducer]
+// Rotates one of the caller's strokes around its centroid; positive angles
+// turn clockwise on screen, as the canvas y axis points down
+pub fn rotate_stroke(ctx: &ReducerContext, stroke_id: u64, degrees: f32) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if !degrees.is_finite() {
+        return Err(format!("Invalid rotation angle {}", degrees));
+    }
+    let points = owned_stroke_points(ctx, stroke_id)?;
+    if points.is_empty() {
+        return Ok(());
+    }
+
+    let count = points.len() as f32;
+    let center_x = points.iter().map(|p| p.x).sum::<f32>() / count;
+    let center_y = points.iter().map(|p| p.y).sum::<f32>() / count;
+
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        let (x, y) = rotate_around((point.x, point.y), (center_x, center_y), degrees);
+        batch.update(CanvasPoint { x, y, ..point });
+    }
+    batch.finish();
+    Ok(())
+}
+
+// Rotates a position around `center` by `degrees`, clockwise on screen
+fn rotate_around(position: (f32, f32), center: (f32, f32), degrees: f32) -> (f32, f32) {
+    let (sin, cos) = degrees.rem_euclid(360.0).to_radians().sin_cos();
+    let dx = position.0 - center.0;
+    let dy = position.1 - center.1;
+    (
+        center.0 + dx * cos - dy * sin,
+        center.1 + dx * sin + dy * cos,
+    )
+}
+
+#[reducer]
+// Puts back the points removed by the caller's most recent erase
+pub fn undo_last_erase(ctx: &ReducerContext) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let mut stash: Vec<EraseUndo> = ctx.db.erase_undo().erased_by().filter(ctx.sender).collect();
+    if stash.is_empty() {
+        return Err("There is no erase to undo".to_string());
+    }
+
+    stash.sort_by_key(|row| row.draw_order);
+    for row in stash {
+        reinsert_point(
+            ctx,
+            CanvasPoint {
+                identity: row.identity,
+                opacity: row.opacity,
+                stroke_id: row.stroke_id,
+                layer_id: row.layer_id,
+                origin: row.origin.clone(),
+                blend_mode: row.blend_mode.clone(),
+                ..new_canvas_point(ctx, row.x, row.y, row.color.clone(), row.size)
+            },
+        );
+        ctx.db.erase_undo().delete(row);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Rotates the hue of points' colors by `degrees`, keeping their saturation
+// and lightness. With `own_only` anyone may recolor their own points;
+// recoloring everyone's needs a room admin.
+pub fn shift_hue(ctx: &ReducerContext, degrees: f32, own_only: bool) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if !degrees.is_finite() {
+        return Err(format!("Invalid hue shift {}", degrees));
+    }
+    if !own_only && !is_admin(ctx) {
+        return Err("Only room admins can recolor everyone's points".to_string());
+    }
+
+    let points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| !own_only || point.identity == ctx.sender)
+        .collect();
+    let mut shifted = 0;
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        // Colors that don't parse are left alone
+        let Ok(color) = shift_hex_hue(&point.color, degrees) else {
+            continue;
+        };
+        if color != point.color.to_ascii_lowercase() {
+            batch.update(CanvasPoint { color, ..point });
+            shifted += 1;
+        }
+    }
+    batch.finish();
+
+    log::info!(
+        "User {} shifted the hue of {} points by {} degrees",
+        ctx.sender,
+        shifted,
+        degrees
+    );
+    Ok(())
+}
+
+// Turns a hex color's hue by `degrees`, wrapping around the color wheel
+fn shift_hex_hue(color: &str, degrees: f32) -> Result<String, String> {
+    let (hue, saturation, lightness) = rgb_to_hsl(parse_hex_color(color)?);
+    Ok(hsl_to_hex((
+        (hue + degrees).rem_euclid(360.0),
+        saturation,
+        lightness,
+    )))
+}
+
+#[reducer]
+// Replaces each point's color with the nearest color in `palette`, by RGB
+// distance. Ownership rules match shift_hue: `own_only` recolors just the
+// caller's points, and recoloring everyone's needs a room admin.
+pub fn quantize_palette(
+    ctx: &ReducerContext,
+    palette: Vec<String>,
+    own_only: bool,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if palette.is_empty() || palette.len() > MAX_PALETTE_COLORS {
+        return Err(format!(
+            "Palettes must have 1 to {} colors",
+            MAX_PALETTE_COLORS
+        ));
+    }
+    let palette = palette
+        .iter()
+        .map(|color| Ok((color.to_ascii_lowercase(), parse_hex_color(color)?)))
+        .collect::<Result<Vec<_>, String>>()?;
+    if !own_only && !is_admin(ctx) {
+        return Err("Only room admins can recolor everyone's points".to_string());
+    }
+
+    let points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| !own_only || point.identity == ctx.sender)
+        .collect();
+    let mut recolored = 0;
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        // Colors that don't parse are left alone
+        let Ok(rgb) = parse_hex_color(&point.color) else {
+            continue;
+        };
+        let color = nearest_palette_color(rgb, &palette);
+        if color != point.color.to_ascii_lowercase() {
+            batch.update(CanvasPoint {
+                color: color.to_string(),
+                ..point
+            });
+            recolored += 1;
+        }
+    }
+    batch.finish();
+
+    log::info!(
+        "User {} quantized {} points to a {} color palette",
+        ctx.sender,
+        recolored,
+        palette.len()
+    );
+    Ok(())
+}
+
+#[reducer]
+// Blends points' colors `strength` of the way toward `tint`, from 0.0 (no
+// change) to 1.0 (every point becomes the tint). Ownership rules match
+// shift_hue.
+pub fn tint_canvas(
+    ctx: &ReducerContext,
+    tint: String,
+    strength: f32,
+    own_only: bool,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let tint_rgb = parse_hex_color(&tint)?;
+    if !strength.is_finite() {
+        return Err(format!("Invalid tint strength {}", strength));
+    }
+    let strength = strength.clamp(0.0, 1.0);
+    if !own_only && !is_admin(ctx) {
+        return Err("Only room admins can recolor everyone's points".to_string());
+    }
+
+    let points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| !own_only || point.identity == ctx.sender)
+        .collect();
+    let mut tinted = 0;
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        // Colors that don't parse are left alone
+        let Ok(rgb) = parse_hex_color(&point.color) else {
+            continue;
+        };
+        let color = blend_hex_colors(rgb, tint_rgb, strength);
+        if color != point.color.to_ascii_lowercase() {
+            batch.update(CanvasPoint { color, ..point });
+            tinted += 1;
+        }
+    }
+    batch.finish();
+
+    log::info!(
+        "User {} tinted {} points toward {} at strength {}",
+        ctx.sender,
+        tinted,
+        tint,
+        strength
+    );
+    Ok(())
+}
+
+#[reducer]
+// Locks a user to one drawing color, or frees them again when `color` is None
+pub fn assign_color(
+    ctx: &ReducerContext,
+    identity: Identity,
+    color: Option<String>,
+) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can assign colors".to_string());
+    }
+
+    ctx.db.color_assignment().identity().delete(identity);
+    if let Some(color) = color {
+        validate_color(&color)?;
+        ctx.db.color_assignment().insert(ColorAssignment {
+            identity,
+            color,
+            assigned_by: ctx.sender,
+            assigned_at: ctx.timestamp,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Sets the brush of each listed user with a cursor, e.g. to prepare a class
+// before a lesson. Every assignment is checked before any is applied; users
+// without a cursor are skipped, and a user locked to an assigned color keeps it.
+pub fn apply_brush_template(
+    ctx: &ReducerContext,
+    assignments: Vec<BrushAssignment>,
+) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can apply brush templates".to_string());
+    }
+    if assignments.len() > MAX_BRUSH_ASSIGNMENTS {
+        return Err(format!(
+            "Brush templates are limited to {} users",
+            MAX_BRUSH_ASSIGNMENTS
+        ));
+    }
+    for assignment in &assignments {
+        validate_color(&assignment.color)?;
+        validate_size(assignment.size)?;
+    }
+
+    let mut applied = 0;
+    for assignment in assignments {
+        let Some(cursor) = ctx.db.cursor().identity().find(assignment.identity) else {
+            continue;
+        };
+        let color = ctx
+            .db
+            .color_assignment()
+            .identity()
+            .find(assignment.identity)
+            .map(|locked| locked.color)
+            .unwrap_or(assignment.color);
+        ctx.db.cursor().identity().update(Cursor {
+            color,
+            size: clamp_brush_size(ctx, assignment.size),
+            ..cursor
+        });
+        applied += 1;
+    }
+
+    audit(
+        ctx,
+        "apply_brush_template",
+        format!("Set the brushes of {} users", applied),
+    );
+    Ok(())
+}
+
+#[reducer]
+// Deletes each listed state the caller may delete, as delete_canvas_state
+// would, skipping the rest. The counts are reported through action_result.
+pub fn delete_canvas_states(ctx: &ReducerContext, ids: Vec<u64>) {
+    let mut deleted = 0;
+    let mut skipped = 0;
+    let mut seen = HashSet::new();
+
+    for state_id in ids {
+        if !seen.insert(state_id) {
+            continue;
+        }
+        let reason = match ctx.db.canvas_state().id().find(state_id) {
+            None => "it doesn't exist",
+            Some(state) if state.created_by != ctx.sender => "it isn't theirs",
+            Some(state) if state.is_locked => "it is locked",
+            Some(state) => {
+                delete_canvas_state_and_points(ctx, state);
+                deleted += 1;
+                continue;
+            }
+        };
+        log::info!(
+            "User {} skipped deleting canvas state {}: {}",
+            ctx.sender,
+            state_id,
+            reason
+        );
+        skipped += 1;
+    }
+
+    write_result(
+        ctx,
+        "delete_canvas_states",
+        skipped == 0,
+        format!("Deleted {} canvas states, skipped {}", deleted, skipped),
+    );
+}
+
+#[reducer]
+// Finds saved states whose name contains `query`, ignoring case, and writes
+// them to the caller's state_search_result rows. Every saved state is visible
+// to everyone in the room, so all of them are searched.
+pub fn search_states(ctx: &ReducerContext, query: String) {
+    let old_results: Vec<StateSearchResult> = ctx
+        .db
+        .state_search_result()
+        .identity()
+        .filter(ctx.sender)
+        .collect();
+    for row in old_results {
+        ctx.db.state_search_result().delete(row);
+    }
+
+    let query = query.trim().to_lowercase();
+    let mut matches: Vec<CanvasState> = ctx
+        .db
+        .canvas_state()
+        .iter()
+        .filter(|state| state.name.to_lowercase().contains(&query))
+        .collect();
+    matches.sort_by_key(|state| std::cmp::Reverse((state.created_at, state.id)));
+
+    for (rank, state) in matches
+        .into_iter()
+        .take(MAX_STATE_SEARCH_RESULTS)
+        .enumerate()
+    {
+        ctx.db.state_search_result().insert(StateSearchResult {
+            id: 0, // Will be auto-incremented
+            identity: ctx.sender,
+            rank: rank as u32,
+            state_id: state.id,
+            name: state.name,
+        });
+    }
+}
+
+#[reducer]
+// Writes up to `limit` saved states to the caller's state_query_result rows,
+// sorted by `sort`: "recent" or "oldest" by creation time, "largest" or
+// "smallest" by point count. Like search_states, every state is visible.
+pub fn query_states(ctx: &ReducerContext, sort: String, limit: u32) -> Result<(), String> {
+    if limit == 0 || limit > MAX_STATE_QUERY_RESULTS {
+        return Err(format!(
+            "Limit must be between 1 and {}",
+            MAX_STATE_QUERY_RESULTS
+        ));
+    }
+
+    let mut states: Vec<CanvasState> = ctx.db.canvas_state().iter().collect();
+    match sort.as_str() {
+        "recent" => states.sort_by_key(|s| std::cmp::Reverse((s.created_at, s.id))),
+        "oldest" => states.sort_by_key(|s| (s.created_at, s.id)),
+        "largest" => states.sort_by_key(|s| std::cmp::Reverse((s.point_count, s.id))),
+        "smallest" => states.sort_by_key(|s| (s.point_count, s.id)),
+        _ => {
+            return Err(format!(
+                "Unknown sort {:?}, expected recent, oldest, largest or smallest",
+                sort
+            ))
+        }
+    }
+
+    let old_results: Vec<StateQueryResult> = ctx
+        .db
+        .state_query_result()
+        .identity()
+        .filter(ctx.sender)
+        .collect();
+    for row in old_results {
+        ctx.db.state_query_result().delete(row);
+    }
+
+    for (rank, state) in states.into_iter().take(limit as usize).enumerate() {
+        ctx.db.state_query_result().insert(StateQueryResult {
+            id: 0, // Will be auto-incremented
+            identity: ctx.sender,
+            rank: rank as u32,
+            state_id: state.id,
+            name: state.name,
+            created_at: state.created_at,
+            point_count: state.point_count,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Tallies a saved state's point colors into the caller's state_color_stats
+// rows, the same way the live color histogram does. A state with no points
+// just leaves the caller with no rows.
+pub fn state_color_breakdown(ctx: &ReducerContext, state_id: u64) -> Result<(), String> {
+    if ctx.db.canvas_state().id().find(state_id).is_none() {
+        return Err(format!("Canvas state {} not found", state_id));
+    }
+
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for point in ctx.db.saved_canvas_point().state_id().filter(state_id) {
+        *counts.entry(point.color).or_insert(0) += 1;
+    }
+
+    // Most used first, ties broken by color so the ranking is stable
+    let mut ranked: Vec<(String, u64)> = counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let old_stats: Vec<StateColorStats> = ctx
+        .db
+        .state_color_stats()
+        .identity()
+        .filter(ctx.sender)
+        .collect();
+    for row in old_stats {
+        ctx.db.state_color_stats().delete(row);
+    }
+    for (rank, (color, count)) in ranked.into_iter().take(MAX_STATE_COLOR_STATS).enumerate() {
+        ctx.db.state_color_stats().insert(StateColorStats {
+            id: 0, // Will be auto-incremented
+            identity: ctx.sender,
+            state_id,
+            rank: rank as u32,
+            color,
+            count,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Measures which way the canvas's strokes run, from each stroke's first point
+// to its last in paint order, into the caller's analysis_result row.
+// Symmetry copies are left out so mirrored strokes don't cancel out.
+pub fn analyze_stroke_directions(ctx: &ReducerContext) {
+    // First and last point of every stroke, by draw order
+    let mut ends: HashMap<u64, (&CanvasPoint, &CanvasPoint)> = HashMap::new();
+    let points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| point.stroke_id != 0 && point.mirror_of == 0)
+        .collect();
+    for point in &points {
+        let entry = ends.entry(point.stroke_id).or_insert((point, point));
+        if point.draw_order < entry.0.draw_order {
+            entry.0 = point;
+        }
+        if point.draw_order > entry.1.draw_order {
+            entry.1 = point;
+        }
+    }
+
+    let mut histogram = vec![0; ANGLE_HISTOGRAM_BINS];
+    let (mut sum_dx, mut sum_dy, mut stroke_count) = (0.0, 0.0, 0u64);
+    for (first, last) in ends.into_values() {
+        let (dx, dy) = (last.x - first.x, last.y - first.y);
+        if dx == 0.0 && dy == 0.0 {
+            continue;
+        }
+        let angle = dy.atan2(dx).to_degrees().rem_euclid(360.0);
+        let bin = (angle / (360.0 / ANGLE_HISTOGRAM_BINS as f32)) as usize;
+        histogram[bin.min(ANGLE_HISTOGRAM_BINS - 1)] += 1;
+        sum_dx += dx;
+        sum_dy += dy;
+        stroke_count += 1;
+    }
+
+    let (mean_dx, mean_dy) = if stroke_count == 0 {
+        (0.0, 0.0)
+    } else {
+        (sum_dx / stroke_count as f32, sum_dy / stroke_count as f32)
+    };
+    let mean_angle = (mean_dx != 0.0 || mean_dy != 0.0)
+        .then(|| mean_dy.atan2(mean_dx).to_degrees().rem_euclid(360.0));
+    let result = AnalysisResult {
+        identity: ctx.sender,
+        stroke_count,
+        mean_dx,
+        mean_dy,
+        mean_angle,
+        angle_histogram: histogram,
+        analyzed_at: ctx.timestamp,
+    };
+    if ctx
+        .db
+        .analysis_result()
+        .identity()
+        .find(ctx.sender)
+        .is_some()
+    {
+        ctx.db.analysis_result().identity().update(result);
+    } else {
+        ctx.db.analysis_result().insert(result);
+    }
+}
+
+#[reducer]
+// Writes a lobby summary of each room the caller may enter to their room_list
+// rows. This module is one room; with an access code set, only members and
+// admins see it listed, and once archived nobody does.
+pub fn list_rooms(ctx: &ReducerContext) {
+    let old_rows: Vec<RoomList> = ctx.db.room_list().identity().filter(ctx.sender).collect();
+    for row in old_rows {
+        ctx.db.room_list().delete(row);
+    }
+    if !is_room_member(ctx) || canvas_settings(ctx).archived {
+        return;
+    }
+
+    ctx.db.room_list().insert(RoomList {
+        id: 0, // Will be auto-incremented
+        identity: ctx.sender,
+        room_id: ctx.identity(),
+        topic: canvas_settings(ctx).room_topic,
+        online_count: ctx.db.cursor().count(),
+        point_count: canvas_meta(ctx).point_count,
+        listed_at: ctx.timestamp,
+    });
+}
+
+#[reducer]
+// Deletes every point whose center lies outside the rectangle between two
+// corners. With `translate` the remaining points are also shifted so the
+// rectangle's top-left corner becomes 0,0.
+pub fn crop_canvas(
+    ctx: &ReducerContext,
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+    translate: bool,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if !is_admin(ctx) {
+        return Err("Only room admins can crop the canvas".to_string());
+    }
+    if ![x0, y0, x1, y1].iter().all(|v| v.is_finite()) {
+        return Err("Crop corners must be finite".to_string());
+    }
+
+    // Either pair of opposite corners describes the same rectangle
+    let (min_x, max_x) = (x0.min(x1), x0.max(x1));
+    let (min_y, max_y) = (y0.min(y1), y0.max(y1));
+
+    let points: Vec<CanvasPoint> = ctx.db.canvas_point().iter().collect();
+    let mut removed = 0;
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        let inside = (min_x..=max_x).contains(&point.x) && (min_y..=max_y).contains(&point.y);
+        if !inside {
+            batch.delete(point);
+            removed += 1;
+        } else if translate && (min_x != 0.0 || min_y != 0.0) {
+            batch.update(CanvasPoint {
+                x: point.x - min_x,
+                y: point.y - min_y,
+                ..point
+            });
+        }
+    }
+    batch.finish();
+
+    log::info!(
+        "User {} cropped the canvas to ({}, {})-({}, {}), removing {} points",
+        ctx.sender,
+        min_x,
+        min_y,
+        max_x,
+        max_y,
+        removed
+    );
+    Ok(())
+}
+
+#[reducer]
+// Draws a straight line from (x0, y0) toward (x1, y1), snapped to the nearest
+// 45 degree direction, for shift-drawn lines that stay straight despite jitter
+pub fn add_constrained_segment(
+    ctx: &ReducerContext,
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+    color: String,
+    size: f32,
+) -> Result<(), String> {
+    check_join_cooldown(ctx, "add_constrained_segment")?;
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    check_tool_allowed(ctx, "add_constrained_segment", TOOL_LINE)?;
+    validate_position(x0, y0)?;
+    validate_position(x1, y1)?;
+    validate_color(&color)?;
+    validate_size(size)?;
+    let size = clamp_brush_size(ctx, size);
+    let (x0, y0) = snap_to_grid(ctx, x0, y0, true);
+    let (x1, y1) = snap_to_grid(ctx, x1, y1, true);
+
+    // Keep the length of the drag but round its direction to 45 degrees
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    let length = (dx * dx + dy * dy).sqrt();
+    let step = std::f32::consts::FRAC_PI_4;
+    let angle = (dy.atan2(dx) / step).round() * step;
+    let (dir_x, dir_y) = (angle.cos(), angle.sin());
+
+    // Shorten the line rather than bend it if the snapped end leaves the canvas
+    let mut reach = length;
+    for (start, dir, limit) in [
+        (x0, dir_x, CANVAS_WIDTH as f32),
+        (y0, dir_y, CANVAS_HEIGHT as f32),
+    ] {
+        if dir > f32::EPSILON {
+            reach = reach.min((limit - start) / dir);
+        } else if dir < -f32::EPSILON {
+            reach = reach.min(start / -dir);
+        }
+    }
+    let (end_x, end_y) = (x0 + reach * dir_x, y0 + reach * dir_y);
+
+    for (x, y) in segment_positions(x0, y0, end_x, end_y, size) {
+        draw_point(ctx, x, y, color.clone(), size, BLEND_MODE_NORMAL);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Makes points disappear once they are `seconds` old, or keeps them forever
+// when `seconds` is 0
+pub fn set_point_ttl(ctx: &ReducerContext, seconds: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        point_ttl_seconds: seconds,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: deletes points older than the room's point TTL, if one is set
+pub fn expire_old_points(
+    ctx: &ReducerContext,
+    _schedule: PointExpirySchedule,
+) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("expire_old_points may only be run by the scheduler".to_string());
+    }
+    if !is_room_active(ctx) {
+        return Ok(());
+    }
+    if canvas_settings(ctx).canvas_frozen {
+        return Ok(());
+    }
+
+    // Most rooms keep their points, so don't scan the canvas for them
+    let ttl = canvas_settings(ctx).point_ttl_seconds;
+    if ttl == 0 {
+        return Ok(());
+    }
+
+    let cutoff = ctx.timestamp - Duration::from_secs(ttl);
+    let expired: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| point.timestamp < cutoff)
+        .collect();
+    let mut batch = PointBatch::new(ctx);
+    for point in expired {
+        batch.delete(point);
+    }
+    batch.finish();
+    Ok(())
+}
+
+#[reducer]
+// Draws a point at each position with a color blended from `color_start` to
+// `color_end` by how far along the path the position is
+pub fn add_gradient_stroke(
+    ctx: &ReducerContext,
+    points: Vec<PathPoint>,
+    color_start: String,
+    color_end: String,
+    size: f32,
+) -> Result<(), String> {
+    check_join_cooldown(ctx, "add_gradient_stroke")?;
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    check_tool_allowed(ctx, "add_gradient_stroke", TOOL_GRADIENT)?;
+    if points.len() > MAX_STROKE_PATH_POINTS {
+        return Err(format!(
+            "Gradient strokes are limited to {} points",
+            MAX_STROKE_PATH_POINTS
+        ));
+    }
+    let from = parse_hex_color(&color_start)?;
+    let to = parse_hex_color(&color_end)?;
+    validate_size(size)?;
+    for point in &points {
+        validate_position(point.x, point.y)?;
+    }
+
+    for (point, t) in points.iter().zip(path_progress(&points)) {
+        draw_point(
+            ctx,
+            point.x,
+            point.y,
+            blend_hex_colors(from, to, t),
+            size,
+            BLEND_MODE_NORMAL,
+        );
+    }
+    Ok(())
+}
+
+#[reducer]
+// Draws a point at each position, thinning the stroke toward both ends so it
+// looks hand-drawn; the middle of the path gets the full `base_size`
+pub fn add_tapered_stroke(
+    ctx: &ReducerContext,
+    points: Vec<PathPoint>,
+    color: String,
+    base_size: f32,
+) -> Result<(), String> {
+    check_join_cooldown(ctx, "add_tapered_stroke")?;
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    check_tool_allowed(ctx, "add_tapered_stroke", TOOL_TAPER)?;
+    if points.len() > MAX_STROKE_PATH_POINTS {
+        return Err(format!(
+            "Tapered strokes are limited to {} points",
+            MAX_STROKE_PATH_POINTS
+        ));
+    }
+    validate_color(&color)?;
+    validate_size(base_size)?;
+    for point in &points {
+        validate_position(point.x, point.y)?;
+    }
+
+    for (point, t) in points.iter().zip(path_progress(&points)) {
+        let taper = TAPER_END_SCALE + (1.0 - TAPER_END_SCALE) * (std::f32::consts::PI * t).sin();
+        let size = (base_size * taper).max(MIN_BRUSH_SIZE);
+        draw_point(
+            ctx,
+            point.x,
+            point.y,
+            color.clone(),
+            size,
+            BLEND_MODE_NORMAL,
+        );
+    }
+    Ok(())
+}
+
+#[reducer]
+// Closes one of the caller's nearly-closed strokes by filling in points from
+// its last point back to its first
+pub fn close_stroke(ctx: &ReducerContext, stroke_id: u64) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let mut points = owned_stroke_points(ctx, stroke_id)?;
+    if points.len() < CLOSE_STROKE_MIN_POINTS {
+        return Err(format!(
+            "Stroke {} needs at least {} points to close",
+            stroke_id, CLOSE_STROKE_MIN_POINTS
+        ));
+    }
+    points.sort_by_key(|point| point.draw_order);
+    let first = &points[0];
+    let last = &points[points.len() - 1];
+
+    let gap = ((first.x - last.x).powi(2) + (first.y - last.y).powi(2)).sqrt();
+    if gap > CLOSE_STROKE_THRESHOLD {
+        return Err(format!(
+            "Stroke {} ends are {:.0}px apart, too far to close",
+            stroke_id, gap
+        ));
+    }
+
+    // The ends themselves already exist; only the gap between them is drawn
+    let positions = segment_positions(last.x, last.y, first.x, first.y, last.size);
+    let gap_positions = &positions[1..positions.len() - 1];
+    for &(x, y) in gap_positions {
+        insert_canvas_point(
+            ctx,
+            CanvasPoint {
+                opacity: last.opacity,
+                stroke_id,
+                layer_id: last.layer_id,
+                blend_mode: last.blend_mode.clone(),
+                ..new_canvas_point(ctx, x, y, last.color.clone(), last.size)
+            },
+        );
+    }
+
+    if let Some(stroke) = ctx.db.stroke_meta().stroke_id().find(stroke_id) {
+        ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+            point_count: stroke.point_count + gap_positions.len() as u64,
+            ..stroke
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Sets the topic shown in the room header; an empty topic removes it
+pub fn set_room_topic(ctx: &ReducerContext, topic: String) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change the room topic".to_string());
+    }
+    let topic = topic.trim().to_string();
+    if topic.chars().count() > MAX_ROOM_TOPIC_LEN {
+        return Err(format!(
+            "Room topic is limited to {} characters",
+            MAX_ROOM_TOPIC_LEN
+        ));
+    }
+
+    let settings = canvas_settings(ctx);
+    audit(
+        ctx,
+        "set_room_topic",
+        format!(
+            "Changed topic from {:?} to {:?}",
+            settings.room_topic, topic
+        ),
+    );
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        room_topic: topic,
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Writes every state the caller saved, with its points, to their
+// export_result as one JSON bundle for backup or moving elsewhere
+pub fn export_user_states(ctx: &ReducerContext) -> Result<(), String> {
+    let mut states: Vec<CanvasState> = ctx
+        .db
+        .canvas_state()
+        .iter()
+        .filter(|state| state.created_by == ctx.sender)
+        .collect();
+    states.sort_by_key(|state| state.id);
+
+    let mut bundle_states = Vec::new();
+    let mut bundle_bytes = 0;
+    for state in states {
+        let entry = json!({
+            "id": state.id,
+            "name": state.name,
+            "description": state.description,
+            "created_at_micros": state.created_at.to_micros_since_unix_epoch(),
+            "is_autosave": state.is_autosave,
+            "is_locked": state.is_locked,
+            "point_count": state.point_count,
+            "points": saved_points_json(ctx, state.id),
+        });
+        bundle_bytes += entry.to_string().len();
+        if bundle_bytes > MAX_EXPORT_BUNDLE_BYTES {
+            return Err(format!(
+                "Your saved states are too large to export in one bundle (over {} bytes)",
+                MAX_EXPORT_BUNDLE_BYTES
+            ));
+        }
+        bundle_states.push(entry);
+    }
+
+    let bundle = json!({
+        "version": USER_STATES_BUNDLE_VERSION,
+        "owner": ctx.sender.to_hex().to_string(),
+        "width": CANVAS_WIDTH,
+        "height": CANVAS_HEIGHT,
+        "states": bundle_states,
+    });
+
+    write_export(ctx, "user-states", bundle.to_string());
+    Ok(())
+}
+
+#[reducer]
+// Writes a page of the chat as a versioned JSON transcript to the caller's
+// export_result, oldest first, starting after message `after_id` (0 for the
+// beginning). Senders are named from user_profile where they still have one.
+// When more messages remain, `next_after_id` gives the `after_id` of the next
+// page; it is null on the last page, including for a room with no chat.
+pub fn export_chat(ctx: &ReducerContext, after_id: u64) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can export the chat".to_string());
+    }
+
+    let mut messages: Vec<ChatMessage> = ctx
+        .db
+        .chat_message()
+        .iter()
+        .filter(|message| message.id > after_id)
+        .collect();
+    messages.sort_by_key(|message| message.id);
+    let has_more = messages.len() > MAX_CHAT_EXPORT_MESSAGES;
+    messages.truncate(MAX_CHAT_EXPORT_MESSAGES);
+
+    let entries: Vec<serde_json::Value> = messages
+        .iter()
+        .map(|message| {
+            let sender_name = ctx
+                .db
+                .user_profile()
+                .identity()
+                .find(message.sender)
+                .map(|profile| profile.display_name);
+            json!({
+                "id": message.id,
+                "sender": message.sender.to_hex().to_string(),
+                "sender_name": sender_name,
+                "text": message.text,
+                "sent_at_micros": message.sent_at.to_micros_since_unix_epoch(),
+            })
+        })
+        .collect();
+    let next_after_id = if has_more {
+        messages.last().map(|message| message.id)
+    } else {
+        None
+    };
+
+    let transcript = json!({
+        "version": CHAT_TRANSCRIPT_VERSION,
+        "after_id": after_id,
+        "next_after_id": next_after_id,
+        "messages": entries,
+    });
+    write_export(ctx, "chat", transcript.to_string());
+    log::info!(
+        "User {} exported {} chat messages after {}",
+        ctx.sender,
+        messages.len(),
+        after_id
+    );
+    Ok(())
+}
+
+#[reducer]
+// Recreates the states in a bundle from export_user_states as new states
+// owned by the caller. Malformed states are skipped; the imported and skipped
+// counts are reported through action_result.
+pub fn import_user_states(ctx: &ReducerContext, bundle: String) {
+    let parsed: Result<serde_json::Value, String> =
+        serde_json::from_str(&bundle).map_err(|e| format!("Bundle is not valid JSON: {}", e));
+    let states = parsed.and_then(|mut bundle| {
+        if bundle["version"].as_u64() != Some(USER_STATES_BUNDLE_VERSION as u64) {
+            return Err(format!(
+                "Unsupported bundle version, expected {}",
+                USER_STATES_BUNDLE_VERSION
+            ));
+        }
+        match bundle["states"].take() {
+            serde_json::Value::Array(states) if states.len() > MAX_BUNDLE_STATES => Err(format!(
+                "Bundle has {} states, at most {} can be imported at once",
+                states.len(),
+                MAX_BUNDLE_STATES
+            )),
+            serde_json::Value::Array(states) => Ok(states),
+            _ => Err("Bundle has no states list".to_string()),
+        }
+    });
+    let states = match states {
+        Ok(states) => states,
+        Err(message) => {
+            write_result(ctx, "import_user_states", false, message);
+            return;
+        }
+    };
+
+    let mut imported = 0;
+    let mut skipped = 0;
+    for (index, state) in states.iter().enumerate() {
+        match import_bundled_state(ctx, state) {
+            Ok(()) => imported += 1,
+            Err(reason) => {
+                log::info!(
+                    "User {} skipped bundled state {}: {}",
+                    ctx.sender,
+                    index,
+                    reason
+                );
+                skipped += 1;
+            }
+        }
+    }
+
+    write_result(
+        ctx,
+        "import_user_states",
+        skipped == 0,
+        format!("Imported {} canvas states, skipped {}", imported, skipped),
+    );
+}
+
+#[reducer]
+// Changes the color of one point, reporting the outcome through action_result
+pub fn recolor_point(ctx: &ReducerContext, point_id: u64, color: String) {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        write_result(ctx, "recolor_point", false, reason.to_string());
+        return;
+    }
+    let outcome = validate_color(&color)
+        .and_then(|_| editable_point(ctx, point_id))
+        .and_then(|point| {
+            // A color-locked user can't recolor their way around the lock
+            if !is_admin(ctx) && drawing_color_for(ctx, color.clone()) != color {
+                return Err("Your drawing color is assigned by an admin".to_string());
+            }
+            update_canvas_point(ctx, CanvasPoint { color, ..point });
+            Ok(format!("Recolored point {}", point_id))
+        });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "recolor_point", true, message),
+        Err(message) => write_result(ctx, "recolor_point", false, message),
+    }
+}
+
+#[reducer]
+// Changes every point the caller drew to one color, whatever color each has
+// now, reporting how many changed through action_result
+pub fn recolor_my_points(ctx: &ReducerContext, to: String) {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        write_result(ctx, "recolor_my_points", false, reason.to_string());
+        return;
+    }
+    let outcome = validate_color(&to).and_then(|_| {
+        // A color-locked user can't recolor their way around the lock
+        if !is_admin(ctx) && drawing_color_for(ctx, to.clone()) != to {
+            return Err("Your drawing color is assigned by an admin".to_string());
+        }
+        let points = owner_points_recolored(ctx.db.canvas_point().iter(), ctx.sender, &to);
+        let recolored = points.len();
+        let mut batch = PointBatch::new(ctx);
+        for point in points {
+            batch.update(point);
+        }
+        batch.finish();
+        Ok(format!("Recolored {} of your points to {}", recolored, to))
+    });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "recolor_my_points", true, message),
+        Err(message) => write_result(ctx, "recolor_my_points", false, message),
+    }
+}
+
+// The points recolor_my_points rewrites, already in the new color: every one
+// of the owner's points that isn't in it yet, and none of anyone else's
+fn owner_points_recolored(
+    points: impl IntoIterator<Item = CanvasPoint>,
+    owner: Identity,
+    to: &str,
+) -> Vec<CanvasPoint> {
+    points
+        .into_iter()
+        .filter(|point| point.identity == owner && point.color != to)
+        .map(|point| CanvasPoint {
+            color: to.to_string(),
+            ..point
+        })
+        .collect()
+}
+
+#[reducer]
+// Changes the size of one point, reporting the outcome through action_result
+pub fn resize_point(ctx: &ReducerContext, point_id: u64, size: f32) {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        write_result(ctx, "resize_point", false, reason.to_string());
+        return;
+    }
+    let outcome = validate_size(size)
+        .and_then(|_| editable_point(ctx, point_id))
+        .map(|point| {
+            let size = clamp_brush_size(ctx, size);
+            update_canvas_point(ctx, CanvasPoint { size, ..point });
+            format!("Resized point {} to {}", point_id, size)
+        });
+
+    match outcome {
+        Ok(message) => write_result(ctx, "resize_point", true, message),
+        Err(message) => write_result(ctx, "resize_point", false, message),
+    }
+}
+
+#[reducer]
+// Finds the point whose center is closest to (x, y), within `max_dist`, and
+// writes it to the caller's nearest_result row
+pub fn find_nearest_point(
+    ctx: &ReducerContext,
+    x: f32,
+    y: f32,
+    max_dist: f32,
+) -> Result<(), String> {
+    if ![x, y, max_dist].iter().all(|v| v.is_finite()) || max_dist < 0.0 {
+        return Err("Position and range must be finite, with a non-negative range".to_string());
+    }
+    if max_dist > MAX_NEAREST_POINT_DISTANCE {
+        return Err(format!(
+            "Range can be at most {}",
+            MAX_NEAREST_POINT_DISTANCE
+        ));
+    }
+
+    let nearest = points_near(ctx, x, y, max_dist)
+        .into_iter()
+        .filter(|point| (point.x - x).abs() <= max_dist && (point.y - y).abs() <= max_dist)
+        .map(|point| {
+            let distance = ((point.x - x).powi(2) + (point.y - y).powi(2)).sqrt();
+            (distance, point)
+        })
+        .filter(|(distance, _)| *distance <= max_dist)
+        .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.id.cmp(&b.1.id)));
+
+    let result = match nearest {
+        Some((distance, point)) => NearestResult {
+            identity: ctx.sender,
+            point_id: Some(point.id),
+            x: point.x,
+            y: point.y,
+            distance,
+            found_at: ctx.timestamp,
+        },
+        None => NearestResult {
+            identity: ctx.sender,
+            point_id: None,
+            x,
+            y,
+            distance: 0.0,
+            found_at: ctx.timestamp,
+        },
+    };
+    if ctx
+        .db
+        .nearest_result()
+        .identity()
+        .find(ctx.sender)
+        .is_some()
+    {
+        ctx.db.nearest_result().identity().update(result);
+    } else {
+        ctx.db.nearest_result().insert(result);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Freezes the canvas so no points can be drawn, erased or changed until it is
+// unfrozen; cursors keep moving
+pub fn freeze_canvas(ctx: &ReducerContext) -> Result<(), String> {
+    set_canvas_frozen(ctx, true)
+}
+
+#[reducer]
+// Lets the canvas be edited again after freeze_canvas
+pub fn unfreeze_canvas(ctx: &ReducerContext) -> Result<(), String> {
+    set_canvas_frozen(ctx, false)
+}
+
+#[reducer]
+// Makes another user a room admin
+pub fn grant_admin(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can grant admin rights".to_string());
+    }
+    if ctx.db.room_admin().identity().find(target).is_some() {
+        return Ok(());
+    }
+
+    ctx.db.room_admin().insert(RoomAdmin {
+        identity: target,
+        granted_at: ctx.timestamp,
+    });
+    audit(ctx, "grant_admin", format!("Made {} an admin", target));
+    Ok(())
+}
+
+#[reducer]
+// Takes admin rights away from a user, who may be the caller. The room always
+// keeps at least one admin.
+pub fn revoke_admin(ctx: &ReducerContext, target: Identity) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can revoke admin rights".to_string());
+    }
+    let Some(admin) = ctx.db.room_admin().identity().find(target) else {
+        return Err(format!("{} is not an admin", target));
+    };
+    if ctx.db.room_admin().count() <= 1 {
+        return Err("The room's last admin can't be removed".to_string());
+    }
+
+    ctx.db.room_admin().delete(admin);
+    audit(
+        ctx,
+        "revoke_admin",
+        format!("Removed {} as an admin", target),
+    );
+    Ok(())
+}
+
+#[reducer]
+// Makes the room private behind an access code, or opens it again with None.
+// Setting or clearing a code removes every existing membership.
+pub fn set_access_code(ctx: &ReducerContext, code: Option<String>) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can change canvas settings".to_string());
+    }
+    if let Some(code) = &code {
+        let length = code.chars().count();
+        if !(MIN_ACCESS_CODE_LEN..=MAX_ACCESS_CODE_LEN).contains(&length) {
+            return Err(format!(
+                "Access codes must be {} to {} characters",
+                MIN_ACCESS_CODE_LEN, MAX_ACCESS_CODE_LEN
+            ));
+        }
+    }
+
+    ctx.db.room_secret().id().delete(ROOM_SECRET_ID);
+    if let Some(code) = &code {
+        let salt = format!("{:032x}", ctx.random::<u128>());
+        ctx.db.room_secret().insert(RoomSecret {
+            id: ROOM_SECRET_ID,
+            access_code_hash: hash_access_code(&salt, code),
+            salt,
+        });
+    }
+    // Failed guesses at the old code don't count against the new one
+    let failures: Vec<JoinFailure> = ctx.db.join_failure().iter().collect();
+    for failure in failures {
+        ctx.db.join_failure().delete(failure);
+    }
+
+    let members: Vec<RoomMember> = ctx.db.room_member().iter().collect();
+    for member in members {
+        ctx.db.room_member().delete(member);
+    }
+
+    let detail = if code.is_some() {
+        "Set a new access code"
+    } else {
+        "Removed the access code"
+    };
+    audit(ctx, "set_access_code", detail.to_string());
+    let settings = canvas_settings(ctx);
+    ctx.db.canvas_settings().id().update(CanvasSettings {
+        access_code_required: code.is_some(),
+        ..settings
+    });
+    Ok(())
+}
+
+#[reducer]
+// Joins the room so the caller can draw. Rooms with an access code need the
+// matching `code`; open rooms accept anyone. After MAX_JOIN_FAILURES wrong
+// codes in JOIN_FAILURE_WINDOW the caller must wait for the window to pass.
+// Wrong codes are reported through action_result rather than an Err, since
+// an Err would roll back the failure count.
+pub fn join_room(ctx: &ReducerContext, code: Option<String>) -> Result<(), String> {
+    if let Some(secret) = ctx.db.room_secret().id().find(ROOM_SECRET_ID) {
+        let failure = ctx
+            .db
+            .join_failure()
+            .identity()
+            .find(ctx.sender)
+            .filter(|failure| failure.window_started + JOIN_FAILURE_WINDOW > ctx.timestamp);
+        if failure
+            .as_ref()
+            .is_some_and(|failure| failure.failures >= MAX_JOIN_FAILURES)
+        {
+            return Err("Too many wrong access codes; try again later".to_string());
+        }
+
+        let matches = code
+            .is_some_and(|code| hash_access_code(&secret.salt, &code) == secret.access_code_hash);
+        if !matches {
+            let failure = failure.unwrap_or(JoinFailure {
+                identity: ctx.sender,
+                failures: 0,
+                window_started: ctx.timestamp,
+            });
+            ctx.db.join_failure().identity().delete(ctx.sender);
+            ctx.db.join_failure().insert(JoinFailure {
+                failures: failure.failures + 1,
+                ..failure
+            });
+            write_result(ctx, "join_room", false, "Wrong access code".to_string());
+            return Ok(());
+        }
+        ctx.db.join_failure().identity().delete(ctx.sender);
+    }
+
+    if ctx.db.room_member().identity().find(ctx.sender).is_none() {
+        ctx.db.room_member().insert(RoomMember {
+            identity: ctx.sender,
+            joined_at: ctx.timestamp,
+        });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Adds a mirrored duplicate of every point on the canvas, reflected across the
+// canvas center: left-to-right for "horizontal", top-to-bottom for
+// "vertical". Points on locked layers aren't copied. The copies are the caller's and form one finished stroke, so
+// delete_stroke can remove them together.
+pub fn create_mirrored_copy(ctx: &ReducerContext, axis: String) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let horizontal = match axis.as_str() {
+        "horizontal" => true,
+        "vertical" => false,
+        _ => {
+            return Err(format!(
+                "Unknown axis {:?}, expected horizontal or vertical",
+                axis
+            ))
+        }
+    };
+
+    // Locked layers take no new points, so their content isn't copied
+    let locked: HashSet<u64> = ctx
+        .db
+        .layer()
+        .iter()
+        .filter(|layer| layer.locked)
+        .map(|layer| layer.id)
+        .collect();
+    let mut points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| !locked.contains(&point.layer_id))
+        .collect();
+    if points.is_empty() {
+        return Ok(());
+    }
+    if points.len() > MAX_MIRRORED_COPY_POINTS {
+        return Err(format!(
+            "The canvas has more than {} points, too many to mirror",
+            MAX_MIRRORED_COPY_POINTS
+        ));
+    }
+    points.sort_by_key(|point| point.draw_order);
+
+    let stroke = ctx.db.stroke_meta().insert(StrokeMeta {
+        stroke_id: 0, // Will be auto-incremented
+        identity: ctx.sender,
+        started_at: ctx.timestamp,
+        ended_at: Some(ctx.timestamp),
+        point_count: points.len() as u64,
+    });
+    for point in points {
+        let (x, y) = if horizontal {
+            (CANVAS_WIDTH as f32 - point.x, point.y)
+        } else {
+            (point.x, CANVAS_HEIGHT as f32 - point.y)
+        };
+        insert_canvas_point(
+            ctx,
+            CanvasPoint {
+                opacity: point.opacity,
+                layer_id: point.layer_id,
+                blend_mode: point.blend_mode,
+                stroke_id: stroke.stroke_id,
+                ..new_canvas_point(ctx, x, y, point.color, point.size)
+            },
+        );
+    }
+    Ok(())
+}
+
+#[reducer]
+// Erases one of the caller's strokes in full; undo_last_erase brings it back
+pub fn delete_stroke(ctx: &ReducerContext, stroke_id: u64) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let points = owned_stroke_points(ctx, stroke_id)?;
+    // Locked layers can't be erased, and deleting is erasing
+    if let Some(point) = points.iter().find(|point| {
+        ctx.db
+            .layer()
+            .id()
+            .find(point.layer_id)
+            .is_some_and(|layer| layer.locked)
+    }) {
+        return Err(format!("Layer {} is locked", point.layer_id));
+    }
+    erase_canvas_points(ctx, points);
+    Ok(())
+}
+
+#[reducer]
+// Writes the color of the topmost visible point covering (x, y) to the
+// caller's eyedropper_result row. Higher layers win, then later paint order
+// within a layer; points on hidden layers and in hidden strokes are skipped.
+pub fn sample_color(ctx: &ReducerContext, x: f32, y: f32) -> Result<(), String> {
+    if !x.is_finite() || !y.is_finite() {
+        return Err(format!("Invalid position ({}, {})", x, y));
+    }
+
+    // Layer stacking and visibility, with the base layer at the bottom
+    let layers: HashMap<u64, (u32, bool)> = ctx
+        .db
+        .layer()
+        .iter()
+        .map(|layer| (layer.id, (layer.z_order, layer.hidden)))
+        .collect();
+    let stacking = |point: &CanvasPoint| match point.layer_id {
+        BASE_LAYER_ID => Some(0),
+        layer_id => match layers.get(&layer_id) {
+            Some(&(z_order, false)) => Some(z_order),
+            _ => None,
+        },
+    };
+
+    let hidden_strokes: HashSet<u64> = ctx
+        .db
+        .stroke_visibility()
+        .iter()
+        .filter(|row| row.hidden)
+        .map(|row| row.stroke_id)
+        .collect();
+
+    // No brush circle is wider than MAX_BRUSH_SIZE, so that bounds the search
+    let topmost = points_near(ctx, x, y, MAX_BRUSH_SIZE)
+        .into_iter()
+        .filter(|point| point_overlaps_circle(point, x, y, 0.0))
+        .filter(|point| !hidden_strokes.contains(&point.stroke_id))
+        .filter_map(|point| stacking(&point).map(|z_order| ((z_order, point.draw_order), point)))
+        .max_by_key(|(key, _)| *key)
+        .map(|(_, point)| point);
+
+    let result = EyedropperResult {
+        identity: ctx.sender,
+        color: topmost.as_ref().map(|point| point.color.clone()),
+        point_id: topmost.map(|point| point.id),
+        sampled_at: ctx.timestamp,
+    };
+    if ctx
+        .db
+        .eyedropper_result()
+        .identity()
+        .find(ctx.sender)
+        .is_some()
+    {
+        ctx.db.eyedropper_result().identity().update(result);
+    } else {
+        ctx.db.eyedropper_result().insert(result);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Hides or shows one of the caller's strokes
+pub fn set_stroke_visibility(
+    ctx: &ReducerContext,
+    stroke_id: u64,
+    hidden: bool,
+) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let points = owned_stroke_points(ctx, stroke_id)?;
+    if points.is_empty() {
+        return Err(format!("Stroke {} has no points to hide", stroke_id));
+    }
+
+    let row = StrokeVisibility { stroke_id, hidden };
+    if ctx
+        .db
+        .stroke_visibility()
+        .stroke_id()
+        .find(stroke_id)
+        .is_some()
+    {
+        ctx.db.stroke_visibility().stroke_id().update(row);
+    } else {
+        ctx.db.stroke_visibility().insert(row);
+    }
+    Ok(())
+}
+
+#[reducer]
+// Names one of the caller's strokes for the objects list; an empty label
+// removes the name
+pub fn set_stroke_label(ctx: &ReducerContext, stroke_id: u64, label: String) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let points = owned_stroke_points(ctx, stroke_id)?;
+    if points.is_empty() {
+        return Err(format!("Stroke {} has no points to label", stroke_id));
+    }
+    let label = label.trim().to_string();
+    if label.chars().count() > MAX_STROKE_LABEL_LEN {
+        return Err(format!(
+            "Stroke labels must be at most {} characters",
+            MAX_STROKE_LABEL_LEN
+        ));
+    }
+
+    if label.is_empty() {
+        ctx.db.stroke_label().stroke_id().delete(stroke_id);
+    } else if ctx.db.stroke_label().stroke_id().find(stroke_id).is_some() {
+        ctx.db
+            .stroke_label()
+            .stroke_id()
+            .update(StrokeLabel { stroke_id, label });
+    } else {
+        ctx.db
+            .stroke_label()
+            .insert(StrokeLabel { stroke_id, label });
+    }
+    Ok(())
+}
+
+#[reducer]
+// Splits one of the caller's strokes in two: `point_id` and every point after
+// it in paint order move to a new stroke. If the stroke was still being drawn,
+// the new second half is the one that stays open.
+pub fn split_stroke(ctx: &ReducerContext, stroke_id: u64, point_id: u64) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    let points = owned_stroke_points(ctx, stroke_id)?;
+    let pivot = points
+        .iter()
+        .find(|point| point.id == point_id)
+        .map(|point| point.draw_order)
+        .ok_or_else(|| format!("Point {} is not in stroke {}", point_id, stroke_id))?;
+    let (tail, head): (Vec<CanvasPoint>, Vec<CanvasPoint>) = points
+        .into_iter()
+        .partition(|point| point.draw_order >= pivot);
+    if head.is_empty() {
+        return Err("Splitting at the first point would leave an empty stroke".to_string());
+    }
+    let Some(stroke) = ctx.db.stroke_meta().stroke_id().find(stroke_id) else {
+        return Err(format!("Stroke {} not found", stroke_id));
+    };
+
+    let second = ctx.db.stroke_meta().insert(StrokeMeta {
+        stroke_id: 0, // Will be auto-incremented
+        identity: ctx.sender,
+        started_at: ctx.timestamp,
+        ended_at: stroke.ended_at,
+        point_count: tail.len() as u64,
+    });
+    ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+        point_count: head.len() as u64,
+        ended_at: Some(stroke.ended_at.unwrap_or(ctx.timestamp)),
+        ..stroke
+    });
+    let active = ctx
+        .db
+        .active_stroke()
+        .identity()
+        .find(ctx.sender)
+        .filter(|active| active.stroke_id == stroke_id);
+    if let Some(active) = active {
+        ctx.db.active_stroke().identity().update(ActiveStroke {
+            stroke_id: second.stroke_id,
+            ..active
+        });
+    }
+
+    let mut batch = PointBatch::new(ctx);
+    for point in tail {
+        batch.update(CanvasPoint {
+            stroke_id: second.stroke_id,
+            ..point
+        });
+    }
+    batch.finish();
+    Ok(())
+}
+
+#[reducer]
+// Puts every point the caller owns into one new, finished stroke so it can be
+// moved or transformed as a whole. The new stroke id is reported through
+// action_result. Strokes left empty are removed, except an open one, which
+// stays open to draw into.
+pub fn group_all_my_strokes(ctx: &ReducerContext) {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        write_result(ctx, "group_all_my_strokes", false, reason.to_string());
+        return;
+    }
+    let points: Vec<CanvasPoint> = ctx
+        .db
+        .canvas_point()
+        .iter()
+        .filter(|point| point.identity == ctx.sender)
+        .collect();
+    if points.is_empty() {
+        write_result(
+            ctx,
+            "group_all_my_strokes",
+            false,
+            "You have no points to group".to_string(),
+        );
+        return;
+    }
+    if points.len() > MAX_SELECTION_POINTS {
+        write_result(
+            ctx,
+            "group_all_my_strokes",
+            false,
+            format!("Can't group more than {} points", MAX_SELECTION_POINTS),
+        );
+        return;
+    }
+
+    let group = ctx.db.stroke_meta().insert(StrokeMeta {
+        stroke_id: 0, // Will be auto-incremented
+        identity: ctx.sender,
+        started_at: ctx.timestamp,
+        ended_at: Some(ctx.timestamp),
+        point_count: points.len() as u64,
+    });
+
+    let mut moved_from: HashMap<u64, u64> = HashMap::new();
+    let point_count = points.len();
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        if point.stroke_id != 0 {
+            *moved_from.entry(point.stroke_id).or_insert(0) += 1;
+        }
+        batch.update(CanvasPoint {
+            stroke_id: group.stroke_id,
+            ..point
+        });
+    }
+    batch.finish();
+
+    // The old strokes lose what moved; finished ones left empty go
+    for (stroke_id, moved) in moved_from {
+        let Some(stroke) = ctx.db.stroke_meta().stroke_id().find(stroke_id) else {
+            continue;
+        };
+        let remaining = stroke.point_count.saturating_sub(moved);
+        if remaining == 0 && stroke.ended_at.is_some() {
+            ctx.db.stroke_meta().delete(stroke);
+        } else {
+            ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+                point_count: remaining,
+                ..stroke
+            });
+        }
+    }
+
+    write_result(
+        ctx,
+        "group_all_my_strokes",
+        true,
+        format!(
+            "Grouped {} points into stroke {}",
+            point_count, group.stroke_id
+        ),
+    );
+}
+
+#[reducer]
+// Merges stroke `b` into stroke `a`, both the caller's: b's points take a's
+// id and b is removed. If b was the caller's open stroke, a stays open in its
+// place.
+pub fn join_strokes(ctx: &ReducerContext, a: u64, b: u64) -> Result<(), String> {
+    if let Some(reason) = canvas_edit_blocked(ctx) {
+        return Err(reason.to_string());
+    }
+    if a == b {
+        return Err("A stroke can't be joined to itself".to_string());
+    }
+    owned_stroke_points(ctx, a)?;
+    let points = owned_stroke_points(ctx, b)?;
+    let (Some(first), Some(second)) = (
+        ctx.db.stroke_meta().stroke_id().find(a),
+        ctx.db.stroke_meta().stroke_id().find(b),
+    ) else {
+        return Err("Both strokes must exist".to_string());
+    };
+
+    let active = ctx
+        .db
+        .active_stroke()
+        .identity()
+        .find(ctx.sender)
+        .filter(|active| active.stroke_id == b);
+    let was_active = active.is_some();
+    if let Some(active) = active {
+        ctx.db.active_stroke().identity().update(ActiveStroke {
+            stroke_id: a,
+            ..active
+        });
+    }
+    ctx.db.stroke_meta().stroke_id().update(StrokeMeta {
+        point_count: first.point_count + points.len() as u64,
+        ended_at: if was_active { None } else { first.ended_at },
+        ..first
+    });
+    ctx.db.stroke_meta().delete(second);
+
+    let mut batch = PointBatch::new(ctx);
+    for point in points {
+        batch.update(CanvasPoint {
+            stroke_id: a,
+            ..point
+        });
+    }
+    batch.finish();
+    Ok(())
+}
+
+#[reducer]
+// Records the current table sizes in usage_report right away
+pub fn report_usage(ctx: &ReducerContext) -> Result<(), String> {
+    if !is_admin(ctx) {
+        return Err("Only room admins can report usage".to_string());
+    }
+    write_usage_report(ctx);
+    Ok(())
+}
+
+#[reducer]
+// Scheduled: records the table sizes for trend tracking
+pub fn record_usage(ctx: &ReducerContext, _schedule: UsageReportSchedule) -> Result<(), String> {
+    if ctx.sender != ctx.identity() {
+        return Err("record_usage may only be run by the scheduler".to_string());
+    }
+    write_usage_report(ctx);
+    Ok(())
+}
+
+#[reducer]
+// Deletes saved points whose canvas state no longer exists, left behind if a
+// state was ever deleted without its points, and reports how many went
+pub fn cleanup_orphan_saved_points(ctx: &ReducerContext) {
+    if !is_admin(ctx) {
+        write_result(
+            ctx,
+            "cleanup_orphan_saved_points",
+            false,
+            "Only room admins can clean up saved points".to_string(),
+        );
+        return;
+    }
+
+    let state_ids: HashSet<u64> = ctx.db.canvas_state().iter().map(|state| state.id).collect();
+    let orphans: Vec<SavedCanvasPoint> = ctx
+        .db
+        .saved_canvas_point()
+        .iter()
+        .filter(|point| !state_ids.contains(&point.state_id))
+        .collect();
+    let removed = orphans.len();
+    for point in orphans {
+        ctx.db.saved_canvas_point().delete(point);
+    }
+
+    log::info!(
+        "User {} removed {} orphaned saved points",
+        ctx.sender,
+        removed
+    );
+    write_result(
+        ctx,
+        "cleanup_orphan_saved_points",
+        true,
+        format!("Removed {} orphaned saved points", removed),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_secs(secs: i64) -> Timestamp {
+        Timestamp::from_micros_since_unix_epoch(secs * 1_000_000)
+    }
+
+    fn point_at(x: f32, y: f32, size: f32) -> CanvasPoint {
+        CanvasPoint {
+            id: 1,
+            identity: Identity::ZERO,
+            x,
+            y,
+            color: "#000000".to_string(),
+            size,
+            opacity: 1.0,
+            draw_order: 1,
+            stroke_id: 0,
+            layer_id: BASE_LAYER_ID,
+            mirror_of: 0,
+            origin: ORIGIN_DRAWN.to_string(),
+            blend_mode: BLEND_MODE_NORMAL.to_string(),
+            timestamp: at_secs(0),
+            bucket_x: 0,
+            bucket_y: 0,
+        }
+    }
+
+    #[test]
+    fn access_code_hash_depends_on_code_and_salt() {
+        let hash = hash_access_code("salt-a", "correct horse");
+        assert_eq!(hash, hash_access_code("salt-a", "correct horse"));
+        assert_ne!(hash, hash_access_code("salt-a", "correct horsf"));
+        assert_ne!(hash, hash_access_code("salt-b", "correct horse"));
+    }
+
+    #[test]
+    fn join_cooldown_ends_exactly_at_the_boundary() {
+        let cooldown = Duration::from_secs(30);
+        let joined = at_secs(1_000);
+        assert_eq!(
+            cooldown_remaining(joined, at_secs(1_000), cooldown),
+            cooldown
+        );
+        assert_eq!(
+            cooldown_remaining(joined, at_secs(1_029), cooldown),
+            Duration::from_secs(1)
+        );
+        let just_before = Timestamp::from_micros_since_unix_epoch(1_030 * 1_000_000 - 1);
+        assert_eq!(
+            cooldown_remaining(joined, just_before, cooldown),
+            Duration::from_micros(1)
+        );
+        assert!(cooldown_remaining(joined, at_secs(1_030), cooldown).is_zero());
+        assert!(cooldown_remaining(joined, at_secs(2_000), cooldown).is_zero());
+    }
+
+    #[test]
+    fn join_cooldown_counts_a_clock_behind_the_join_as_no_wait_yet() {
+        let cooldown = Duration::from_secs(30);
+        assert_eq!(
+            cooldown_remaining(at_secs(1_000), at_secs(999), cooldown),
+            cooldown
+        );
+    }
+
+    #[test]
+    fn eraser_circle_overlaps_by_sum_of_radii() {
+        let point = point_at(100.0, 100.0, 5.0);
+        // Touching edges count as an overlap
+        assert!(point_overlaps_circle(&point, 115.0, 100.0, 10.0));
+        assert!(point_overlaps_circle(&point, 100.0, 85.0, 10.0));
+        assert!(!point_overlaps_circle(&point, 115.5, 100.0, 10.0));
+        // Within the per-axis box but outside the circle
+        assert!(!point_overlaps_circle(&point, 111.0, 111.0, 10.0));
+        assert!(point_overlaps_circle(&point, 110.0, 110.0, 10.0));
+        assert!(point_overlaps_circle(&point, 100.0, 100.0, 0.5));
+    }
+
+    #[test]
+    fn erase_skips_points_on_a_locked_layer() {
+        let layer = |id, locked, hidden| Layer {
+            id,
+            name: format!("Layer {}", id),
+            z_order: id as u32,
+            locked,
+            hidden,
+            created_by: Identity::ZERO,
+        };
+        assert!(layer_blocks_erase(&layer(2, true, false), true));
+        assert!(layer_blocks_erase(&layer(3, false, true), false));
+        assert!(!layer_blocks_erase(&layer(3, false, true), true));
+        assert!(!layer_blocks_erase(&layer(4, false, false), false));
+
+        let guard = EraseGuard {
+            protected_layers: HashSet::from([2]),
+            protected_strokes: HashSet::new(),
+            eraser: Identity::ONE,
+            fresh_after: at_secs(0),
+        };
+        let locked = CanvasPoint {
+            layer_id: 2,
+            ..point_at(50.0, 50.0, 4.0)
+        };
+        let open = CanvasPoint {
+            layer_id: 4,
+            ..point_at(50.0, 50.0, 4.0)
+        };
+        // Both overlap the eraser, only the unlocked one is erased
+        assert!(point_overlaps_circle(&locked, 50.0, 50.0, 10.0));
+        assert!(!guard.allows(&locked));
+        assert!(guard.allows(&open));
+    }
+
+    #[test]
+    fn flip_mirrors_a_point_across_the_stroke_center() {
+        let stroke = [point_at(10.0, 20.0, 2.0), point_at(30.0, 60.0, 2.0)];
+        let bounds = Bounds::of_circles(stroke.iter().map(|p| (p.x, p.y, p.size))).unwrap();
+        let center = bounds.center();
+        assert_eq!(center, (20.0, 40.0));
+
+        let point = || CanvasPoint {
+            color: "#ff8800".to_string(),
+            ..point_at(12.0, 25.0, 3.0)
+        };
+        let across = flip_point(point(), center, true);
+        assert_eq!((across.x, across.y), (28.0, 25.0));
+        let down = flip_point(point(), center, false);
+        assert_eq!((down.x, down.y), (12.0, 55.0));
+        assert_eq!((down.size, down.color.as_str()), (3.0, "#ff8800"));
+        // Flipping twice puts the point back
+        let back = flip_point(across, center, true);
+        assert_eq!((back.x, back.y), (12.0, 25.0));
+    }
+
+    fn assert_near(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-3 && (actual.1 - expected.1).abs() < 1e-3,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn rotate_turns_quarter_and_half_turns_around_the_center() {
+        let center = (100.0, 100.0);
+        // y points down, so a positive quarter turn takes right to down
+        assert_near(rotate_around((110.0, 100.0), center, 90.0), (100.0, 110.0));
+        assert_near(rotate_around((100.0, 110.0), center, 90.0), (90.0, 100.0));
+        assert_near(rotate_around((110.0, 105.0), center, 180.0), (90.0, 95.0));
+        assert_near(rotate_around((110.0, 100.0), center, -90.0), (100.0, 90.0));
+        // Angles are taken modulo a full turn, and the center stays put
+        assert_near(rotate_around((110.0, 100.0), center, 450.0), (100.0, 110.0));
+        assert_near(rotate_around(center, center, 90.0), center);
+    }
+
+    #[test]
+    fn hue_shift_turns_known_colors_around_the_wheel() {
+        assert_eq!(shift_hex_hue("#ff0000", 120.0).unwrap(), "#00ff00");
+        assert_eq!(shift_hex_hue("#FF0000", 240.0).unwrap(), "#0000ff");
+        assert_eq!(shift_hex_hue("#ff0000", -120.0).unwrap(), "#0000ff");
+        assert_eq!(shift_hex_hue("#00ff00", 480.0).unwrap(), "#0000ff");
+        assert_eq!(shift_hex_hue("#ff00ff", 60.0).unwrap(), "#ff0000");
+        assert_eq!(shift_hex_hue("#ff8080", 180.0).unwrap(), "#80ffff");
+        // Grays have no hue to shift
+        assert_eq!(shift_hex_hue("#808080", 90.0).unwrap(), "#808080");
+        assert!(shift_hex_hue("red", 90.0).is_err());
+    }
+
+    #[test]
+    fn erasing_a_finished_stroke_dot_by_dot_orphans_its_meta_last() {
+        let stroke = StrokeMeta {
+            stroke_id: 7,
+            identity: Identity::ZERO,
+            started_at: at_secs(0),
+            ended_at: Some(at_secs(5)),
+            point_count: 1,
+        };
+        // Erasing all but the last dot keeps the meta, erasing that one drops it
+        assert!(!stroke_meta_orphaned(&stroke, true));
+        assert!(stroke_meta_orphaned(&stroke, false));
+
+        // A stroke still being drawn keeps its meta while empty
+        let open = StrokeMeta {
+            ended_at: None,
+            ..stroke
+        };
+        assert!(!stroke_meta_orphaned(&open, false));
+    }
+
+    #[test]
+    fn moving_a_subset_to_a_layer_changes_only_those_points() {
+        let me = Identity::ONE;
+        let point = |id, identity, layer_id| CanvasPoint {
+            id,
+            identity,
+            layer_id,
+            ..point_at(0.0, 0.0, 1.0)
+        };
+        let rows = [
+            (1, me, BASE_LAYER_ID),
+            (2, me, BASE_LAYER_ID),
+            (3, me, 3),
+            (4, Identity::ZERO, BASE_LAYER_ID),
+            (5, me, 5),
+            (6, me, BASE_LAYER_ID),
+        ];
+        let row = |id: u64| {
+            let (id, identity, layer_id) = rows[id as usize - 1];
+            point(id, identity, layer_id)
+        };
+        let mut canvas = [1, 2, 3, 4, 5, 6].map(row);
+        // Select 2, 3, 4 and 5, with 2 picked twice
+        let selected = [2, 3, 4, 5, 2].map(row);
+        let moved = points_moved_to_layer(selected, me, 5);
+        let moved_ids: Vec<u64> = moved.iter().map(|point| point.id).collect();
+        assert_eq!(moved_ids, [2, 3]);
+
+        for point in moved {
+            let index = point.id as usize - 1;
+            canvas[index] = point;
+        }
+        let layers: Vec<u64> = canvas.iter().map(|point| point.layer_id).collect();
+        assert_eq!(
+            layers,
+            [BASE_LAYER_ID, 5, 5, BASE_LAYER_ID, 5, BASE_LAYER_ID]
+        );
+    }
+
+    #[test]
+    fn square_eraser_covers_its_corners_and_touching_edges() {
+        // Side 20 centered on (100, 100)
+        let in_corner = point_at(109.0, 109.0, 0.5);
+        assert!(point_overlaps_square(&in_corner, 100.0, 100.0, 10.0));
+        assert!(!point_overlaps_circle(&in_corner, 100.0, 100.0, 10.0));
+        assert!(EraserShape::Square.overlaps(&in_corner, 100.0, 100.0, 10.0));
+        assert!(!EraserShape::Circle.overlaps(&in_corner, 100.0, 100.0, 10.0));
+
+        // A brush circle just touching an edge counts
+        assert!(point_overlaps_square(
+            &point_at(112.0, 100.0, 2.0),
+            100.0,
+            100.0,
+            10.0
+        ));
+        assert!(!point_overlaps_square(
+            &point_at(112.5, 100.0, 2.0),
+            100.0,
+            100.0,
+            10.0
+        ));
+        // Past a corner, the distance to the corner decides
+        assert!(!point_overlaps_square(
+            &point_at(112.0, 112.0, 2.0),
+            100.0,
+            100.0,
+            10.0
+        ));
+        assert!(point_overlaps_square(
+            &point_at(111.0, 111.0, 2.0),
+            100.0,
+            100.0,
+            10.0
+        ));
+    }
+
+    #[test]
+    fn zero_or_invalid_eraser_radius_has_no_area() {
+        assert!(!eraser_has_area(0.0));
+        assert!(!eraser_has_area(-0.0));
+        assert!(!eraser_has_area(-3.0));
+        assert!(!eraser_has_area(f32::NAN));
+        assert!(!eraser_has_area(f32::INFINITY));
+        assert!(eraser_has_area(0.1));
+        // Without the guard a zero radius would still erase the point under it
+        assert!(point_overlaps_circle(
+            &point_at(5.0, 5.0, 1.0),
+            5.0,
+            5.0,
+            0.0
+        ));
+    }
+
+    #[test]
+    fn quantize_maps_colors_to_the_nearest_palette_entry() {
+        let palette: Vec<(String, (u8, u8, u8))> = ["#000000", "#ffffff", "#ff0000", "#0000ff"]
+            .iter()
+            .map(|color| (color.to_string(), parse_hex_color(color).unwrap()))
+            .collect();
+        let nearest = |color| nearest_palette_color(parse_hex_color(color).unwrap(), &palette);
+        assert_eq!(nearest("#101010"), "#000000");
+        assert_eq!(nearest("#e0e0e0"), "#ffffff");
+        assert_eq!(nearest("#c02020"), "#ff0000");
+        assert_eq!(nearest("#2020a0"), "#0000ff");
+        assert_eq!(nearest("#ff0000"), "#ff0000");
+        // Ties go to the earlier palette entry
+        assert_eq!(nearest("#800080"), "#ff0000");
+    }
+
+    #[test]
+    fn downsampling_an_oversized_save_fits_the_cap() {
+        let cap = MAX_POINTS_PER_STATE;
+        let (kept, stride) = downsample_to_cap((0..cap).collect(), cap);
+        assert_eq!((kept.len() as u64, stride), (cap, 1));
+
+        let (kept, stride) = downsample_to_cap((0..cap + 1).collect(), cap);
+        assert_eq!(stride, 2);
+        assert!(kept.len() as u64 <= cap);
+        assert_eq!(kept[..3], [0, 2, 4]);
+
+        let (kept, stride) = downsample_to_cap((0..cap * 3 - 1).collect(), cap);
+        assert_eq!(stride, 3);
+        assert_eq!(kept.len() as u64, cap);
+        assert_eq!(kept.last(), Some(&(cap * 3 - 3)));
+    }
+
+    #[test]
+    fn tint_blends_toward_the_target_by_strength() {
+        let color = parse_hex_color("#204060").unwrap();
+        let tint = parse_hex_color("#ffffff").unwrap();
+        assert_eq!(blend_hex_colors(color, tint, 0.0), "#204060");
+        assert_eq!(blend_hex_colors(color, tint, 1.0), "#ffffff");
+        // Halfway: 0x20 + (0xff - 0x20) / 2 = 143.5, rounded to 0x90
+        assert_eq!(blend_hex_colors(color, tint, 0.5), "#90a0b0");
+        let red = parse_hex_color("#ff0000").unwrap();
+        let blue = parse_hex_color("#0000ff").unwrap();
+        assert_eq!(blend_hex_colors(red, blue, 0.25), "#bf0040");
+    }
+
+    #[test]
+    fn saving_identical_content_twice_gives_the_same_hash() {
+        let canvas = [point_at(10.0, 20.0, 3.0), point_at(30.0, 40.0, 5.0)];
+        let save = |state_id, canvas: &[CanvasPoint]| {
+            let saved: Vec<SavedCanvasPoint> = canvas
+                .iter()
+                .map(|point| to_saved_point(state_id, point))
+                .collect();
+            content_hash(&saved)
+        };
+        // Different states and draw times, same drawing
+        let redrawn = [
+            CanvasPoint {
+                id: 8,
+                timestamp: at_secs(90),
+                ..point_at(10.0, 20.0, 3.0)
+            },
+            point_at(30.0, 40.0, 5.0),
+        ];
+        assert_eq!(save(1, &canvas), save(2, &redrawn));
+
+        let recolored = [
+            CanvasPoint {
+                color: "#000001".to_string(),
+                ..point_at(10.0, 20.0, 3.0)
+            },
+            point_at(30.0, 40.0, 5.0),
+        ];
+        assert_ne!(save(1, &canvas), save(1, &recolored));
+        let moved = [point_at(10.0, 20.5, 3.0), point_at(30.0, 40.0, 5.0)];
+        assert_ne!(save(1, &canvas), save(1, &moved));
+        let reordered = [point_at(30.0, 40.0, 5.0), point_at(10.0, 20.0, 3.0)];
+        assert_ne!(save(1, &canvas), save(1, &reordered));
+    }
+
+    #[test]
+    fn points_closer_than_the_spacing_are_dropped() {
+        let last = point_at(100.0, 100.0, 2.0);
+        assert!(within_spacing(&last, 101.0, 101.0, 2.0));
+        assert!(within_spacing(&last, 100.0, 100.0, 2.0));
+        // Exactly at the spacing or beyond is kept
+        assert!(!within_spacing(&last, 102.0, 100.0, 2.0));
+        assert!(!within_spacing(&last, 103.0, 104.0, 2.0));
+        // The default spacing of 0 keeps everything
+        assert!(!within_spacing(&last, 100.0, 100.0, 0.0));
+
+        // A dense stroke thins to points at least the spacing apart
+        let mut kept = vec![point_at(0.0, 0.0, 1.0)];
+        for step in 1..=20 {
+            let x = step as f32 * 0.5;
+            if !within_spacing(kept.last().unwrap(), x, 0.0, 2.0) {
+                kept.push(point_at(x, 0.0, 1.0));
+            }
+        }
+        let xs: Vec<f32> = kept.iter().map(|p| p.x).collect();
+        assert_eq!(xs, [0.0, 2.0, 4.0, 6.0, 8.0, 10.0]);
+    }
+
+    #[test]
+    fn repair_normalizes_a_corrupted_state() {
+        let saved =
+            |x: f32, color: &str, size: f32, opacity: f32, blend_mode: &str| SavedCanvasPoint {
+                blend_mode: blend_mode.to_string(),
+                ..to_saved_point(
+                    3,
+                    &CanvasPoint {
+                        color: color.to_string(),
+                        opacity,
+                        ..point_at(x, 10.0, size)
+                    },
+                )
+            };
+        let corrupted = [
+            saved(1.0, "#00ff00", 4.0, 0.5, BLEND_MODE_NORMAL),
+            saved(2.0, "green", 4.0, 0.5, BLEND_MODE_NORMAL),
+            saved(
+                3.0,
+                "#00ff00",
+                MAX_BRUSH_SIZE * 10.0,
+                0.5,
+                BLEND_MODE_NORMAL,
+            ),
+            saved(4.0, "#00ff00", f32::NAN, 7.0, "dodge"),
+            saved(f32::INFINITY, "#00ff00", 4.0, 0.5, BLEND_MODE_NORMAL),
+        ];
+        let repairs: Vec<SavedPointRepair> = corrupted.iter().map(repair_saved_point).collect();
+
+        assert!(matches!(repairs[0], SavedPointRepair::Intact));
+        let SavedPointRepair::Fixed(recolored) = &repairs[1] else {
+            panic!("an invalid color should be fixed");
+        };
+        assert_eq!(recolored.color, REPAIR_FALLBACK_COLOR);
+        assert_eq!((recolored.x, recolored.state_id), (2.0, 3));
+        let SavedPointRepair::Fixed(clamped) = &repairs[2] else {
+            panic!("an oversized brush should be fixed");
+        };
+        assert_eq!(clamped.size, MAX_BRUSH_SIZE);
+        let SavedPointRepair::Fixed(reset) = &repairs[3] else {
+            panic!("non-finite size and bad opacity should be fixed");
+        };
+        assert_eq!(
+            (reset.size, reset.opacity, reset.blend_mode.as_str()),
+            (DEFAULT_BRUSH_SIZE, 1.0, BLEND_MODE_NORMAL)
+        );
+        assert!(matches!(repairs[4], SavedPointRepair::Dropped));
+    }
+
+    #[test]
+    fn recoloring_my_points_leaves_other_users_alone() {
+        let (me, other) = (Identity::ONE, Identity::ZERO);
+        let rows = [
+            (1, me, "#ff0000"),
+            (2, other, "#ff0000"),
+            (3, me, "#00ff00"),
+            (4, other, "#0000ff"),
+            (5, me, "#0000ff"),
+        ];
+        let row = |index: usize| {
+            let (id, identity, color) = rows[index];
+            CanvasPoint {
+                id,
+                identity,
+                color: color.to_string(),
+                ..point_at(0.0, 0.0, 1.0)
+            }
+        };
+        let mut canvas = [0, 1, 2, 3, 4].map(row);
+
+        let recolored = owner_points_recolored([0, 1, 2, 3, 4].map(row), me, "#0000ff");
+        // Mine already in the color is skipped, so the reported count is 2
+        let ids: Vec<u64> = recolored.iter().map(|point| point.id).collect();
+        assert_eq!(ids, [1, 3]);
+        for point in recolored {
+            let index = point.id as usize - 1;
+            canvas[index] = point;
+        }
+
+        let colors: Vec<(Identity, &str)> = canvas
+            .iter()
+            .map(|point| (point.identity, point.color.as_str()))
+            .collect();
+        assert_eq!(
+            colors,
+            [
+                (me, "#0000ff"),
+                (other, "#ff0000"),
+                (me, "#0000ff"),
+                (other, "#0000ff"),
+                (me, "#0000ff"),
+            ]
+        );
+    }
+
+    #[test]
+    fn brush_sizes_clamp_into_the_allowed_range() {
+        assert_eq!(brush_size_within(4.0, 20.0), 4.0);
+        assert_eq!(brush_size_within(40.0, 20.0), 20.0);
+        assert_eq!(brush_size_within(f32::INFINITY, 20.0), 20.0);
+        assert_eq!(brush_size_within(0.0, 20.0), MIN_BRUSH_SIZE);
+        assert_eq!(brush_size_within(-5.0, 20.0), MIN_BRUSH_SIZE);
+        assert_eq!(brush_size_within(f32::NEG_INFINITY, 20.0), MIN_BRUSH_SIZE);
+        assert_eq!(brush_size_within(f32::NAN, 20.0), DEFAULT_BRUSH_SIZE);
+        assert_eq!(brush_size_within(f32::NAN, 2.0), 2.0);
+    }
+
+    #[test]
+    fn point_count_tracks_a_mix_of_canvas_operations() {
+        use PointCountChange::*;
+        // Mirror each change on a plain list standing in for canvas_point
+        let mut canvas: Vec<u64> = Vec::new();
+        let mut count = 0;
+        let mut apply = |change: PointCountChange| {
+            match change {
+                Inserted => canvas.push(canvas.len() as u64),
+                Deleted => {
+                    canvas.pop();
+                }
+                Cleared => canvas.clear(),
+                Loaded(loaded) => canvas = (0..loaded).collect(),
+            }
+            count = next_point_count(count, change);
+            assert_eq!(count, canvas.len() as u64, "after {:?}", change);
+        };
+        for change in [
+            Inserted,
+            Inserted,
+            Inserted,
+            Deleted,
+            Inserted,
+            Cleared,
+            Deleted,
+            Loaded(5),
+            Inserted,
+            Deleted,
+            Deleted,
+            Loaded(0),
+            Inserted,
+        ] {
+            apply(change);
+        }
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn beginning_a_second_stroke_ends_the_first() {
+        let stroke = |stroke_id, ended_at| StrokeMeta {
+            stroke_id,
+            identity: Identity::ONE,
+            started_at: at_secs(0),
+            ended_at,
+            point_count: 2,
+        };
+        // The first begin_stroke left stroke 1 open; stroke 2 was ended earlier
+        let mine = [stroke(1, None), stroke(2, Some(at_secs(3)))];
+        let dangling = dangling_strokes(mine);
+        assert_eq!(dangling.len(), 1);
+        assert_eq!(dangling[0].stroke_id, 1);
+
+        let second_begin = at_secs(10);
+        let finished: Vec<FinishedStroke> = dangling
+            .into_iter()
+            .map(|stroke| finished_stroke(stroke, true, second_begin))
+            .collect();
+        let [FinishedStroke::Ended(first)] = &finished[..] else {
+            panic!("the open stroke should be ended");
+        };
+        assert_eq!((first.stroke_id, first.ended_at), (1, Some(second_begin)));
+        assert_eq!(first.point_count, 2);
+
+        // An open stroke nothing was drawn into is dropped instead
+        assert!(matches!(
+            finished_stroke(stroke(3, None), false, second_begin),
+            FinishedStroke::Dropped(_)
+        ));
+        assert!(matches!(
+            finished_stroke(stroke(2, Some(at_secs(3))), true, second_begin),
+            FinishedStroke::Unchanged
+        ));
     }
 }